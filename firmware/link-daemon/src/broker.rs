@@ -0,0 +1,831 @@
+//! Drives a session from the runner picking up a job through to the
+//! runner going away again, forwarding packets between the runner and
+//! the link along the way.
+//!
+//! Every state has a time limit, and the job as a whole has a deadline;
+//! whichever runs out first powers off the SUT and tears the session
+//! down, so that a stuck job can't keep a rig reserved forever. While
+//! the SUT is powered on, a [`Watchdog`] also looks out for it hanging.
+//!
+//! The broker tells the time by a [`Clock`], so that replays and tests
+//! can move time along themselves (see [`Broker::run_until`]).
+use crate::{
+	clock::{Clock, ManualClock, SystemClock},
+	session::{BrokerMessage, ControlMessage},
+	watchdog::{Action, Watchdog},
+	Error, SessionConfig,
+};
+use async_std::channel::{Receiver, Sender};
+use futures::{prelude::*, select};
+use link_protocol::{
+	Capabilities, ClientToDaemon, DaemonToClient, DaemonToLink, LinkToDaemon, PowerState, Scene,
+};
+use log::{debug, error, info, warn};
+use std::{
+	convert::Infallible,
+	fmt,
+	time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+	/// Waiting for the runner to pick up a job.
+	Idle,
+	/// The runner is describing the job. The SUT is powered on once
+	/// it has sent both the bootfile sizes and the test session.
+	Provisioning {
+		has_bootfile_size: bool,
+		has_test_session: bool,
+	},
+	/// The SUT has been powered on; waiting for the first test.
+	Booting,
+	/// Tests are running.
+	Testing,
+	/// The job is over (or ran out of time); waiting for the runner's
+	/// container to go away.
+	Teardown,
+}
+
+impl fmt::Display for State {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Self::Idle => "idle",
+			Self::Provisioning { .. } => "provisioning",
+			Self::Booting => "booting",
+			Self::Testing => "testing",
+			Self::Teardown => "teardown",
+		})
+	}
+}
+
+/// What a deadline is for.
+#[derive(Debug, Clone, Copy)]
+enum Expiry {
+	State,
+	Job,
+	Watchdog,
+}
+
+/// How long a session may stay in each state, and how long a job may
+/// take overall (from the moment provisioning starts).
+pub(crate) struct Timeouts {
+	pub idle: Duration,
+	pub provisioning: Duration,
+	pub booting: Duration,
+	pub testing: Duration,
+	pub teardown: Duration,
+	pub job: Duration,
+	/// How long the SUT may be quiet on its serial line before the
+	/// watchdog steps in.
+	pub serial: Duration,
+}
+
+impl Timeouts {
+	pub fn from_config(config: &SessionConfig) -> Self {
+		Self {
+			idle: Duration::from_secs(config.idle_timeout_secs),
+			provisioning: Duration::from_secs(config.provisioning_timeout_secs),
+			booting: Duration::from_secs(config.booting_timeout_secs),
+			testing: Duration::from_secs(config.testing_timeout_secs),
+			teardown: Duration::from_secs(config.teardown_timeout_secs),
+			job: Duration::from_secs(config.job_deadline_secs),
+			serial: match config.serial_watchdog_secs {
+				0 => Duration::MAX,
+				secs => Duration::from_secs(secs),
+			},
+		}
+	}
+
+	fn of(&self, state: State) -> Duration {
+		match state {
+			State::Idle => self.idle,
+			State::Provisioning { .. } => self.provisioning,
+			State::Booting => self.booting,
+			State::Testing => self.testing,
+			State::Teardown => self.teardown,
+		}
+	}
+}
+
+pub(crate) struct Broker<C: Clock> {
+	clock: C,
+	state: State,
+	/// When the current state was entered.
+	entered: Instant,
+	/// When the runner started provisioning its job, if it has.
+	job_started: Option<Instant>,
+	timeouts: Timeouts,
+	watchdog: Watchdog,
+	capabilities: Capabilities,
+	link: Sender<ControlMessage<DaemonToLink>>,
+	client: Sender<ControlMessage<DaemonToClient>>,
+	docker: Sender<ControlMessage<Infallible>>,
+}
+
+pub(crate) async fn handle_broker(
+	capabilities: Capabilities,
+	timeouts: Timeouts,
+	receiver: Receiver<BrokerMessage>,
+	link: Sender<ControlMessage<DaemonToLink>>,
+	client: Sender<ControlMessage<DaemonToClient>>,
+	docker: Sender<ControlMessage<Infallible>>,
+) -> Result<(), Error> {
+	let mut broker = Broker::new(SystemClock, capabilities, timeouts, link, client, docker);
+
+	loop {
+		let (deadline, expiry) = broker.deadline().unzip();
+		let mut timer = future::FutureExt::fuse(match deadline {
+			Some(deadline) => async_io::Timer::at(deadline),
+			None => async_io::Timer::never(),
+		});
+
+		select! {
+			message = receiver.recv().fuse() => broker.handle(message?).await?,
+			_ = timer => if let Some(expiry) = expiry {
+				broker.expire(expiry).await?;
+			},
+		}
+	}
+}
+
+impl Broker<ManualClock> {
+	/// Moves the clock forward to `until`, handling every deadline that
+	/// passes along the way at the moment it passes.
+	pub async fn run_until(&mut self, until: Instant) -> Result<(), Error> {
+		while let Some((at, expiry)) = self.deadline().filter(|&(at, _)| at <= until) {
+			self.clock.set(at);
+			self.expire(expiry).await?;
+		}
+
+		self.clock.set(until);
+		Ok(())
+	}
+}
+
+impl<C: Clock> Broker<C> {
+	pub fn new(
+		clock: C,
+		capabilities: Capabilities,
+		timeouts: Timeouts,
+		link: Sender<ControlMessage<DaemonToLink>>,
+		client: Sender<ControlMessage<DaemonToClient>>,
+		docker: Sender<ControlMessage<Infallible>>,
+	) -> Self {
+		debug!("starting broker");
+
+		Self {
+			state: State::Idle,
+			entered: clock.now(),
+			job_started: None,
+			watchdog: Watchdog::new(timeouts.serial),
+			clock,
+			timeouts,
+			capabilities,
+			link,
+			client,
+			docker,
+		}
+	}
+
+	pub fn state(&self) -> State {
+		self.state
+	}
+
+	fn transition(&mut self, to: State, reason: &str) {
+		info!("session state: {} -> {to} ({reason})", self.state);
+		self.state = to;
+		self.entered = self.clock.now();
+
+		if matches!(to, State::Booting | State::Testing) {
+			self.watchdog.arm(self.entered);
+		} else {
+			self.watchdog.disarm();
+		}
+	}
+
+	/// The next deadline, if any, and what it's for.
+	fn deadline(&self) -> Option<(Instant, Expiry)> {
+		let state = self
+			.entered
+			.checked_add(self.timeouts.of(self.state))
+			.map(|at| (at, Expiry::State));
+		let job = self
+			.job_started
+			.filter(|_| self.state != State::Teardown)
+			.and_then(|started| started.checked_add(self.timeouts.job))
+			.map(|at| (at, Expiry::Job));
+		let watchdog = self.watchdog.deadline().map(|at| (at, Expiry::Watchdog));
+
+		[state, job, watchdog]
+			.into_iter()
+			.flatten()
+			.min_by_key(|&(at, _)| at)
+	}
+
+	async fn expire(&mut self, expiry: Expiry) -> Result<(), Error> {
+		let reason = match expiry {
+			Expiry::State if self.state == State::Teardown => {
+				error!("session teardown did not finish in time");
+				return Err(Error::SessionTimedOut(self.state));
+			}
+			Expiry::State => format!(
+				"the {} state timed out after {}s",
+				self.state,
+				self.timeouts.of(self.state).as_secs()
+			),
+			Expiry::Job => format!(
+				"the job did not finish within {}s",
+				self.timeouts.job.as_secs()
+			),
+			Expiry::Watchdog => {
+				let quiet = self.watchdog.timeout().as_secs();
+				match self.watchdog.expire(self.clock.now()) {
+					Action::PressReset => {
+						warn!("SUT has been quiet on its serial line for {quiet}s; pressing reset");
+						return self.send_link(DaemonToLink::PressReset).await;
+					}
+					Action::PowerOff => format!(
+						"the SUT was quiet on its serial line for {quiet}s, even after being reset"
+					),
+				}
+			}
+		};
+
+		self.fail(&reason).await
+	}
+
+	/// Powers off the SUT, tells the runner why, and tears the session
+	/// down.
+	async fn fail(&mut self, reason: &str) -> Result<(), Error> {
+		warn!(
+			"failing session while {}: {reason}; powering off the SUT",
+			self.state
+		);
+		self.send_link(DaemonToLink::SetPowerState(PowerState::Off))
+			.await?;
+
+		// Long reasons are cut short rather than not sent at all.
+		let mut truncated = heapless::String::new();
+		for c in reason.chars() {
+			if truncated.push(c).is_err() {
+				break;
+			}
+		}
+		self.client
+			.send(ControlMessage::Packet(DaemonToClient::SessionFailed {
+				reason: truncated,
+			}))
+			.await?;
+
+		self.docker.send(ControlMessage::End).await?;
+		self.transition(State::Teardown, reason);
+
+		Ok(())
+	}
+
+	async fn send_link(&self, packet: DaemonToLink) -> Result<(), Error> {
+		Ok(self.link.send(ControlMessage::Packet(packet)).await?)
+	}
+
+	pub async fn handle(&mut self, message: BrokerMessage) -> Result<(), Error> {
+		match message {
+			BrokerMessage::Link(ControlMessage::Packet(LinkToDaemon::Serial(data))) => {
+				self.watchdog.serial(self.clock.now());
+				self.client
+					.send(ControlMessage::Packet(DaemonToClient::Serial(data)))
+					.await?;
+			}
+			BrokerMessage::Client(ControlMessage::Packet(ClientToDaemon::Serial(data))) => {
+				self.send_link(DaemonToLink::Serial(data)).await?;
+			}
+			BrokerMessage::Client(ControlMessage::Packet(ClientToDaemon::BootfileSize {
+				uefi,
+				bios,
+			})) => {
+				self.send_link(DaemonToLink::BootfileSize { uefi, bios })
+					.await?;
+				self.provision(true, false).await?;
+			}
+			BrokerMessage::Client(ControlMessage::Packet(ClientToDaemon::PressPower)) => {
+				self.send_link(DaemonToLink::PressPower).await?;
+			}
+			BrokerMessage::Client(ControlMessage::Packet(ClientToDaemon::PressReset)) => {
+				self.send_link(DaemonToLink::PressReset).await?;
+			}
+			BrokerMessage::Client(ControlMessage::Packet(ClientToDaemon::StartTest { name })) => {
+				match self.state {
+					State::Booting => {
+						self.transition(State::Testing, "first test started");
+
+						// Switch to testing scene
+						if self.capabilities.contains(Capabilities::MONITOR) {
+							self.send_link(DaemonToLink::SetScene(Scene::Test)).await?;
+						}
+					}
+					State::Testing => {}
+					state => warn!("runner started a test while {state}"),
+				}
+
+				self.send_link(DaemonToLink::StartTest { name }).await?;
+			}
+			BrokerMessage::Client(ControlMessage::Packet(ClientToDaemon::StartTestSession {
+				total_tests,
+				author,
+				title,
+				ref_id,
+			})) => {
+				self.send_link(DaemonToLink::StartTestSession {
+					total_tests,
+					author,
+					title,
+					ref_id,
+				})
+				.await?;
+				self.provision(false, true).await?;
+			}
+			BrokerMessage::Client(ControlMessage::End) => {
+				if self.state != State::Teardown {
+					self.docker.send(ControlMessage::End).await?;
+					self.transition(State::Teardown, "runner disconnected");
+				}
+			}
+			BrokerMessage::LinkFailed { packet, error } => {
+				let reason = format!("the link did not carry out {packet}: {error}");
+				// Including powering off the SUT after an earlier failure.
+				if self.state == State::Teardown {
+					error!("{reason}");
+				} else {
+					self.fail(&reason).await?;
+				}
+			}
+			unknown => {
+				error!("unexpected message sent to broker: {unknown:?}");
+				return Err(Error::UnexpectedPacket);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Notes what the runner has provisioned, and boots the SUT once it
+	/// has everything it needs.
+	async fn provision(&mut self, bootfile_size: bool, test_session: bool) -> Result<(), Error> {
+		let (has_bootfile_size, has_test_session) = match self.state {
+			State::Idle => {
+				self.job_started = Some(self.clock.now());
+				self.transition(
+					State::Provisioning {
+						has_bootfile_size: false,
+						has_test_session: false,
+					},
+					"runner started a job",
+				);
+				(false, false)
+			}
+			State::Provisioning {
+				has_bootfile_size,
+				has_test_session,
+			} => (has_bootfile_size, has_test_session),
+			state => {
+				warn!("runner re-provisioned its job while {state}");
+				return Ok(());
+			}
+		};
+
+		let has_bootfile_size = has_bootfile_size || bootfile_size;
+		let has_test_session = has_test_session || test_session;
+
+		if !(has_bootfile_size && has_test_session) {
+			self.state = State::Provisioning {
+				has_bootfile_size,
+				has_test_session,
+			};
+			return Ok(());
+		}
+
+		if self.capabilities.contains(Capabilities::MONITOR) {
+			// Turn on the monitor
+			self.send_link(DaemonToLink::SetMonitorStandby(false))
+				.await?;
+			// Then set the scene to the logo
+			self.send_link(DaemonToLink::SetScene(Scene::Logo)).await?;
+		}
+		// Turn on the machine
+		self.send_link(DaemonToLink::SetPowerState(PowerState::On))
+			.await?;
+		// Press the power button
+		self.send_link(DaemonToLink::PressPower).await?;
+
+		self.transition(State::Booting, "job provisioned; powered on the SUT");
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_std::channel::{unbounded as make_unbounded_channel, Receiver};
+	use link_protocol::{channel::ReplyError, NackReason};
+	use std::iter;
+
+	const MINUTE: Duration = Duration::from_secs(60);
+
+	/// Generous limits that tests shorten as needed.
+	fn timeouts() -> Timeouts {
+		Timeouts {
+			idle: 60 * MINUTE,
+			provisioning: 60 * MINUTE,
+			booting: 60 * MINUTE,
+			testing: 60 * MINUTE,
+			teardown: 60 * MINUTE,
+			job: 600 * MINUTE,
+			serial: Duration::MAX,
+		}
+	}
+
+	struct Harness {
+		started: Instant,
+		broker: Broker<ManualClock>,
+		link: Receiver<ControlMessage<DaemonToLink>>,
+		client: Receiver<ControlMessage<DaemonToClient>>,
+		docker: Receiver<ControlMessage<Infallible>>,
+	}
+
+	impl Harness {
+		fn new(timeouts: Timeouts) -> Self {
+			let (link_sender, link) = make_unbounded_channel();
+			let (client_sender, client) = make_unbounded_channel();
+			let (docker_sender, docker) = make_unbounded_channel();
+			let started = Instant::now();
+
+			Self {
+				started,
+				broker: Broker::new(
+					ManualClock::new(started),
+					Capabilities::MONITOR,
+					timeouts,
+					link_sender,
+					client_sender,
+					docker_sender,
+				),
+				link,
+				client,
+				docker,
+			}
+		}
+
+		/// Moves the clock to `after` the start of the session.
+		async fn at(&mut self, after: Duration) -> Result<(), Error> {
+			self.broker.run_until(self.started + after).await
+		}
+
+		async fn client(&mut self, packet: ClientToDaemon) {
+			self.broker
+				.handle(BrokerMessage::Client(ControlMessage::Packet(packet)))
+				.await
+				.unwrap();
+		}
+
+		async fn serial(&mut self) {
+			self.broker
+				.handle(BrokerMessage::Link(ControlMessage::Packet(
+					LinkToDaemon::Serial(b"ok\n".iter().copied().collect()),
+				)))
+				.await
+				.unwrap();
+		}
+
+		async fn bootfile_size(&mut self) {
+			self.client(ClientToDaemon::BootfileSize { uefi: 1, bios: 2 })
+				.await;
+		}
+
+		async fn test_session(&mut self) {
+			self.client(ClientToDaemon::StartTestSession {
+				total_tests: 1,
+				author: "author".try_into().unwrap(),
+				title: "title".try_into().unwrap(),
+				ref_id: "ref".try_into().unwrap(),
+			})
+			.await;
+		}
+
+		async fn start_test(&mut self) {
+			self.client(ClientToDaemon::StartTest {
+				name: "test".try_into().unwrap(),
+			})
+			.await;
+		}
+
+		/// Everything sent to the link since the last call.
+		fn link_packets(&self) -> Vec<DaemonToLink> {
+			iter::from_fn(|| self.link.try_recv().ok())
+				.map(|message| match message {
+					ControlMessage::Packet(packet) => packet,
+					_ => panic!("broker sent the link something other than a packet"),
+				})
+				.collect()
+		}
+
+		/// Everything sent to the runner since the last call.
+		fn client_packets(&self) -> Vec<DaemonToClient> {
+			iter::from_fn(|| self.client.try_recv().ok())
+				.map(|message| match message {
+					ControlMessage::Packet(packet) => packet,
+					_ => panic!("broker sent the runner something other than a packet"),
+				})
+				.collect()
+		}
+
+		fn docker_ended(&self) -> bool {
+			matches!(self.docker.try_recv(), Ok(ControlMessage::End))
+		}
+
+		/// Asserts that the session was failed for `reason`.
+		fn assert_failed(&self, reason: &str) {
+			assert_eq!(self.broker.state(), State::Teardown);
+			assert_eq!(
+				self.link_packets().last(),
+				Some(&DaemonToLink::SetPowerState(PowerState::Off))
+			);
+			assert_eq!(
+				self.client_packets(),
+				[DaemonToClient::SessionFailed {
+					reason: reason.try_into().unwrap()
+				}]
+			);
+			assert!(self.docker_ended());
+		}
+	}
+
+	fn boot_packets() -> Vec<DaemonToLink> {
+		vec![
+			DaemonToLink::SetMonitorStandby(false),
+			DaemonToLink::SetScene(Scene::Logo),
+			DaemonToLink::SetPowerState(PowerState::On),
+			DaemonToLink::PressPower,
+		]
+	}
+
+	#[async_std::test]
+	async fn runs_through_every_state() {
+		let mut harness = Harness::new(timeouts());
+		assert_eq!(harness.broker.state(), State::Idle);
+
+		harness.bootfile_size().await;
+		assert_eq!(
+			harness.broker.state(),
+			State::Provisioning {
+				has_bootfile_size: true,
+				has_test_session: false,
+			}
+		);
+		assert_eq!(
+			harness.link_packets(),
+			[DaemonToLink::BootfileSize { uefi: 1, bios: 2 }]
+		);
+
+		harness.test_session().await;
+		assert_eq!(harness.broker.state(), State::Booting);
+		assert_eq!(harness.link_packets()[1..], boot_packets());
+
+		harness.start_test().await;
+		assert_eq!(harness.broker.state(), State::Testing);
+		assert_eq!(
+			harness.link_packets(),
+			[
+				DaemonToLink::SetScene(Scene::Test),
+				DaemonToLink::StartTest {
+					name: "test".try_into().unwrap()
+				},
+			]
+		);
+
+		harness
+			.broker
+			.handle(BrokerMessage::Client(ControlMessage::End))
+			.await
+			.unwrap();
+		assert_eq!(harness.broker.state(), State::Teardown);
+		assert!(harness.docker_ended());
+		assert!(harness.client_packets().is_empty());
+	}
+
+	#[async_std::test]
+	async fn provisions_in_either_order() {
+		let mut harness = Harness::new(timeouts());
+
+		harness.test_session().await;
+		assert_eq!(
+			harness.broker.state(),
+			State::Provisioning {
+				has_bootfile_size: false,
+				has_test_session: true,
+			}
+		);
+		assert_eq!(harness.link_packets().len(), 1);
+
+		harness.bootfile_size().await;
+		assert_eq!(harness.broker.state(), State::Booting);
+		assert_eq!(harness.link_packets()[1..], boot_packets());
+	}
+
+	#[async_std::test]
+	async fn idle_sessions_time_out() {
+		let mut harness = Harness::new(Timeouts {
+			idle: MINUTE,
+			..timeouts()
+		});
+
+		harness.at(MINUTE - Duration::from_millis(1)).await.unwrap();
+		assert_eq!(harness.broker.state(), State::Idle);
+
+		harness.at(MINUTE).await.unwrap();
+		harness.assert_failed("the idle state timed out after 60s");
+	}
+
+	#[async_std::test]
+	async fn provisioning_times_out() {
+		let mut harness = Harness::new(Timeouts {
+			provisioning: MINUTE,
+			..timeouts()
+		});
+
+		harness.at(MINUTE).await.unwrap();
+		harness.bootfile_size().await;
+		harness.at(2 * MINUTE).await.unwrap();
+		harness.assert_failed("the provisioning state timed out after 60s");
+	}
+
+	#[async_std::test]
+	async fn booting_times_out() {
+		let mut harness = Harness::new(Timeouts {
+			booting: MINUTE,
+			..timeouts()
+		});
+
+		harness.bootfile_size().await;
+		harness.test_session().await;
+		harness.at(MINUTE).await.unwrap();
+		harness.assert_failed("the booting state timed out after 60s");
+	}
+
+	#[async_std::test]
+	async fn testing_times_out() {
+		let mut harness = Harness::new(Timeouts {
+			testing: MINUTE,
+			..timeouts()
+		});
+
+		harness.bootfile_size().await;
+		harness.test_session().await;
+		harness.at(MINUTE).await.unwrap();
+		harness.start_test().await;
+		// The limit applies from when testing started.
+		harness
+			.at(2 * MINUTE - Duration::from_millis(1))
+			.await
+			.unwrap();
+		assert_eq!(harness.broker.state(), State::Testing);
+
+		harness.at(2 * MINUTE).await.unwrap();
+		harness.assert_failed("the testing state timed out after 60s");
+	}
+
+	#[async_std::test]
+	async fn teardown_timing_out_ends_the_session() {
+		let mut harness = Harness::new(Timeouts {
+			idle: MINUTE,
+			teardown: MINUTE,
+			..timeouts()
+		});
+
+		harness.at(MINUTE).await.unwrap();
+		assert_eq!(harness.broker.state(), State::Teardown);
+
+		assert!(matches!(
+			harness.at(2 * MINUTE).await,
+			Err(Error::SessionTimedOut(State::Teardown))
+		));
+	}
+
+	#[async_std::test]
+	async fn jobs_have_a_deadline() {
+		let mut harness = Harness::new(Timeouts {
+			job: 10 * MINUTE,
+			..timeouts()
+		});
+
+		// The deadline counts from when provisioning started, however
+		// long the runner took to get there.
+		harness.at(30 * MINUTE).await.unwrap();
+		harness.bootfile_size().await;
+		harness.at(33 * MINUTE).await.unwrap();
+		harness.test_session().await;
+		harness.at(36 * MINUTE).await.unwrap();
+		harness.start_test().await;
+
+		harness
+			.at(40 * MINUTE - Duration::from_millis(1))
+			.await
+			.unwrap();
+		assert_eq!(harness.broker.state(), State::Testing);
+
+		harness.at(40 * MINUTE).await.unwrap();
+		harness.assert_failed("the job did not finish within 600s");
+
+		// And no longer applies once the session is being torn down.
+		harness.at(50 * MINUTE).await.unwrap();
+		assert_eq!(harness.broker.state(), State::Teardown);
+	}
+
+	#[async_std::test]
+	async fn resets_then_fails_quiet_suts() {
+		let mut harness = Harness::new(Timeouts {
+			serial: MINUTE,
+			..timeouts()
+		});
+
+		harness.bootfile_size().await;
+		harness.test_session().await;
+		harness.link_packets();
+
+		harness.at(MINUTE).await.unwrap();
+		assert_eq!(harness.broker.state(), State::Booting);
+		assert_eq!(harness.link_packets(), [DaemonToLink::PressReset]);
+
+		harness.at(2 * MINUTE).await.unwrap();
+		harness
+			.assert_failed("the SUT was quiet on its serial line for 60s, even after being reset");
+	}
+
+	#[async_std::test]
+	async fn serial_output_keeps_suts_alive() {
+		let mut harness = Harness::new(Timeouts {
+			serial: MINUTE,
+			..timeouts()
+		});
+
+		harness.bootfile_size().await;
+		harness.test_session().await;
+		harness.start_test().await;
+		harness.link_packets();
+
+		for half_minute in 1..20 {
+			harness.at(half_minute * MINUTE / 2).await.unwrap();
+			harness.serial().await;
+		}
+
+		assert_eq!(harness.broker.state(), State::Testing);
+		assert!(harness.link_packets().is_empty());
+	}
+
+	#[async_std::test]
+	async fn fails_sessions_when_the_link_fails() {
+		let mut harness = Harness::new(timeouts());
+		harness.bootfile_size().await;
+		harness.test_session().await;
+		harness.link_packets();
+
+		let link_failed = || BrokerMessage::LinkFailed {
+			packet: r#""PressPower""#.into(),
+			error: ReplyError::Rejected(NackReason::Failed),
+		};
+
+		harness.broker.handle(link_failed()).await.unwrap();
+		harness.assert_failed(
+			r#"the link did not carry out "PressPower": the peer rejected the request: failed"#,
+		);
+
+		// Once the session is being torn down, there's nothing left to fail.
+		harness.broker.handle(link_failed()).await.unwrap();
+		assert_eq!(harness.broker.state(), State::Teardown);
+		assert_eq!(harness.link_packets(), []);
+		assert_eq!(harness.client_packets(), []);
+	}
+
+	#[async_std::test]
+	async fn zero_disables_the_serial_watchdog() {
+		let timeouts = Timeouts::from_config(&SessionConfig {
+			idle_timeout_secs: 3600,
+			provisioning_timeout_secs: 300,
+			booting_timeout_secs: 600,
+			testing_timeout_secs: 3600,
+			teardown_timeout_secs: 120,
+			job_deadline_secs: 7200,
+			serial_watchdog_secs: 0,
+		});
+		assert_eq!(timeouts.serial, Duration::MAX);
+
+		let mut harness = Harness::new(timeouts);
+		harness.bootfile_size().await;
+		harness.test_session().await;
+		harness.start_test().await;
+		harness.link_packets();
+
+		harness.at(59 * MINUTE).await.unwrap();
+		assert_eq!(harness.broker.state(), State::Testing);
+		assert!(harness.link_packets().is_empty());
+	}
+}
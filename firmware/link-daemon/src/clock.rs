@@ -0,0 +1,38 @@
+//! Where the broker gets the time from: the system clock while serving
+//! links, or one that only moves when it's told to, so that replays and
+//! tests see the same timeouts every time.
+use std::time::Instant;
+
+pub(crate) trait Clock {
+	fn now(&self) -> Instant;
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+	#[inline]
+	fn now(&self) -> Instant {
+		Instant::now()
+	}
+}
+
+/// A clock that stands still until it's moved forward.
+pub(crate) struct ManualClock(Instant);
+
+impl ManualClock {
+	pub fn new(now: Instant) -> Self {
+		Self(now)
+	}
+
+	/// Moves the clock forward to `now`; it never goes backwards.
+	pub fn set(&mut self, now: Instant) {
+		self.0 = self.0.max(now);
+	}
+}
+
+impl Clock for ManualClock {
+	#[inline]
+	fn now(&self) -> Instant {
+		self.0
+	}
+}
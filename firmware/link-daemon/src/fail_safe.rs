@@ -0,0 +1,29 @@
+//! Link fail-safe policies, as they're passed via the environment.
+use link_protocol::FailSafePolicy;
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+	#[error("expected 'power-off' or 'keep-running:<minutes>'")]
+	Unknown,
+	#[error("invalid number of minutes: {0}")]
+	Minutes(#[from] std::num::ParseIntError),
+}
+
+/// Either `power-off` or `keep-running:<minutes>`.
+#[derive(Clone, Copy)]
+pub struct FailSafe(pub FailSafePolicy);
+
+impl FromStr for FailSafe {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.trim().split_once(':') {
+			None if s.trim() == "power-off" => Ok(Self(FailSafePolicy::PowerOff)),
+			Some(("keep-running", minutes)) => Ok(Self(FailSafePolicy::KeepRunning {
+				minutes: minutes.trim().parse()?,
+			})),
+			_ => Err(Error::Unknown),
+		}
+	}
+}
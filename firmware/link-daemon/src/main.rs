@@ -1,16 +1,28 @@
 #![feature(never_type, async_closure)]
 
+mod broker;
+mod clock;
 mod docker;
+mod fail_safe;
+mod recorder;
+mod replay;
 mod session;
+mod watchdog;
 
-use self::docker::Docker;
+use self::{docker::Docker, fail_safe::FailSafe};
 use async_std::{io, net::TcpListener, prelude::*, task};
 use envconfig::Envconfig;
 
-use link_protocol::{channel::RWError, Error as ProtoError};
+use link_protocol::{
+	channel::{HelloError, Identity, NegotiationError},
+	heartbeat::PeerDead,
+	keys::{Key, KeyList},
+	transcript::ReadError,
+	Error as ProtoError,
+};
 use log::{debug, error, info, warn};
 
-use std::str::FromStr;
+use std::{convert::Infallible, path::Path, process, str::FromStr};
 
 #[derive(Envconfig, Clone)]
 pub(crate) struct Config {
@@ -29,12 +41,81 @@ pub(crate) struct Config {
 	pub gh_access_token: String,
 	#[envconfig(from = "GH_ORGANIZATION")]
 	pub gh_organization: String,
+	/// The daemon's own identity secret (hex); its public key must be
+	/// pinned in the link firmware (`ORO_DAEMON_PUBLIC_KEY`).
+	#[envconfig(from = "LINK_IDENTITY_SECRET")]
+	pub identity_secret: Key,
+	/// Comma separated list of link identity public keys (hex) that are
+	/// allowed to connect.
+	#[envconfig(from = "LINK_TRUSTED_KEYS", default = "")]
+	pub trusted_link_keys: KeyList,
+	/// How often to ping the link, in milliseconds.
+	#[envconfig(from = "LINK_HEARTBEAT_INTERVAL", default = "1000")]
+	pub heartbeat_interval_ms: u64,
+	/// How many pings in a row the link may leave unanswered before the
+	/// session is considered dead.
+	#[envconfig(from = "LINK_HEARTBEAT_MAX_MISSED", default = "3")]
+	pub heartbeat_max_missed: u8,
+	/// How long a link may be disconnected before its session is ended,
+	/// in seconds.
+	#[envconfig(from = "LINK_RESUME_TIMEOUT", default = "300")]
+	pub resume_timeout_secs: u64,
+	/// How many bytes may be sent to a link before switching keys.
+	#[envconfig(from = "LINK_REKEY_BYTES", default = "67108864")]
+	pub rekey_bytes: u64,
+	/// How long a key may be used for before switching keys, in seconds.
+	#[envconfig(from = "LINK_REKEY_INTERVAL", default = "3600")]
+	pub rekey_interval_secs: u64,
+	/// What links do with the system under test while they can't reach
+	/// the daemon; either `power-off` or `keep-running:<minutes>`.
+	#[envconfig(from = "LINK_FAIL_SAFE", default = "keep-running:5")]
+	pub fail_safe: FailSafe,
+	#[envconfig(nested = true)]
+	pub session: SessionConfig,
+	/// Where to write a transcript of every packet in each session,
+	/// for replaying later. Sessions aren't recorded if unset.
+	#[envconfig(from = "LINK_TRANSCRIPT_DIR")]
+	pub transcript_dir: Option<String>,
 	#[envconfig(from = "LEVEL", default = "trace")]
 	pub log_level: String,
 	#[envconfig(from = "VERBOSE", default = "0")]
 	pub verbose: u8,
 }
 
+/// How long sessions may take; see [`broker::Timeouts`]. Also used when
+/// replaying sessions.
+#[derive(Envconfig, Clone)]
+pub(crate) struct SessionConfig {
+	/// How long a session may wait for its runner to pick up a job,
+	/// in seconds.
+	#[envconfig(from = "SESSION_IDLE_TIMEOUT", default = "3600")]
+	pub idle_timeout_secs: u64,
+	/// How long a runner has to provision its job (send the bootfile
+	/// sizes and the test session), in seconds.
+	#[envconfig(from = "SESSION_PROVISIONING_TIMEOUT", default = "300")]
+	pub provisioning_timeout_secs: u64,
+	/// How long the SUT has to boot into its first test, in seconds.
+	#[envconfig(from = "SESSION_BOOTING_TIMEOUT", default = "600")]
+	pub booting_timeout_secs: u64,
+	/// How long tests may run for, in seconds.
+	#[envconfig(from = "SESSION_TESTING_TIMEOUT", default = "3600")]
+	pub testing_timeout_secs: u64,
+	/// How long the runner's container has to go away once the job is
+	/// over, in seconds.
+	#[envconfig(from = "SESSION_TEARDOWN_TIMEOUT", default = "120")]
+	pub teardown_timeout_secs: u64,
+	/// How long a job may take overall, from the start of provisioning,
+	/// in seconds.
+	#[envconfig(from = "SESSION_JOB_DEADLINE", default = "7200")]
+	pub job_deadline_secs: u64,
+	/// How long the SUT may be quiet on its serial line before it's
+	/// considered hung, in seconds. It's reset after this long, then
+	/// powered off (failing the session) after this long again. Zero
+	/// disables the watchdog.
+	#[envconfig(from = "SERIAL_WATCHDOG_TIMEOUT", default = "300")]
+	pub serial_watchdog_secs: u64,
+}
+
 #[derive(thiserror::Error, Debug)]
 #[allow(clippy::enum_variant_names)]
 pub(crate) enum Error {
@@ -42,8 +123,14 @@ pub(crate) enum Error {
 	AsyncIo(#[from] io::Error),
 	#[error("i/o error during protocol transcoding")]
 	Proto(#[from] ProtoError<io::Error>),
-	#[error("i/o error during link connection negotiation")]
-	RWError(#[from] RWError<ProtoError<io::Error>, ProtoError<io::Error>>),
+	#[error("link connection negotiation failed: {0}")]
+	Negotiation(#[from] NegotiationError<ProtoError<io::Error>, ProtoError<io::Error>>),
+	#[error("link is incompatible with this daemon: {0}")]
+	Hello(#[from] HelloError<ProtoError<io::Error>, ProtoError<io::Error>>),
+	#[error("link stopped responding: {0}")]
+	PeerDead(#[from] PeerDead),
+	#[error("link did not reconnect in time to resume its session")]
+	ResumeTimedOut,
 	#[error("expected link to send LinkOnline but another packet was sent instead")]
 	NoHelloPacket,
 	#[error("unexpected packet was sent by peer (either link or client connection)")]
@@ -54,6 +141,14 @@ pub(crate) enum Error {
 	ChannelRecv,
 	#[error("failed to send channel message")]
 	ChannelSend,
+	#[error("session timed out while in the {0} state")]
+	SessionTimedOut(broker::State),
+	#[error("{0}")]
+	Transcript(#[from] ReadError),
+	#[error("session transcript has a malformed packet: {0}")]
+	MalformedPacket(#[from] ProtoError<Infallible>),
+	#[error("invalid configuration: {0}")]
+	Config(#[from] envconfig::Error),
 }
 
 impl From<async_std::channel::RecvError> for Error {
@@ -72,6 +167,15 @@ impl<T> From<async_std::channel::SendError<T>> for Error {
 
 #[async_std::main]
 async fn main() -> Result<!, Error> {
+	// `link-daemon replay <transcript>` replays a recorded session
+	// rather than serving links.
+	if let [command, transcript] = &std::env::args().skip(1).collect::<Vec<_>>()[..] {
+		if command == "replay" {
+			let same = replay::run(Path::new(transcript)).await?;
+			process::exit(if same { 0 } else { 1 });
+		}
+	}
+
 	let config = Config::init_from_env().unwrap();
 
 	let log_level = log::LevelFilter::from_str(&config.log_level)
@@ -116,6 +220,18 @@ async fn main() -> Result<!, Error> {
 
 	info!("starting oro-linkd version {}", env!("CARGO_PKG_VERSION"));
 
+	let identity = Identity::from_secret(config.identity_secret.0);
+	info!(
+		"daemon identity public key: {}",
+		hex::encode(identity.public_key())
+	);
+
+	if config.trusted_link_keys.0.is_empty() {
+		warn!(
+			"no trusted link keys were configured (LINK_TRUSTED_KEYS); all links will be rejected"
+		);
+	}
+
 	let docker = Docker::new(&config.docker_host).expect("failed to parse DOCKER_HOST uri");
 
 	debug!(
@@ -136,12 +252,16 @@ async fn main() -> Result<!, Error> {
 		config.link_server_bind, config.link_server_port
 	);
 
+	let sessions = session::Sessions::default();
+
 	while let Some(stream) = incoming.next().await {
 		let stream = stream?;
 		let config = config.clone();
+		let identity = identity.clone();
+		let sessions = sessions.clone();
 
 		task::spawn(async move {
-			if let Err(err) = self::session::run_session(config, stream).await {
+			if let Err(err) = self::session::accept_link(config, identity, sessions, stream).await {
 				error!("oro link peer connection encountered error: {:?}", err);
 			} else {
 				warn!("oro link peer connection ended with OK result");
@@ -0,0 +1,90 @@
+//! Records the packets of a session to a transcript (see
+//! `LINK_TRANSCRIPT_DIR`), for replaying with `link-daemon replay`.
+use link_protocol::{
+	channel::Incoming,
+	transcript::{Header, Route, Writer},
+	Capabilities, Serialize,
+};
+use log::{info, warn};
+use std::{
+	fs::File,
+	io::{self, BufWriter},
+	path::Path,
+	sync::{Arc, Mutex},
+	time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Records a session's packets, if recording is enabled. Failing to
+/// record is logged, but otherwise doesn't affect the session.
+#[derive(Clone, Default)]
+pub(crate) struct Recorder(Option<Arc<Transcript>>);
+
+struct Transcript {
+	started: Instant,
+	writer: Mutex<Writer<BufWriter<File>>>,
+}
+
+impl Recorder {
+	/// Starts a transcript in `dir`, named after the link and the time
+	/// the session started.
+	pub fn create(
+		dir: &Path,
+		link_id: &str,
+		version: &str,
+		capabilities: Capabilities,
+	) -> io::Result<Self> {
+		let started = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_millis() as u64;
+
+		let path = dir.join(format!("{link_id}-{started}.jsonl"));
+		let writer = Writer::new(
+			BufWriter::new(File::create(&path)?),
+			&Header {
+				link_id: link_id.into(),
+				version: version.into(),
+				capabilities,
+				started,
+			},
+		)?;
+
+		info!("recording session transcript to {}", path.display());
+
+		Ok(Self(Some(Arc::new(Transcript {
+			started: Instant::now(),
+			writer: Mutex::new(writer),
+		}))))
+	}
+
+	pub fn record<P>(&self, route: Route, packet: &P)
+	where
+		P: Serialize + serde::Serialize,
+	{
+		let Some(transcript) = &self.0 else {
+			return;
+		};
+
+		let millis = transcript.started.elapsed().as_millis() as u64;
+		if let Err(err) = transcript
+			.writer
+			.lock()
+			.unwrap()
+			.record(millis, route, packet)
+		{
+			warn!("failed to record packet to session transcript: {err}");
+		}
+	}
+
+	pub fn record_incoming<P>(&self, route: Route, packet: &Incoming<P>)
+	where
+		P: Serialize + serde::Serialize,
+	{
+		match packet {
+			Incoming::Packet(packet) => self.record(route, packet),
+			Incoming::Control(packet) => self.record(route, packet),
+			// There's nothing to record; it couldn't be decoded.
+			Incoming::Unsupported { .. } => {}
+		}
+	}
+}
@@ -0,0 +1,167 @@
+//! Replays a session transcript (see `LINK_TRANSCRIPT_DIR`) through the
+//! broker, and checks that it sends the link and the runner the same
+//! packets it did when the session was recorded:
+//!
+//! ```sh
+//! link-daemon replay /var/lib/oro-link/transcripts/<link>-<started>.jsonl
+//! ```
+//!
+//! The link and the runner are played back from the transcript, along
+//! with the link failing to carry out requests and the runner leaving.
+//! Packets that the session handles itself, rather than the broker (e.g.
+//! the link coming online, session tokens and heartbeats), are left out.
+//!
+//! Time is played back from the transcript too: the broker's clock only
+//! moves as far as each packet's timestamp, so session timeouts (as
+//! configured through the environment, like for the daemon itself) come
+//! into play exactly as they would have, without waiting for them.
+use crate::{
+	broker::{Broker, Timeouts},
+	clock::ManualClock,
+	session::{BrokerMessage, ControlMessage},
+	Error, SessionConfig,
+};
+use async_std::channel::unbounded as make_unbounded_channel;
+use envconfig::Envconfig;
+use link_protocol::{
+	channel::ReplyError,
+	json,
+	transcript::{self, Entry, Event, Route},
+	ClientToDaemon, DaemonToClient, DaemonToLink, LinkToDaemon,
+};
+use std::{
+	fs::File,
+	io::BufReader,
+	iter,
+	path::Path,
+	time::{Duration, Instant},
+};
+
+/// Replays the transcript at `path`, returning whether or not the
+/// broker behaved the same as when it was recorded.
+pub(crate) async fn run(path: &Path) -> Result<bool, Error> {
+	let (header, entries) = transcript::read(BufReader::new(File::open(path)?))?;
+	let entries = entries.collect::<Result<Vec<Entry>, _>>()?;
+	let timeouts = Timeouts::from_config(&SessionConfig::init_from_env()?);
+
+	println!(
+		"replaying session of link {} (firmware {}, capabilities {}) with {} packets",
+		header.link_id,
+		header.version,
+		header.capabilities,
+		entries.len()
+	);
+
+	let (link_sender, link_receiver) = make_unbounded_channel();
+	let (client_sender, client_receiver) = make_unbounded_channel();
+	let (docker_sender, _docker_receiver) = make_unbounded_channel();
+
+	let started = Instant::now();
+	let mut broker = Broker::new(
+		ManualClock::new(started),
+		header.capabilities,
+		timeouts,
+		link_sender,
+		client_sender,
+		docker_sender,
+	);
+
+	let mut recorded_link = Vec::new();
+	let mut recorded_client = Vec::new();
+	let mut failure = None;
+
+	for entry in &entries {
+		// Whatever the broker would have done on its own by now (i.e.
+		// deadlines passing) happens first.
+		if failure.is_none() {
+			let at = started + Duration::from_millis(entry.millis);
+			failure = broker.run_until(at).await.err();
+		}
+
+		if entry.is_control() {
+			continue;
+		}
+
+		let message = match entry.route {
+			Route::LinkToDaemon => match entry.decode()? {
+				LinkToDaemon::LinkOnline { .. } | LinkToDaemon::LinkResume { .. } => None,
+				packet => Some(BrokerMessage::Link(ControlMessage::Packet(packet))),
+			},
+			Route::ClientToDaemon => {
+				let packet: ClientToDaemon = entry.decode()?;
+				Some(BrokerMessage::Client(ControlMessage::Packet(packet)))
+			}
+			Route::DaemonToLink => {
+				match entry.decode()? {
+					DaemonToLink::Session { .. } | DaemonToLink::SetFailSafePolicy(_) => {}
+					packet => recorded_link.push(packet),
+				}
+				None
+			}
+			Route::DaemonToClient => {
+				recorded_client.push(entry.decode::<DaemonToClient>()?);
+				None
+			}
+			Route::Session => match entry.decode()? {
+				Event::LinkFailed { packet, reason } => Some(BrokerMessage::LinkFailed {
+					packet: json::to_line(&packet),
+					error: reason.map_or(ReplyError::TimedOut, ReplyError::Rejected),
+				}),
+				Event::ClientEnded => Some(BrokerMessage::Client(ControlMessage::End)),
+			},
+		};
+
+		// Once the broker fails, the session is over; the rest of the
+		// transcript is only compared against.
+		if let (Some(message), None) = (message, &failure) {
+			failure = broker.handle(message).await.err();
+		}
+	}
+
+	match failure {
+		Some(err) => println!("broker failed: {err}"),
+		None => println!("session ended in the {} state", broker.state()),
+	}
+
+	let replayed_link = iter::from_fn(|| link_receiver.try_recv().ok())
+		.filter_map(packet)
+		.collect::<Vec<_>>();
+	let replayed_client = iter::from_fn(|| client_receiver.try_recv().ok())
+		.filter_map(packet)
+		.collect::<Vec<_>>();
+
+	let link_matches = compare("link", &recorded_link, &replayed_link);
+	let client_matches = compare("runner", &recorded_client, &replayed_client);
+
+	Ok(link_matches && client_matches)
+}
+
+fn packet<P>(message: ControlMessage<P>) -> Option<P> {
+	match message {
+		ControlMessage::Packet(packet) => Some(packet),
+		_ => None,
+	}
+}
+
+/// Prints every packet that differs between the recording and the
+/// replay, returning whether or not they're the same.
+fn compare<P: PartialEq + serde::Serialize>(peer: &str, recorded: &[P], replayed: &[P]) -> bool {
+	let describe = |packet: Option<&P>| packet.map_or_else(|| "nothing".into(), json::to_line);
+
+	let mut matches = true;
+	for i in 0..recorded.len().max(replayed.len()) {
+		let (recorded, replayed) = (recorded.get(i), replayed.get(i));
+		if recorded != replayed {
+			matches = false;
+			println!("{peer} packet #{i} differs:");
+			println!("  recorded: {}", describe(recorded));
+			println!("  replayed: {}", describe(replayed));
+		}
+	}
+
+	if matches {
+		println!("{peer}: all {} packets match", recorded.len());
+	}
+
+	matches
+}
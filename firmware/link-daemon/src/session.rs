@@ -1,4 +1,9 @@
-use crate::{docker::Docker, Config, Error};
+use crate::{
+	broker::{handle_broker, Timeouts},
+	docker::Docker,
+	recorder::Recorder,
+	Config, Error,
+};
 use async_std::{
 	channel::{bounded as make_bounded_channel, Receiver, Sender},
 	fs,
@@ -7,11 +12,24 @@ use async_std::{
 	os::unix::net::UnixListener,
 	task::{self, JoinHandle},
 };
-use futures::{prelude::*, select};
-use link_protocol::{channel, Packet, PowerState, Scene};
+use futures::{pin_mut, prelude::*, select, stream};
+use link_protocol::{
+	channel::{self, Identity, Incoming, RekeyPolicy, ReplyError},
+	heartbeat::Heartbeat,
+	json,
+	transcript::{Event, Route},
+	Capabilities, ClientToDaemon, Control, DaemonToClient, DaemonToLink, LinkToDaemon, NackReason,
+};
 use log::{debug, error, info, trace, warn};
-use rand::rngs::OsRng;
-use std::{os::unix::fs::PermissionsExt, time::Duration};
+use rand::{rngs::OsRng, RngCore};
+use std::{
+	collections::{HashMap, VecDeque},
+	convert::Infallible,
+	os::unix::fs::PermissionsExt,
+	path::Path,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
 
 macro_rules! race_all_or_cancel {
 	($f1:expr) => {
@@ -33,41 +51,195 @@ macro_rules! race_all_or_cancel {
 
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
-enum ControlMessage {
-	EstablishedLink { id: String },
+pub(crate) enum ControlMessage<P> {
 	EstablishedServer { path: String },
-	Packet(Packet),
+	Packet(P),
 	End,
 }
 
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
-enum BrokerMessage {
-	Link(ControlMessage),
-	Client(ControlMessage),
+pub(crate) enum BrokerMessage {
+	Link(ControlMessage<LinkToDaemon>),
+	Client(ControlMessage<ClientToDaemon>),
+	/// The link didn't carry out a packet the session relies on (see
+	/// [`is_control_packet`]).
+	LinkFailed {
+		packet: String,
+		error: ReplyError,
+	},
+}
+
+type LinkSender = channel::PacketSender<BufWriter<TcpStream>, DaemonToLink>;
+type LinkReceiver = channel::PacketReceiver<BufReader<TcpStream>, LinkToDaemon>;
+
+/// A negotiated connection to a link.
+struct LinkConnection {
+	outgoing: LinkSender,
+	incoming: LinkReceiver,
+	capabilities: Capabilities,
+}
+
+/// Sessions that a link may reconnect to, by session token.
+#[derive(Clone, Default)]
+pub(crate) struct Sessions(Arc<Mutex<HashMap<[u8; 32], ResumableSession>>>);
+
+struct ResumableSession {
+	link_id: String,
+	reattach: Sender<LinkConnection>,
+}
+
+impl Sessions {
+	fn insert(&self, token: [u8; 32], link_id: String, reattach: Sender<LinkConnection>) {
+		self.0
+			.lock()
+			.unwrap()
+			.insert(token, ResumableSession { link_id, reattach });
+	}
+
+	fn remove(&self, token: &[u8; 32]) {
+		self.0.lock().unwrap().remove(token);
+	}
+
+	/// Hands the connection to the session with the given token, if it
+	/// exists and belongs to the same link. Otherwise, the connection
+	/// is given back.
+	async fn reattach(
+		&self,
+		token: &[u8; 32],
+		link_id: &str,
+		link: LinkConnection,
+	) -> Result<(), LinkConnection> {
+		let reattach = match self.0.lock().unwrap().get(token) {
+			Some(session) if session.link_id == link_id => session.reattach.clone(),
+			_ => return Err(link),
+		};
+
+		reattach.send(link).await.map_err(|err| err.into_inner())
+	}
+}
+
+/// Accepts a connection from a link, and either starts a new session
+/// for it or hands it over to the session it's resuming.
+pub(crate) async fn accept_link(
+	config: Config,
+	identity: Identity,
+	sessions: Sessions,
+	stream: TcpStream,
+) -> Result<(), Error> {
+	info!("starting link connection");
+
+	let mut link = connect_link(stream, &identity, &config.trusted_link_keys.0).await?;
+
+	// wait for the first real packet - the online packet - from the link
+	let online = link.incoming.receive().await?;
+	let (uid, version, token) = match &online {
+		Incoming::Packet(LinkToDaemon::LinkOnline { uid, version }) => {
+			(*uid, version.clone(), None)
+		}
+		Incoming::Packet(LinkToDaemon::LinkResume {
+			uid,
+			version,
+			token,
+		}) => (*uid, version.clone(), Some(*token)),
+		hello => {
+			error!("unexpected packet from link: {hello:?}");
+			return Err(Error::NoHelloPacket);
+		}
+	};
+
+	let link_id = hex::encode_upper(&uid[..]);
+	info!("link online: {link_id} (firmware version {version})");
+
+	if let Some(token) = token {
+		match sessions.reattach(&token, &link_id, link).await {
+			Ok(()) => {
+				info!("link {link_id} is resuming its session");
+				return Ok(());
+			}
+			Err(returned) => {
+				warn!("link {link_id} tried to resume an unknown session; starting a new one");
+				link = returned;
+			}
+		}
+	}
+
+	let mut token = [0u8; 32];
+	OsRng.fill_bytes(&mut token);
+
+	let recorder = match &config.transcript_dir {
+		Some(dir) => Recorder::create(Path::new(dir), &link_id, &version, link.capabilities)
+			.unwrap_or_else(|err| {
+				error!("failed to start session transcript in {dir}: {err}");
+				Recorder::default()
+			}),
+		None => Recorder::default(),
+	};
+	recorder.record_incoming(Route::LinkToDaemon, &online);
+
+	// Older links don't know about sessions and just reset when the
+	// connection drops.
+	if link.capabilities.contains(Capabilities::SESSION_RESUME) {
+		for packet in [
+			DaemonToLink::Session {
+				token,
+				resumed: false,
+			},
+			DaemonToLink::SetFailSafePolicy(config.fail_safe.0),
+		] {
+			recorder.record(Route::DaemonToLink, &packet);
+			link.outgoing.send(packet).await?;
+		}
+	}
+
+	let (reattach_sender, reattach_receiver) = make_bounded_channel(1);
+	sessions.insert(token, link_id.clone(), reattach_sender);
+	let result = run_session(
+		config,
+		identity,
+		link_id,
+		link,
+		token,
+		reattach_receiver,
+		recorder,
+	)
+	.await;
+	sessions.remove(&token);
+	result
 }
 
-pub(crate) async fn run_session(config: Config, link_stream: TcpStream) -> Result<(), Error> {
+async fn run_session(
+	config: Config,
+	identity: Identity,
+	link_id: String,
+	link: LinkConnection,
+	token: [u8; 32],
+	reattach: Receiver<LinkConnection>,
+	recorder: Recorder,
+) -> Result<(), Error> {
 	let (broker_sender, broker_receiver) = make_bounded_channel(32);
 	let (link_sender, link_receiver) = make_bounded_channel(32);
 	let (client_sender, client_receiver) = make_bounded_channel(32);
 	let (docker_sender, docker_receiver) = make_bounded_channel(2);
 
+	let capabilities = link.capabilities;
+	let timeouts = Timeouts::from_config(&config.session);
+
 	let link_handle = task::spawn(handle_link(
-		link_stream,
+		link,
+		token,
+		reattach,
+		LinkSettings::from_config(&config),
+		recorder.clone(),
 		broker_sender.clone(),
 		link_receiver,
 	));
 
-	// wait for the link to indicate it's established a connection
-	let link_id = match broker_receiver.recv().await? {
-		BrokerMessage::Link(ControlMessage::EstablishedLink { id }) => id,
-		_ => return Err(Error::NoHelloPacket),
-	};
-
 	// start the UDS server for the github actions runner
 	let client_handle = task::spawn(handle_client(
 		link_id.clone(),
+		identity,
+		recorder,
 		broker_sender.clone(),
 		client_receiver,
 	));
@@ -88,6 +260,8 @@ pub(crate) async fn run_session(config: Config, link_stream: TcpStream) -> Resul
 
 	// start the broker
 	let broker_handle = task::spawn(handle_broker(
+		capabilities,
+		timeouts,
 		broker_receiver,
 		link_sender,
 		client_sender,
@@ -97,152 +271,354 @@ pub(crate) async fn run_session(config: Config, link_stream: TcpStream) -> Resul
 	race_all_or_cancel!(link_handle, client_handle, docker_handle, broker_handle)
 }
 
-async fn handle_broker(
-	broker: Receiver<BrokerMessage>,
-	link: Sender<ControlMessage>,
-	client: Sender<ControlMessage>,
-	docker: Sender<ControlMessage>,
-) -> Result<(), Error> {
-	debug!("starting broker");
+/// Capabilities the daemon can't run a test session without.
+const REQUIRED_LINK_CAPABILITIES: Capabilities = Capabilities::SERIAL
+	.union(Capabilities::POWER_CONTROL)
+	.union(Capabilities::PXE);
 
-	let mut has_sent_bootfile_size = false;
-	let mut has_sent_test_session = false;
-	let mut has_started_test_session = false;
-	let mut has_started_first_test = false;
+/// How long the link has to acknowledge a control packet.
+const LINK_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
 
-	loop {
-		match broker.recv().await? {
-			BrokerMessage::Link(ControlMessage::Packet(Packet::Serial(data))) => {
-				client
-					.send(ControlMessage::Packet(Packet::Serial(data)))
-					.await?;
-			}
-			BrokerMessage::Client(ControlMessage::Packet(Packet::Serial(data))) => {
-				link.send(ControlMessage::Packet(Packet::Serial(data)))
-					.await?;
-			}
-			BrokerMessage::Client(ControlMessage::Packet(Packet::BootfileSize { uefi, bios })) => {
-				link.send(ControlMessage::Packet(Packet::BootfileSize { uefi, bios }))
-					.await?;
-				has_sent_bootfile_size = true;
-			}
-			BrokerMessage::Client(ControlMessage::Packet(Packet::PressPower)) => {
-				link.send(ControlMessage::Packet(Packet::PressPower))
-					.await?;
-			}
-			BrokerMessage::Client(ControlMessage::Packet(Packet::PressReset)) => {
-				link.send(ControlMessage::Packet(Packet::PressReset))
-					.await?;
-			}
-			BrokerMessage::Client(ControlMessage::Packet(Packet::StartTest { name })) => {
-				if !has_started_first_test {
-					has_started_first_test = true;
+/// Packets the link is expected to acknowledge, since the session
+/// can't really continue if they didn't take effect.
+fn is_control_packet(packet: &DaemonToLink) -> bool {
+	matches!(
+		packet,
+		DaemonToLink::SetPowerState(_)
+			| DaemonToLink::PressPower
+			| DaemonToLink::PressReset
+			| DaemonToLink::BootfileSize { .. }
+	)
+}
 
-					// Switch to testing scene
-					link.send(ControlMessage::Packet(Packet::SetScene(Scene::Test)))
-						.await?;
-				}
+/// Everything the daemon knows how to drive on a link.
+const DAEMON_CAPABILITIES: Capabilities = Capabilities::SERIAL
+	.union(Capabilities::POWER_CONTROL)
+	.union(Capabilities::PXE)
+	.union(Capabilities::USB_HID)
+	.union(Capabilities::PACKET_CAPTURE)
+	.union(Capabilities::MONITOR)
+	.union(Capabilities::HEARTBEAT)
+	.union(Capabilities::SESSION_RESUME)
+	.union(Capabilities::REKEY);
 
-				link.send(ControlMessage::Packet(Packet::StartTest { name }))
-					.await?;
-			}
-			BrokerMessage::Client(ControlMessage::Packet(Packet::StartTestSession {
-				total_tests,
-				author,
-				title,
-				ref_id,
-			})) => {
-				link.send(ControlMessage::Packet(Packet::StartTestSession {
-					total_tests,
-					author,
-					title,
-					ref_id,
-				}))
-				.await?;
-				has_sent_test_session = true;
-			}
-			BrokerMessage::Client(ControlMessage::End) => {
-				docker.send(ControlMessage::End).await?;
-			}
-			unknown => {
-				error!("unexpected message sent to broker: {unknown:?}");
-				return Err(Error::UnexpectedPacket);
-			}
-		}
+/// How many packets are held for a link while it's disconnected.
+const LINK_BACKLOG_LEN: usize = 256;
 
-		if has_sent_bootfile_size && has_sent_test_session && !has_started_test_session {
-			has_started_test_session = true;
-
-			// Turn on the monitor
-			link.send(ControlMessage::Packet(Packet::SetMonitorStandby(false)))
-				.await?;
-			// Then set the scene to the logo
-			link.send(ControlMessage::Packet(Packet::SetScene(Scene::Logo)))
-				.await?;
-			// Turn on the machine
-			link.send(ControlMessage::Packet(Packet::SetPowerState(
-				PowerState::On,
-			)))
-			.await?;
-			// Press the power button
-			link.send(ControlMessage::Packet(Packet::PressPower))
-				.await?;
+async fn connect_link(
+	stream: TcpStream,
+	identity: &Identity,
+	trusted_keys: &[[u8; 32]],
+) -> Result<LinkConnection, Error> {
+	let (outgoing, mut incoming) = {
+		// create buffered readers/writers for stream
+		let sock_reader = BufReader::new(stream.clone());
+		let sock_writer = BufWriter::new(stream);
+		channel::negotiate(
+			sock_writer,
+			sock_reader,
+			&mut OsRng,
+			channel::Side::Server,
+			identity,
+			trusted_keys,
+		)
+		.await?
+	};
+
+	info!("established link protocol channel");
+
+	let capabilities = channel::exchange_hello(
+		&outgoing,
+		&mut incoming,
+		DAEMON_CAPABILITIES,
+		REQUIRED_LINK_CAPABILITIES,
+	)
+	.await?;
+	info!("negotiated link capabilities: {capabilities}");
+
+	Ok(LinkConnection {
+		outgoing,
+		incoming,
+		capabilities,
+	})
+}
+
+/// How the connection to a link is kept alive and healthy.
+struct LinkSettings {
+	resume_timeout: Duration,
+	heartbeat_interval: Duration,
+	heartbeat_max_missed: u8,
+	rekey: RekeyPolicy,
+}
+
+impl LinkSettings {
+	fn from_config(config: &Config) -> Self {
+		Self {
+			resume_timeout: Duration::from_secs(config.resume_timeout_secs),
+			heartbeat_interval: Duration::from_millis(config.heartbeat_interval_ms),
+			heartbeat_max_missed: config.heartbeat_max_missed,
+			rekey: RekeyPolicy {
+				max_bytes: config.rekey_bytes,
+				max_millis: config.rekey_interval_secs * 1000,
+			},
 		}
 	}
 }
 
 async fn handle_link(
-	stream: TcpStream,
+	mut link: LinkConnection,
+	token: [u8; 32],
+	reattach: Receiver<LinkConnection>,
+	settings: LinkSettings,
+	recorder: Recorder,
 	broker: Sender<BrokerMessage>,
-	receiver: Receiver<ControlMessage>,
+	receiver: Receiver<ControlMessage<DaemonToLink>>,
 ) -> Result<(), Error> {
-	info!("starting link connection");
+	// packets from the broker that came in while the link was away
+	let mut backlog = VecDeque::new();
 
-	let (mut outgoing, mut incoming) = {
-		// create buffered readers/writers for stream
-		let sock_reader = BufReader::new(stream.clone());
-		let sock_writer = BufWriter::new(stream);
-		channel::negotiate(sock_writer, sock_reader, &mut OsRng, channel::Side::Server).await?
-	};
+	loop {
+		let result = serve_link(
+			&mut link,
+			&mut backlog,
+			&settings,
+			&recorder,
+			&broker,
+			&receiver,
+			&reattach,
+		)
+		.await;
 
-	info!("established link protocol channel");
+		match result {
+			Ok(replacement) => {
+				// The link reconnected before we noticed the old connection
+				// was gone; the new one is the one to trust.
+				info!("link reconnected; dropping its previous connection");
+				link = replacement;
+			}
+			// Links that don't know about sessions reset instead of
+			// resuming, so there's nothing to wait for.
+			Err(err @ (Error::Proto(_) | Error::PeerDead(_)))
+				if !link.capabilities.contains(Capabilities::SESSION_RESUME) =>
+			{
+				warn!("lost connection to link: {err}; it can't resume its session");
+				return Err(err);
+			}
+			Err(err @ (Error::Proto(_) | Error::PeerDead(_))) => {
+				warn!(
+					"lost connection to link: {err}; waiting up to {}s for it to resume",
+					settings.resume_timeout.as_secs()
+				);
+				link = wait_for_resume(&reattach, settings.resume_timeout, &receiver, &mut backlog)
+					.await?;
+			}
+			Err(err) => return Err(err),
+		}
 
-	// wait for first packet - the hello packet - from the link
-	let hello = incoming.receive().await?;
-	if let Packet::LinkOnline { uid, version } = hello {
-		let id = hex::encode_upper(&uid[..]);
-		info!("link online: {id} (firmware version {version})");
-		broker
-			.send(BrokerMessage::Link(ControlMessage::EstablishedLink { id }))
-			.await?;
-	} else {
-		error!("unexpected packet from link: {hello:?}");
-		return Err(Error::NoHelloPacket);
+		info!("link resumed its session");
+		let resumed = DaemonToLink::Session {
+			token,
+			resumed: true,
+		};
+		recorder.record(Route::DaemonToLink, &resumed);
+		if let Err(err) = link.outgoing.send(resumed).await {
+			warn!("failed to tell link its session was resumed: {err}");
+		}
+	}
+}
+
+/// Shuttles packets between the link and the broker until either the
+/// connection fails or the link reconnects, in which case the new
+/// connection is returned.
+async fn serve_link(
+	link: &mut LinkConnection,
+	backlog: &mut VecDeque<DaemonToLink>,
+	settings: &LinkSettings,
+	recorder: &Recorder,
+	broker: &Sender<BrokerMessage>,
+	receiver: &Receiver<ControlMessage<DaemonToLink>>,
+	reattach: &Receiver<LinkConnection>,
+) -> Result<LinkConnection, Error> {
+	while let Some(packet) = backlog.front() {
+		send_to_link(&link.outgoing, recorder, broker, packet.clone()).await?;
+		backlog.pop_front();
 	}
 
 	debug!("link connection negotiated; waiting for packets");
 
+	// Older links that don't answer pings are left to TCP to time out.
+	let started = Instant::now();
+	let rekey = link.capabilities.contains(Capabilities::REKEY);
+	let mut heartbeat = link
+		.capabilities
+		.contains(Capabilities::HEARTBEAT)
+		.then(|| Heartbeat::new(settings.heartbeat_max_missed));
+	let mut heartbeat_ticker = async_io::Timer::interval(settings.heartbeat_interval);
+
+	// Receiving carries on across iterations rather than starting over on
+	// each one, since dropping it halfway through a record would leave the
+	// rest of the record to be read as the next one.
+	let outgoing = &link.outgoing;
+	let incoming = stream::unfold(&mut link.incoming, |incoming| async move {
+		let packet = receive_from_link(incoming, outgoing, recorder).await;
+		Some((packet, incoming))
+	})
+	.fuse();
+	pin_mut!(incoming);
+
 	loop {
 		select! {
-			packet = incoming.receive().fuse() => {
-				trace!("link -> broker: {packet:?}");
-				broker.send(BrokerMessage::Link(ControlMessage::Packet(packet?))).await?;
+			packet = incoming.select_next_some() => match packet? {
+				Incoming::Control(Control::Ping { nonce }) => {
+					recorder.record(Route::LinkToDaemon, &Control::Ping { nonce });
+					let pong = Control::Pong { nonce };
+					recorder.record(Route::DaemonToLink, &pong);
+					outgoing.send_control(pong).await?;
+				}
+				Incoming::Control(Control::Pong { nonce }) => {
+					recorder.record(Route::LinkToDaemon, &Control::Pong { nonce });
+					if let Some(heartbeat) = heartbeat.as_mut() {
+						heartbeat.pong(nonce, started.elapsed().as_millis() as u64);
+						trace!("link round trip time: {:?}ms", heartbeat.rtt_millis());
+					}
+				}
+				Incoming::Control(packet) => {
+					recorder.record(Route::LinkToDaemon, &packet);
+					error!("unexpected control packet from link: {}", json::to_line(&packet));
+					return Err(Error::UnexpectedPacket);
+				}
+				Incoming::Packet(packet) => {
+					recorder.record(Route::LinkToDaemon, &packet);
+					trace!("link -> broker: {}", json::to_line(&packet));
+					broker.send(BrokerMessage::Link(ControlMessage::Packet(packet))).await?;
+				}
+				Incoming::Unsupported { seq } => {
+					warn!("link sent a request the daemon doesn't understand");
+					let nack = Control::Nack { seq, reason: NackReason::Unsupported };
+					recorder.record(Route::DaemonToLink, &nack);
+					outgoing.send_control(nack).await?;
+				}
+			},
+			_ = heartbeat_ticker.next().fuse() => {
+				let now = started.elapsed().as_millis() as u64;
+				if let Some(heartbeat) = heartbeat.as_mut() {
+					let ping = heartbeat.tick(now)?;
+					recorder.record(Route::DaemonToLink, &ping);
+					outgoing.send_control(ping).await?;
+				}
+				if rekey && outgoing.rekey_if_due(&mut OsRng, &settings.rekey, now).await?
+				{
+					debug!("started rekeying link connection");
+				}
 			},
 			packet = receiver.recv().fuse() => match packet? {
 				ControlMessage::Packet(packet) => {
-					trace!("broker -> link: {packet:?}");
-					outgoing.send(packet).await?;
+					trace!("broker -> link: {}", json::to_line(&packet));
+					send_to_link(outgoing, recorder, broker, packet).await?;
 				},
 				unknown => panic!("unexpected message from broker: {unknown:?}")
+			},
+			replacement = reattach.recv().fuse() => return Ok(replacement?),
+		}
+	}
+}
+
+/// Receives the link's next packet. Rekeys are answered on the way,
+/// as nothing else may be received until they are.
+async fn receive_from_link(
+	incoming: &mut LinkReceiver,
+	outgoing: &LinkSender,
+	recorder: &Recorder,
+) -> Result<Incoming<LinkToDaemon>, Error> {
+	loop {
+		match incoming.receive_request().await?.1 {
+			Incoming::Control(Control::Rekey { public_key }) => {
+				recorder.record(Route::LinkToDaemon, &Control::Rekey { public_key });
+				outgoing.answer_rekey(incoming, &public_key).await?;
+				debug!("rekeyed link connection");
 			}
+			packet => return Ok(packet),
+		}
+	}
+}
+
+/// Waits for the link to reconnect, holding on to whatever the broker
+/// sends it in the meantime.
+async fn wait_for_resume(
+	reattach: &Receiver<LinkConnection>,
+	timeout: Duration,
+	receiver: &Receiver<ControlMessage<DaemonToLink>>,
+	backlog: &mut VecDeque<DaemonToLink>,
+) -> Result<LinkConnection, Error> {
+	let mut deadline = future::FutureExt::fuse(async_io::Timer::after(timeout));
+
+	loop {
+		select! {
+			link = reattach.recv().fuse() => return Ok(link?),
+			packet = receiver.recv().fuse() => match packet? {
+				ControlMessage::Packet(packet) => {
+					if backlog.len() < LINK_BACKLOG_LEN {
+						backlog.push_back(packet);
+					} else {
+						warn!("link backlog is full; dropping packet: {}", json::to_line(&packet));
+					}
+				},
+				unknown => panic!("unexpected message from broker: {unknown:?}")
+			},
+			_ = deadline => return Err(Error::ResumeTimedOut),
 		}
 	}
 }
 
+/// Sends a packet to the link. The link's reply to control packets is
+/// waited on in the background, so as not to hold up the packets behind
+/// them; if it doesn't carry one out, the broker is told.
+async fn send_to_link(
+	outgoing: &LinkSender,
+	recorder: &Recorder,
+	broker: &Sender<BrokerMessage>,
+	packet: DaemonToLink,
+) -> Result<(), Error> {
+	recorder.record(Route::DaemonToLink, &packet);
+
+	if is_control_packet(&packet) {
+		let description = json::to_line(&packet);
+		let pending = outgoing.send_request(packet.clone()).await?;
+		let recorder = recorder.clone();
+		let broker = broker.clone();
+		task::spawn(async move {
+			match pending.wait(LINK_REPLY_TIMEOUT).await {
+				Ok(()) => debug!("link acknowledged {description}"),
+				Err(error) => {
+					error!("link did not carry out {description}: {error}");
+					let reason = match error {
+						ReplyError::Rejected(reason) => Some(reason),
+						ReplyError::TimedOut => None,
+					};
+					recorder.record(Route::Session, &Event::LinkFailed { packet, reason });
+					// The broker is only gone if the session is over.
+					let _ = broker
+						.send(BrokerMessage::LinkFailed {
+							packet: description,
+							error,
+						})
+						.await;
+				}
+			}
+		});
+	} else {
+		outgoing.send(packet).await?;
+	}
+
+	Ok(())
+}
+
 async fn handle_client(
 	link_id: String,
+	identity: Identity,
+	recorder: Recorder,
 	broker: Sender<BrokerMessage>,
-	receiver: Receiver<ControlMessage>,
+	receiver: Receiver<ControlMessage<DaemonToClient>>,
 ) -> Result<(), Error> {
 	info!("starting github actions runner server");
 
@@ -278,28 +654,67 @@ async fn handle_client(
 
 	info!("accepted connection from github actions runner");
 
-	let (mut outgoing, mut incoming) = {
+	let (outgoing, incoming): (
+		channel::PacketSender<_, DaemonToClient>,
+		channel::PacketReceiver<_, ClientToDaemon>,
+	) = {
 		let (sock_reader, sock_writer) = stream.split();
 		// create buffered readers/writers for stream
 		let sock_reader = BufReader::new(sock_reader);
 		let sock_writer = BufWriter::new(sock_writer);
-		channel::negotiate(sock_writer, sock_reader, &mut OsRng, channel::Side::Server).await?
+		// The socket is only reachable from the runner container,
+		// so there's no identity to pin on this end.
+		channel::negotiate(
+			sock_writer,
+			sock_reader,
+			&mut OsRng,
+			channel::Side::Server,
+			&identity,
+			&channel::TrustAny,
+		)
+		.await?
 	};
 
+	// Like with the link, receiving must never be dropped halfway through.
+	let incoming = stream::unfold(incoming, |mut incoming| async move {
+		let packet = incoming.receive_request().await.map(|(_, packet)| packet);
+		Some((packet, incoming))
+	})
+	.fuse();
+	pin_mut!(incoming);
+
 	loop {
 		select! {
-			packet = incoming.receive().fuse() => {
-				trace!("client -> broker: {packet:?}");
-				if let Ok(packet) = packet {
+			packet = incoming.select_next_some() => match packet {
+				Ok(Incoming::Packet(packet)) => {
+					recorder.record(Route::ClientToDaemon, &packet);
+					trace!("client -> broker: {}", json::to_line(&packet));
 					broker.send(BrokerMessage::Client(ControlMessage::Packet(packet))).await?;
-				} else {
+				}
+				Ok(Incoming::Control(packet)) => {
+					recorder.record(Route::ClientToDaemon, &packet);
+					warn!(
+						"ignoring control packet from github actions runner: {}",
+						json::to_line(&packet)
+					);
+				}
+				Ok(Incoming::Unsupported { seq }) => {
+					warn!("github actions runner sent a request the daemon doesn't understand");
+					let nack = Control::Nack { seq, reason: NackReason::Unsupported };
+					if outgoing.send_control(nack).await.is_err() {
+						warn!("github actions runner disconnected");
+						break;
+					}
+				}
+				Err(_) => {
 					warn!("github actions runner disconnected");
 					break;
 				}
 			},
 			packet = receiver.recv().fuse() => match packet? {
 				ControlMessage::Packet(packet) => {
-					trace!("broker -> client: {packet:?}");
+					trace!("broker -> client: {}", json::to_line(&packet));
+					recorder.record(Route::DaemonToClient, &packet);
 					if outgoing.send(packet).await.is_err() {
 						warn!("github actions runner disconnected");
 						break;
@@ -310,6 +725,7 @@ async fn handle_client(
 		}
 	}
 
+	recorder.record(Route::Session, &Event::ClientEnded);
 	broker
 		.send(BrokerMessage::Client(ControlMessage::End))
 		.await?;
@@ -323,7 +739,7 @@ async fn handle_docker(
 	config: Config,
 	link_id: String,
 	socket_path: String,
-	receiver: Receiver<ControlMessage>,
+	receiver: Receiver<ControlMessage<Infallible>>,
 ) -> Result<(), Error> {
 	let docker = Docker::new(&config.docker_host)?;
 
@@ -436,3 +852,195 @@ impl<'a> Drop for ContainerGuard<'a> {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_std::{future::timeout, net::TcpListener};
+	use envconfig::Envconfig;
+	use std::net::SocketAddr;
+
+	/// Links that can resume sessions, but don't need heartbeats or rekeys.
+	const RESUMABLE: Capabilities = REQUIRED_LINK_CAPABILITIES.union(Capabilities::SESSION_RESUME);
+
+	const UID: [u8; 32] = [7; 32];
+
+	type LinkEnd = (
+		channel::PacketSender<BufWriter<TcpStream>, LinkToDaemon>,
+		channel::PacketReceiver<BufReader<TcpStream>, DaemonToLink>,
+	);
+
+	fn daemon_identity() -> Identity {
+		Identity::from_secret([1; 32])
+	}
+
+	fn link_identity() -> Identity {
+		Identity::from_secret([2; 32])
+	}
+
+	fn config() -> Config {
+		let vars = [
+			("DOCKER_HOST", "unix:///var/run/docker.sock".into()),
+			("DOCKER_REF", "runner".into()),
+			("GH_ACCESS_TOKEN", "token".into()),
+			("GH_ORGANIZATION", "org".into()),
+			("LINK_IDENTITY_SECRET", hex::encode([1; 32])),
+			(
+				"LINK_TRUSTED_KEYS",
+				hex::encode(link_identity().public_key()),
+			),
+		];
+		Config::init_from_hashmap(&vars.map(|(var, value)| (var.into(), value)).into()).unwrap()
+	}
+
+	fn settings(resume_timeout: Duration) -> LinkSettings {
+		LinkSettings {
+			resume_timeout,
+			heartbeat_interval: Duration::from_secs(1),
+			heartbeat_max_missed: 3,
+			rekey: RekeyPolicy {
+				max_bytes: u64::MAX,
+				max_millis: u64::MAX,
+			},
+		}
+	}
+
+	/// Connects to the daemon at `address` the way a link does.
+	async fn connect_as_link(address: SocketAddr, capabilities: Capabilities) -> LinkEnd {
+		let stream = TcpStream::connect(address).await.unwrap();
+		let (outgoing, mut incoming) = channel::negotiate(
+			BufWriter::new(stream.clone()),
+			BufReader::new(stream),
+			&mut OsRng,
+			channel::Side::Client,
+			&link_identity(),
+			&daemon_identity().public_key(),
+		)
+		.await
+		.unwrap();
+		channel::exchange_hello(
+			&outgoing,
+			&mut incoming,
+			capabilities,
+			Capabilities::empty(),
+		)
+		.await
+		.unwrap();
+		(outgoing, incoming)
+	}
+
+	/// Connects a link to the daemon, returning both ends.
+	async fn connect(capabilities: Capabilities) -> (LinkConnection, LinkEnd) {
+		let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+		let address = listener.local_addr().unwrap();
+
+		future::join(
+			async {
+				let (stream, _) = listener.accept().await.unwrap();
+				connect_link(stream, &daemon_identity(), &[link_identity().public_key()])
+					.await
+					.unwrap()
+			},
+			connect_as_link(address, capabilities),
+		)
+		.await
+	}
+
+	#[async_std::test]
+	async fn links_resume_their_session_with_its_token() {
+		let token = [3; 32];
+		let sessions = Sessions::default();
+		let (reattach_sender, reattach) = make_bounded_channel(1);
+		sessions.insert(token, hex::encode_upper(UID), reattach_sender);
+
+		let (broker, _broker_receiver) = make_bounded_channel(1);
+		let (_link_sender, receiver) = make_bounded_channel(1);
+		let (link, link_end) = connect(RESUMABLE).await;
+		let session = task::spawn(handle_link(
+			link,
+			token,
+			reattach,
+			settings(Duration::from_secs(60)),
+			Recorder::default(),
+			broker,
+			receiver,
+		));
+
+		// The link drops its connection, and comes back with the token.
+		drop(link_end);
+		let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let accepted = task::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			accept_link(config(), daemon_identity(), sessions, stream).await
+		});
+
+		let (outgoing, mut incoming) = connect_as_link(address, RESUMABLE).await;
+		outgoing
+			.send(LinkToDaemon::LinkResume {
+				uid: UID,
+				version: "test".try_into().unwrap(),
+				token,
+			})
+			.await
+			.unwrap();
+
+		accepted.await.unwrap();
+		assert!(matches!(
+			incoming.receive().await.unwrap(),
+			Incoming::Packet(DaemonToLink::Session {
+				token: resumed_token,
+				resumed: true,
+			}) if resumed_token == token
+		));
+
+		session.cancel().await;
+	}
+
+	#[async_std::test]
+	async fn rejects_mismatched_session_tokens() {
+		let token = [3; 32];
+		let link_id = hex::encode_upper(UID);
+		let sessions = Sessions::default();
+		let (reattach_sender, reattach) = make_bounded_channel(1);
+		sessions.insert(token, link_id.clone(), reattach_sender);
+
+		let (link, _link_end) = connect(RESUMABLE).await;
+		let link = sessions
+			.reattach(&[4; 32], &link_id, link)
+			.await
+			.unwrap_err();
+		// Another link can't take over the session, even with its token.
+		let link = sessions
+			.reattach(&token, &hex::encode_upper([8; 32]), link)
+			.await
+			.unwrap_err();
+		assert!(reattach.is_empty());
+
+		assert!(sessions.reattach(&token, &link_id, link).await.is_ok());
+		assert!(reattach.try_recv().is_ok());
+	}
+
+	#[async_std::test]
+	async fn fails_sessions_that_cannot_resume_right_away() {
+		let (broker, _broker_receiver) = make_bounded_channel(1);
+		let (_link_sender, receiver) = make_bounded_channel(1);
+		let (_reattach_sender, reattach) = make_bounded_channel(1);
+		let (link, link_end) = connect(REQUIRED_LINK_CAPABILITIES).await;
+		let session = task::spawn(handle_link(
+			link,
+			[3; 32],
+			reattach,
+			settings(Duration::from_secs(3600)),
+			Recorder::default(),
+			broker,
+			receiver,
+		));
+
+		drop(link_end);
+		assert!(matches!(
+			timeout(Duration::from_secs(5), session).await,
+			Ok(Err(Error::Proto(_)))
+		));
+	}
+}
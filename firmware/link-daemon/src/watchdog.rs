@@ -0,0 +1,148 @@
+//! Watches the SUT's serial output while it's powered on. A SUT that
+//! goes quiet for too long has most likely hung, so the watchdog first
+//! has it reset, and if it stays quiet after that, has it powered off
+//! and the session failed.
+use std::time::{Duration, Instant};
+
+/// What to do about a quiet SUT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+	/// Press the reset button, in case the SUT comes back.
+	PressReset,
+	/// The SUT stayed quiet after being reset; power it off and fail
+	/// the session.
+	PowerOff,
+}
+
+pub(crate) struct Watchdog {
+	timeout: Duration,
+	/// When the SUT last printed something (or was last reset), while
+	/// the watchdog is armed.
+	since: Option<Instant>,
+	has_reset: bool,
+}
+
+impl Watchdog {
+	pub fn new(timeout: Duration) -> Self {
+		Self {
+			timeout,
+			since: None,
+			has_reset: false,
+		}
+	}
+
+	/// How long the SUT may stay quiet before the watchdog acts.
+	pub fn timeout(&self) -> Duration {
+		self.timeout
+	}
+
+	/// Starts watching, e.g. once the SUT has been powered on.
+	pub fn arm(&mut self, now: Instant) {
+		self.since = Some(now);
+		self.has_reset = false;
+	}
+
+	pub fn disarm(&mut self) {
+		self.since = None;
+	}
+
+	/// The SUT printed something, so it's still alive.
+	pub fn serial(&mut self, now: Instant) {
+		if self.since.is_some() {
+			self.arm(now);
+		}
+	}
+
+	/// When the watchdog will act, if it's armed.
+	pub fn deadline(&self) -> Option<Instant> {
+		self.since?.checked_add(self.timeout)
+	}
+
+	/// Called at the deadline; escalates each time the SUT stays quiet.
+	pub fn expire(&mut self, now: Instant) -> Action {
+		if self.has_reset {
+			self.disarm();
+			Action::PowerOff
+		} else {
+			self.since = Some(now);
+			self.has_reset = true;
+			Action::PressReset
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TIMEOUT: Duration = Duration::from_secs(300);
+
+	#[test]
+	fn only_has_a_deadline_while_armed() {
+		let now = Instant::now();
+		let mut watchdog = Watchdog::new(TIMEOUT);
+		assert_eq!(watchdog.deadline(), None);
+
+		watchdog.arm(now);
+		assert_eq!(watchdog.deadline(), Some(now + TIMEOUT));
+
+		watchdog.disarm();
+		assert_eq!(watchdog.deadline(), None);
+
+		// Serial output doesn't arm it, either.
+		watchdog.serial(now);
+		assert_eq!(watchdog.deadline(), None);
+	}
+
+	#[test]
+	fn resets_then_powers_off() {
+		let now = Instant::now();
+		let mut watchdog = Watchdog::new(TIMEOUT);
+		watchdog.arm(now);
+
+		let reset_at = now + TIMEOUT;
+		assert_eq!(watchdog.expire(reset_at), Action::PressReset);
+		assert_eq!(watchdog.deadline(), Some(reset_at + TIMEOUT));
+
+		assert_eq!(watchdog.expire(reset_at + TIMEOUT), Action::PowerOff);
+		assert_eq!(watchdog.deadline(), None);
+	}
+
+	#[test]
+	fn serial_output_after_a_reset_starts_over() {
+		let now = Instant::now();
+		let mut watchdog = Watchdog::new(TIMEOUT);
+		watchdog.arm(now);
+
+		let reset_at = now + TIMEOUT;
+		assert_eq!(watchdog.expire(reset_at), Action::PressReset);
+
+		// The SUT came back after being reset...
+		let serial_at = reset_at + TIMEOUT / 2;
+		watchdog.serial(serial_at);
+		assert_eq!(watchdog.deadline(), Some(serial_at + TIMEOUT));
+
+		// ...so the next time it goes quiet, it's reset again rather
+		// than powered off.
+		assert_eq!(watchdog.expire(serial_at + TIMEOUT), Action::PressReset);
+	}
+
+	#[test]
+	fn rearming_clears_the_reset() {
+		let now = Instant::now();
+		let mut watchdog = Watchdog::new(TIMEOUT);
+		watchdog.arm(now);
+		assert_eq!(watchdog.expire(now + TIMEOUT), Action::PressReset);
+
+		watchdog.disarm();
+		watchdog.arm(now + 2 * TIMEOUT);
+		assert_eq!(watchdog.expire(now + 3 * TIMEOUT), Action::PressReset);
+	}
+
+	#[test]
+	fn never_expires_without_a_timeout() {
+		let mut watchdog = Watchdog::new(Duration::MAX);
+		watchdog.arm(Instant::now());
+		assert_eq!(watchdog.deadline(), None);
+	}
+}
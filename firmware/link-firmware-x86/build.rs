@@ -190,6 +190,63 @@ fn render_font(id: &str, path: &str, charmap: &str) -> TokenStream {
 	}
 }
 
+/// Reads a 32 byte hex key from the environment. A missing key is an
+/// error, unless `missing` says what to use instead (with a warning).
+fn read_key(var: &str, missing: Option<[u8; 32]>) -> [u8; 32] {
+	println!("cargo:rerun-if-env-changed={}", var);
+
+	let Ok(hex) = env::var(var) else {
+		let Some(key) = missing else {
+			panic!("{} must be set to 32 hex-encoded bytes", var);
+		};
+		println!("cargo:warning={} is not set", var);
+		return key;
+	};
+
+	let hex = hex.trim();
+	assert!(
+		hex.len() == 64 && hex.is_ascii(),
+		"{} must be exactly 32 hex-encoded bytes",
+		var
+	);
+
+	let mut key = [0u8; 32];
+	for (i, byte) in key.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+			.unwrap_or_else(|_| panic!("{} is not valid hex", var));
+	}
+
+	key
+}
+
+fn render_keys() -> TokenStream {
+	// Without a pinned key, the link can't tell a real daemon from
+	// anyone else, so building without one has to be asked for. The
+	// all-zero key it falls back to is rejected during negotiation, so
+	// such a link can't talk to any daemon at all.
+	let daemon_public_key = read_key(
+		"ORO_DAEMON_PUBLIC_KEY",
+		env::var_os("CARGO_FEATURE_INSECURE_UNPINNED_DAEMON").map(|_| [0; 32]),
+	);
+	// Without a provisioned secret, the link's identity key follows from
+	// its chip ID alone, which anyone who can read the chip can derive.
+	let provisioned_secret = read_key(
+		"ORO_LINK_SECRET",
+		env::var_os("CARGO_FEATURE_INSECURE_UNPROVISIONED_SECRET").map(|_| [0; 32]),
+	);
+
+	quote! {
+		/// The daemon's identity public key. The link refuses to talk
+		/// to any daemon that doesn't present it.
+		pub const DAEMON_PUBLIC_KEY: [u8; 32] = [ #(#daemon_public_key),* ];
+
+		/// A secret provisioned per-deployment that is mixed into the link's
+		/// identity key derivation, such that the key can't be derived from the
+		/// chip's unique ID alone.
+		pub const PROVISIONED_SECRET: [u8; 32] = [ #(#provisioned_secret),* ];
+	}
+}
+
 pub fn main() {
 	let mut font_source = quote! { use super::FontData; };
 	font_source.extend(render_font(
@@ -210,5 +267,8 @@ pub fn main() {
 	let dest_path = Path::new(&out_dir).join("oro-link-fontdata.rs");
 	fs::write(dest_path, source).unwrap();
 
+	let dest_path = Path::new(&out_dir).join("oro-link-keys.rs");
+	fs::write(dest_path, render_keys().to_string()).unwrap();
+
 	println!("cargo:rerun-if-changed=build.rs");
 }
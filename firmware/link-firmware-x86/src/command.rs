@@ -7,7 +7,7 @@ use embassy_sync::{
 	channel::{Channel, Receiver, Sender},
 };
 use heapless::String;
-use link_protocol::Packet;
+use link_protocol::{resume::Reconnected, Control, DaemonToLink, LinkToDaemon};
 
 pub type CommandChannel<const SZ: usize> = Channel<NoopRawMutex, Command, SZ>;
 pub type CommandReceiver<const SZ: usize> = Receiver<'static, NoopRawMutex, Command, SZ>;
@@ -16,14 +16,20 @@ pub type CommandSender<const SZ: usize> = Sender<'static, NoopRawMutex, Command,
 #[derive(Format)]
 #[non_exhaustive]
 pub enum Command {
-	/// A new daemon connection has been established
-	DaemonConnected,
+	/// A new daemon connection has been established, which either
+	/// resumed the previous session or started a new one.
+	DaemonConnected(Reconnected),
 	/// The daemon connection was dropped/disconnected
 	DaemonDisconnected,
 	/// An incoming packet for processing
-	IncomingPacket(Packet),
+	IncomingPacket(DaemonToLink),
+	/// An incoming packet the daemon expects an `Ack`/`Nack` for
+	IncomingRequest { seq: u16, packet: DaemonToLink },
 	/// An outgoing packet for sending to the daemon
-	OutgoingPacket(Packet),
+	OutgoingPacket(LinkToDaemon),
+	/// An outgoing control packet (e.g. an `Ack`/`Nack`) for sending
+	/// to the daemon
+	OutgoingControl(Control),
 	/// Resets the link
 	Reset,
 	/// Changes the currently displayed scene
@@ -0,0 +1,5 @@
+//! Keys provisioned into the firmware at build time.
+//!
+//! See `build.rs` for the environment variables that populate these.
+
+include!(concat!(env!("OUT_DIR"), "/oro-link-keys.rs"));
@@ -6,6 +6,7 @@
 mod chip;
 mod command;
 mod font;
+mod keys;
 mod service;
 mod uc;
 
@@ -15,15 +16,19 @@ use core::cell::RefCell;
 use core::{panic::PanicInfo, task::Context};
 use defmt::{debug, error, info, warn};
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
 use embassy_net::{
 	driver::{Capabilities, HardwareAddress, LinkState, RxToken, TxToken},
 	Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4,
 };
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_usb as usb;
-use heapless::Vec;
-use link_protocol::{self as proto, Packet};
+use heapless::{Deque, Vec};
+use link_protocol::{
+	self as proto, channel::Identity, resume::Reconnected, Control, DaemonToLink, FailSafePolicy,
+	LinkToDaemon, NackReason,
+};
 use static_cell::make_static;
 use uc::{
 	DebugLed, Monitor, PowerState, ResetManager, Rng, Scene, SystemUnderTest, UniqueId, WallClock,
@@ -87,10 +92,12 @@ async fn time_task(
 async fn daemon_task(
 	stack: &'static Stack<impl uc::EthernetDriver>,
 	rng: impl Rng + 'static,
+	uid: [u8; 32],
+	identity: Identity,
 	broker_sender: CommandSender<8>,
 	daemon_receiver: CommandReceiver<4>,
 ) -> ! {
-	service::daemon::run(stack, rng, broker_sender, daemon_receiver).await
+	service::daemon::run(stack, rng, uid, identity, broker_sender, daemon_receiver).await
 }
 
 #[embassy_executor::task]
@@ -137,6 +144,9 @@ pub async fn main(spawner: Spawner) -> ! {
 
 	info!("link uid: {:?}", uid.unique_id());
 
+	let identity = Identity::from_secret(uid.identity_secret());
+	info!("link identity public key: {:?}", identity.public_key());
+
 	static mut BROKER_CHANNEL: CommandChannel<8> = CommandChannel::new();
 	static mut DAEMON_CHANNEL: CommandChannel<4> = CommandChannel::new();
 	static mut MONITOR_CHANNEL: CommandChannel<4> = CommandChannel::new();
@@ -204,7 +214,14 @@ pub async fn main(spawner: Spawner) -> ! {
 	spawner.must_spawn(usb_task(usb_builder, broker_sender, usb_receiver));
 
 	spawner.must_spawn(time_task(extnet, wall_clock));
-	spawner.must_spawn(daemon_task(extnet, rng, broker_sender, daemon_receiver));
+	spawner.must_spawn(daemon_task(
+		extnet,
+		rng,
+		uid.unique_id(),
+		identity,
+		broker_sender,
+		daemon_receiver,
+	));
 	spawner.must_spawn(serial_task(
 		syscom_tx,
 		syscom_rx,
@@ -212,44 +229,93 @@ pub async fn main(spawner: Spawner) -> ! {
 		serial_receiver,
 	));
 
+	// Whether or not there's currently a session with the daemon.
+	let mut connected = false;
+	// What to do with the system while the daemon is unreachable, and
+	// when to do it.
+	let mut fail_safe = FailSafePolicy::default();
+	let mut fail_safe_deadline: Option<Instant> = None;
+	// Serial output from the system while the daemon is unreachable,
+	// sent once the session is resumed. The oldest bytes are dropped
+	// when it fills up.
+	let mut serial_backlog = Deque::<u8, 2048>::new();
+	let mut serial_dropped = 0usize;
+
 	loop {
-		match broker_receiver.receive().await {
-			Command::IncomingPacket(Packet::SetScene(scene)) => {
-				monitor_sender
-					.send(Command::SetScene(match scene {
-						proto::Scene::Log => uc::Scene::Log,
-						proto::Scene::Logo => uc::Scene::OroLogo,
-						proto::Scene::Test => uc::Scene::Test,
-						unknown => {
-							warn!(
-								"daemon: requested to switch to unknown scene: {:?}",
-								unknown
-							);
-							continue;
-						}
-					}))
-					.await
+		let command = match fail_safe_deadline {
+			Some(deadline) => match select(broker_receiver.receive(), Timer::at(deadline)).await {
+				Either::First(command) => command,
+				Either::Second(()) => {
+					warn!(
+						"broker: daemon is still unreachable; fail-safe is powering off the system"
+					);
+					system.transition_power_state(PowerState::Off);
+					fail_safe_deadline = None;
+					continue;
+				}
+			},
+			None => broker_receiver.receive().await,
+		};
+
+		// Requests are handled just like any other incoming packet,
+		// except that the daemon is told whether or not they took effect.
+		let (seq, command) = match command {
+			Command::IncomingRequest { seq, packet } => {
+				(Some(seq), Command::IncomingPacket(packet))
 			}
-			Command::IncomingPacket(Packet::Log(entry)) => {
-				monitor_sender
-					.send(Command::Log(match entry {
-						proto::LogEntry::Info(msg) => uc::LogSeverity::Info.make(msg),
-						proto::LogEntry::Warn(msg) => uc::LogSeverity::Warn.make(msg),
-						proto::LogEntry::Error(msg) => uc::LogSeverity::Error.make(msg),
-						unknown => {
-							warn!(
-								"daemon: requested to log to monitor with unknown level: {:?}",
-								unknown
-							);
-							continue;
-						}
-					}))
-					.await
+			command => (None, command),
+		};
+
+		let outcome = match command {
+			Command::IncomingPacket(DaemonToLink::SetScene(scene)) => {
+				let scene = match scene {
+					proto::Scene::Log => Some(uc::Scene::Log),
+					proto::Scene::Logo => Some(uc::Scene::OroLogo),
+					proto::Scene::Test => Some(uc::Scene::Test),
+					unknown => {
+						warn!(
+							"daemon: requested to switch to unknown scene: {:?}",
+							unknown
+						);
+						None
+					}
+				};
+
+				match scene {
+					Some(scene) => {
+						monitor_sender.send(Command::SetScene(scene)).await;
+						Ok(())
+					}
+					None => Err(NackReason::Unsupported),
+				}
+			}
+			Command::IncomingPacket(DaemonToLink::Log(entry)) => {
+				let frame = match entry {
+					proto::LogEntry::Info(msg) => Some(uc::LogSeverity::Info.make(msg)),
+					proto::LogEntry::Warn(msg) => Some(uc::LogSeverity::Warn.make(msg)),
+					proto::LogEntry::Error(msg) => Some(uc::LogSeverity::Error.make(msg)),
+					unknown => {
+						warn!(
+							"daemon: requested to log to monitor with unknown level: {:?}",
+							unknown
+						);
+						None
+					}
+				};
+
+				match frame {
+					Some(frame) => {
+						monitor_sender.send(Command::Log(frame)).await;
+						Ok(())
+					}
+					None => Err(NackReason::Unsupported),
+				}
 			}
-			Command::IncomingPacket(Packet::SetMonitorStandby(standby)) => {
-				monitor_sender.send(Command::SetStandby(standby)).await
+			Command::IncomingPacket(DaemonToLink::SetMonitorStandby(standby)) => {
+				monitor_sender.send(Command::SetStandby(standby)).await;
+				Ok(())
 			}
-			Command::IncomingPacket(Packet::StartTestSession {
+			Command::IncomingPacket(DaemonToLink::StartTestSession {
 				total_tests,
 				author,
 				title,
@@ -262,72 +328,173 @@ pub async fn main(spawner: Spawner) -> ! {
 						title,
 						ref_id,
 					})
-					.await
+					.await;
+				Ok(())
 			}
-			Command::IncomingPacket(Packet::StartTest { name }) => {
-				monitor_sender.send(Command::StartTest { name }).await
+			Command::IncomingPacket(DaemonToLink::StartTest { name }) => {
+				monitor_sender.send(Command::StartTest { name }).await;
+				Ok(())
 			}
-			Command::IncomingPacket(Packet::SetPowerState(state)) => {
+			Command::IncomingPacket(DaemonToLink::SetPowerState(state)) => {
 				debug!("broker: transitioning to power state: {:?}", state);
-				system.transition_power_state(match state {
-					proto::PowerState::Off => PowerState::Off,
-					proto::PowerState::Standby => PowerState::Standby,
-					proto::PowerState::On => PowerState::On,
+				let state = match state {
+					proto::PowerState::Off => Some(PowerState::Off),
+					proto::PowerState::Standby => Some(PowerState::Standby),
+					proto::PowerState::On => Some(PowerState::On),
 					_ => {
 						warn!(
 							"broker: asked to transition to unknown power state: {:?}",
 							state
 						);
-						PowerState::Off
+						None
 					}
-				});
+				};
+
+				match state {
+					Some(state) => {
+						system.transition_power_state(state);
+						Ok(())
+					}
+					None => Err(NackReason::Unsupported),
+				}
 			}
-			Command::IncomingPacket(Packet::PressPower) => {
+			Command::IncomingPacket(DaemonToLink::PressPower) => {
 				debug!("broker: pressing the power button");
 				system.power();
+				Ok(())
 			}
-			Command::IncomingPacket(Packet::PressReset) => {
+			Command::IncomingPacket(DaemonToLink::PressReset) => {
 				debug!("broker: pressing the reset button");
 				system.reset();
+				Ok(())
 			}
-			Command::IncomingPacket(Packet::Serial(data)) => {
+			Command::IncomingPacket(DaemonToLink::Serial(data)) => {
 				serial_sender
-					.send(Command::IncomingPacket(Packet::Serial(data)))
+					.send(Command::IncomingPacket(DaemonToLink::Serial(data)))
 					.await;
+				Ok(())
 			}
-			Command::IncomingPacket(Packet::DebugUsbKey(key)) => {
+			Command::IncomingPacket(DaemonToLink::DebugUsbKey(key)) => {
 				usb_sender
-					.send(Command::IncomingPacket(Packet::DebugUsbKey(key)))
+					.send(Command::IncomingPacket(DaemonToLink::DebugUsbKey(key)))
 					.await;
+				Ok(())
 			}
-			Command::OutgoingPacket(packet) => {
+			Command::IncomingPacket(DaemonToLink::SetFailSafePolicy(policy)) => {
+				debug!("broker: fail-safe policy is now {:?}", policy);
+				fail_safe = policy;
+				Ok(())
+			}
+			Command::OutgoingPacket(packet) if connected => {
 				// Forward to daemon
 				daemon_sender.send(Command::OutgoingPacket(packet)).await;
+				Ok(())
 			}
-			Command::DaemonConnected => {
-				debug!("broker: telling daemon we're online");
-				daemon_sender
-					.send(Command::OutgoingPacket(Packet::LinkOnline {
-						uid: uid.unique_id(),
-						version: env!("CARGO_PKG_VERSION").try_into().unwrap(),
-					}))
-					.await;
+			Command::OutgoingPacket(LinkToDaemon::Serial(data)) => {
+				for byte in data {
+					if serial_backlog.is_full() {
+						serial_backlog.pop_front();
+						serial_dropped += 1;
+					}
+					let _ = serial_backlog.push_back(byte);
+				}
+				Ok(())
+			}
+			Command::OutgoingPacket(packet) => {
+				debug!(
+					"broker: dropping packet while daemon is unreachable: {:?}",
+					packet
+				);
+				Ok(())
+			}
+			Command::DaemonConnected(reconnected) => {
+				connected = true;
+				fail_safe_deadline = None;
+
+				if let Reconnected::NewSession { power_off } = reconnected {
+					if power_off {
+						warn!("broker: daemon started a new session; powering off the system");
+						system.transition_power_state(PowerState::Off);
+					}
+					serial_backlog.clear();
+					serial_dropped = 0;
+					fail_safe = FailSafePolicy::default();
+				} else {
+					info!("broker: daemon session resumed");
+					if serial_dropped > 0 {
+						warn!(
+							"broker: {:?} bytes of serial output were dropped while the daemon was unreachable",
+							serial_dropped
+						);
+						serial_dropped = 0;
+					}
+
+					while !serial_backlog.is_empty() {
+						let mut chunk = Vec::<u8, 256>::new();
+						while !chunk.is_full() {
+							let Some(byte) = serial_backlog.pop_front() else {
+								break;
+							};
+							let _ = chunk.push(byte);
+						}
+						daemon_sender
+							.send(Command::OutgoingPacket(LinkToDaemon::Serial(chunk)))
+							.await;
+					}
+				}
+
+				Ok(())
 			}
-			#[allow(clippy::diverging_sub_expression)]
 			Command::DaemonDisconnected => {
-				warn!("broker: daemon connection was dropped; resetting");
-				break;
+				connected = false;
+				match fail_safe {
+					FailSafePolicy::KeepRunning { minutes } => {
+						warn!(
+							"broker: daemon connection was dropped; keeping the system running for {:?} minutes",
+							minutes
+						);
+						fail_safe_deadline =
+							Some(Instant::now() + Duration::from_secs(u64::from(minutes) * 60));
+					}
+					_ => {
+						warn!("broker: daemon connection was dropped; powering off the system");
+						system.transition_power_state(PowerState::Off);
+					}
+				}
+				Ok(())
+			}
+			Command::SetScene(scene) => {
+				monitor_sender.send(Command::SetScene(scene)).await;
+				Ok(())
+			}
+			Command::Log(entry) => {
+				monitor_sender.send(Command::Log(entry)).await;
+				Ok(())
 			}
-			Command::SetScene(scene) => monitor_sender.send(Command::SetScene(scene)).await,
-			Command::Log(entry) => monitor_sender.send(Command::Log(entry)).await,
 			#[allow(clippy::diverging_sub_expression)]
-			Command::IncomingPacket(Packet::ResetLink) | Command::Reset => {
+			Command::IncomingPacket(DaemonToLink::ResetLink) | Command::Reset => {
 				warn!("broker: received request to reset");
+				// Acknowledge before going down; there won't be a chance afterwards.
+				if let Some(seq) = seq {
+					daemon_sender
+						.send(Command::OutgoingControl(Control::Ack { seq }))
+						.await;
+				}
 				break;
 			}
 			unknown => {
 				warn!("broker: unexpected command: {:?}", unknown);
+				Err(NackReason::Unsupported)
 			}
+		};
+
+		if let Some(seq) = seq {
+			daemon_sender
+				.send(Command::OutgoingControl(match outcome {
+					Ok(()) => Control::Ack { seq },
+					Err(reason) => Control::Nack { seq, reason },
+				}))
+				.await;
 		}
 	}
 
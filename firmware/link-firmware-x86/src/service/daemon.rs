@@ -1,27 +1,67 @@
 use crate::{
 	command::{Command, CommandReceiver, CommandSender},
-	uc,
+	keys, uc,
 };
+use core::cell::RefCell;
 use defmt::{debug, error, info, trace, warn};
-use embassy_futures::select::select;
+use embassy_futures::select::select3;
 use embassy_net::{driver::Driver, tcp::TcpSocket, ConfigV4, Ipv4Address, Stack};
-use embassy_time::{Duration, Timer};
-use link_protocol::channel::{negotiate, Side};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use link_protocol::{
+	channel::{
+		exchange_hello, negotiate, HelloError, Identity, Incoming, NegotiationError, RekeyPolicy,
+		Side, SEALED_RECORD_MAX_LEN,
+	},
+	heartbeat::Heartbeat,
+	resume::Resume,
+	Capabilities, Control, DaemonToLink, LinkToDaemon, NackReason,
+};
 
 const ORO_CICD_PORT: u16 = 1337;
+/// The size of each of the socket's buffers; enough for two full
+/// records in each direction, so that one can be sent (or received)
+/// while the other is still being filled.
+const SOCKET_BUF_LEN: usize = 2 * SEALED_RECORD_MAX_LEN;
+/// How often to ping the daemon.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+/// How many pings in a row the daemon may leave unanswered before
+/// the connection is dropped.
+const HEARTBEAT_MAX_MISSED: u8 = 3;
+/// When to switch to a new key for packets sent to the daemon.
+const REKEY_POLICY: RekeyPolicy = RekeyPolicy {
+	max_bytes: 64 * 1024 * 1024,
+	max_millis: 60 * 60 * 1000,
+};
+
+/// Everything this firmware supports. The daemon is free to not use
+/// some of it, so nothing is required of it in return.
+const LINK_CAPABILITIES: Capabilities = Capabilities::SERIAL
+	.union(Capabilities::POWER_CONTROL)
+	.union(Capabilities::PXE)
+	.union(Capabilities::USB_HID)
+	.union(Capabilities::PACKET_CAPTURE)
+	.union(Capabilities::MONITOR)
+	.union(Capabilities::HEARTBEAT)
+	.union(Capabilities::SESSION_RESUME)
+	.union(Capabilities::REKEY);
 
 pub async fn run<D: Driver + 'static, R: uc::Rng, const BSZ: usize, const DSZ: usize>(
 	stack: &Stack<D>,
 	mut rng: R,
+	uid: [u8; 32],
+	identity: Identity,
 	broker_sender: CommandSender<BSZ>,
 	daemon_receiver: CommandReceiver<DSZ>,
 ) -> ! {
-	static mut TX_BUF: [u8; 2048] = [0u8; 2048];
-	static mut RX_BUF: [u8; 2048] = [0u8; 2048];
+	static mut TX_BUF: [u8; SOCKET_BUF_LEN] = [0u8; SOCKET_BUF_LEN];
+	static mut RX_BUF: [u8; SOCKET_BUF_LEN] = [0u8; SOCKET_BUF_LEN];
 	let mut sock = TcpSocket::new(stack, unsafe { &mut RX_BUF[..] }, unsafe {
 		&mut TX_BUF[..]
 	});
 
+	// Kept across reconnects so that the session can be resumed.
+	let mut resume = Resume::new();
+
 	loop {
 		if !stack.is_link_up() {
 			warn!("daemon: link not up; will wait until it is before connecting to daemon");
@@ -55,26 +95,156 @@ pub async fn run<D: Driver + 'static, R: uc::Rng, const BSZ: usize, const DSZ: u
 
 		info!("daemon: negotiating daemon session");
 		let (receiver, sender) = sock.split();
-		let (mut sender, mut receiver) =
-			match negotiate(sender, receiver, &mut rng, Side::Client).await {
-				Ok(v) => v,
-				Err(err) => {
+		let (sender, mut receiver) = match negotiate(
+			sender,
+			receiver,
+			&mut rng,
+			Side::Client,
+			&identity,
+			&keys::DAEMON_PUBLIC_KEY,
+		)
+		.await
+		{
+			Ok(v) => v,
+			Err(NegotiationError::Rejected(side)) => {
+				error!(
+					"daemon: identity key was rejected by the {:?} side; retrying after 10s...",
+					side
+				);
+				Timer::after(Duration::from_secs(10)).await;
+				continue;
+			}
+			Err(err) => {
+				error!(
+					"daemon: failed to negotiate encrypted channel with daemon: {:?}",
+					err
+				);
+				continue;
+			}
+		};
+
+		debug!("daemon: encryption key negotiated, exchanging hello");
+		let capabilities = match exchange_hello(
+			&sender,
+			&mut receiver,
+			LINK_CAPABILITIES,
+			Capabilities::empty(),
+		)
+		.await
+		{
+			Ok(capabilities) => {
+				info!("daemon: negotiated capabilities: {:?}", capabilities);
+				capabilities
+			}
+			Err(err @ (HelloError::VersionMismatch { .. } | HelloError::NoHello)) => {
+				error!(
+					"daemon: daemon is incompatible with this firmware: {:?}; retrying after 60s...",
+					err
+				);
+				Timer::after(Duration::from_secs(60)).await;
+				continue;
+			}
+			Err(err) => {
+				error!("daemon: failed to exchange hello with daemon: {:?}", err);
+				continue;
+			}
+		};
+
+		debug!("daemon: hello exchanged, telling daemon we're online");
+		let supports_resume = capabilities.contains(Capabilities::SESSION_RESUME);
+		let version = env!("CARGO_PKG_VERSION").try_into().unwrap();
+		let online = resume.online(uid, version, supports_resume);
+
+		if let Err(err) = sender.send(online).await {
+			error!("daemon: failed to tell daemon we're online: {:?}", err);
+			continue;
+		}
+
+		let reconnected = if supports_resume {
+			match receiver.receive().await {
+				Ok(Incoming::Packet(DaemonToLink::Session { token, resumed })) => {
+					resume.session(token, resumed)
+				}
+				Ok(packet) => {
 					error!(
-						"daemon: failed to negotiate encrypted channel with daemon: {:?}",
-						err
+						"daemon: expected a session from the daemon, got: {:?}",
+						packet
 					);
 					continue;
 				}
-			};
+				Err(err) => {
+					error!("daemon: failed to receive session from daemon: {:?}", err);
+					continue;
+				}
+			}
+		} else {
+			resume.no_session()
+		};
 
-		debug!("daemon: encryption key negotiated, beginning communications");
-		broker_sender.send(Command::DaemonConnected).await;
+		debug!(
+			"daemon: session established ({:?}), beginning communications",
+			reconnected
+		);
+		broker_sender
+			.send(Command::DaemonConnected(reconnected))
+			.await;
+
+		// Older daemons that don't answer pings are left to TCP to time out.
+		let heartbeat = capabilities
+			.contains(Capabilities::HEARTBEAT)
+			.then(|| RefCell::new(Heartbeat::new(HEARTBEAT_MAX_MISSED)));
+		let heartbeat = &heartbeat;
+		let rekey = capabilities.contains(Capabilities::REKEY);
+		let sender = &sender;
+		let rng = &mut rng;
 
-		select(
+		select3(
 			async move {
 				loop {
-					match receiver.receive().await {
-						Ok(packet) => broker_sender.send(Command::IncomingPacket(packet)).await,
+					match receiver.receive_request().await {
+						Ok((_, Incoming::Control(Control::Ping { nonce }))) => {
+							if let Err(err) = sender.send_control(Control::Pong { nonce }).await {
+								error!("daemon: failed to answer ping: {:?}", err);
+								break;
+							}
+						}
+						Ok((_, Incoming::Control(Control::Rekey { public_key }))) => {
+							if let Err(err) = sender.answer_rekey(&mut receiver, &public_key).await
+							{
+								error!("daemon: failed to answer rekey: {:?}", err);
+								break;
+							}
+							debug!("daemon: rekeyed daemon connection");
+						}
+						Ok((_, Incoming::Control(Control::Pong { nonce }))) => {
+							if let Some(heartbeat) = heartbeat {
+								let mut heartbeat = heartbeat.borrow_mut();
+								heartbeat.pong(nonce, Instant::now().as_millis());
+								trace!("daemon: round trip time: {:?}ms", heartbeat.rtt_millis());
+							}
+						}
+						Ok((_, Incoming::Unsupported { seq })) => {
+							warn!("daemon: rejecting request the link doesn't understand");
+							let nack = Control::Nack {
+								seq,
+								reason: NackReason::Unsupported,
+							};
+							if let Err(err) = sender.send_control(nack).await {
+								error!("daemon: failed to reject request: {:?}", err);
+								break;
+							}
+						}
+						Ok((_, Incoming::Control(packet))) => {
+							warn!("daemon: ignoring unexpected control packet: {:?}", packet);
+						}
+						Ok((Some(seq), Incoming::Packet(packet))) => {
+							broker_sender
+								.send(Command::IncomingRequest { seq, packet })
+								.await
+						}
+						Ok((None, Incoming::Packet(packet))) => {
+							broker_sender.send(Command::IncomingPacket(packet)).await
+						}
 						Err(err) => {
 							error!(
 								"daemon: encountered an error receiving packet from daemon: {:?}",
@@ -94,12 +264,56 @@ pub async fn run<D: Driver + 'static, R: uc::Rng, const BSZ: usize, const DSZ: u
 								break;
 							}
 						}
+						Command::OutgoingControl(packet) => {
+							if let Err(err) = sender.send_control(packet).await {
+								error!("daemon: failed to send control packet: {:?}", err);
+								break;
+							}
+						}
 						unknown => {
 							warn!("daemon: ignoring unknown command: {:?}", unknown);
 						}
 					}
 				}
 			},
+			async move {
+				if heartbeat.is_none() && !rekey {
+					return core::future::pending::<()>().await;
+				}
+
+				let mut ticker = Ticker::every(HEARTBEAT_INTERVAL);
+				loop {
+					ticker.next().await;
+					let now = Instant::now().as_millis();
+
+					if let Some(heartbeat) = heartbeat {
+						let ping = heartbeat.borrow_mut().tick(now);
+						match ping {
+							Ok(ping) => {
+								if let Err(err) = sender.send_control(ping).await {
+									error!("daemon: failed to send ping: {:?}", err);
+									break;
+								}
+							}
+							Err(err) => {
+								error!("daemon: daemon stopped responding: {:?}", err);
+								break;
+							}
+						}
+					}
+
+					if rekey {
+						match sender.rekey_if_due(rng, &REKEY_POLICY, now).await {
+							Ok(true) => debug!("daemon: started rekeying daemon connection"),
+							Ok(false) => {}
+							Err(err) => {
+								error!("daemon: failed to rekey: {:?}", err);
+								break;
+							}
+						}
+					}
+				}
+			},
 		)
 		.await;
 
@@ -11,7 +11,7 @@ use crate::{
 use defmt::{trace, warn};
 use embassy_futures::select::{select, Either};
 use heapless::Vec;
-use link_protocol::Packet;
+use link_protocol::{DaemonToLink, LinkToDaemon};
 
 pub async fn run<TX: UartTx, RX: UartRx, const S: usize, const RC: usize>(
 	mut tx: TX,
@@ -36,7 +36,7 @@ pub async fn run<TX: UartTx, RX: UartRx, const S: usize, const RC: usize>(
 		.await;
 
 		match xmission {
-			Either::First(Command::IncomingPacket(Packet::Serial(data))) => {
+			Either::First(Command::IncomingPacket(DaemonToLink::Serial(data))) => {
 				trace!("serial: forwarding {} bytes to SUT", data.len());
 				if let Err(_err) = tx.write_all(&data[..]).await {
 					// FIXME(qix-): For some reason none of these errors are defmt'able.
@@ -49,7 +49,7 @@ pub async fn run<TX: UartTx, RX: UartRx, const S: usize, const RC: usize>(
 			Either::Second(Some(len)) => {
 				trace!("serial: forwarding {} bytes to daemon", len);
 				broker_sender
-					.send(Command::OutgoingPacket(Packet::Serial(
+					.send(Command::OutgoingPacket(LinkToDaemon::Serial(
 						Vec::from_slice(&rx_buf[..len]).unwrap(),
 					)))
 					.await;
@@ -7,7 +7,7 @@ use embassy_usb as usb;
 use embassy_usb::class::hid::{HidReaderWriter, ReportId, RequestHandler, State};
 use embassy_usb::control::OutResponse;
 use embassy_usb::Handler;
-use link_protocol::Packet;
+use link_protocol::DaemonToLink;
 use static_cell::make_static;
 use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
@@ -40,7 +40,7 @@ pub async fn run<D: usb::driver::Driver<'static>>(
 	// Wait for the USB peripheral to be ready.
 	loop {
 		let packet = usb_receiver.receive().await;
-		if let Command::IncomingPacket(Packet::DebugUsbKey(keycode)) = packet {
+		if let Command::IncomingPacket(DaemonToLink::DebugUsbKey(keycode)) = packet {
 			if keycode == 0 {
 				info!("got usb boot signal");
 				break;
@@ -63,7 +63,7 @@ pub async fn run<D: usb::driver::Driver<'static>>(
 	let in_fut = async {
 		loop {
 			let packet = usb_receiver.receive().await;
-			let Command::IncomingPacket(Packet::DebugUsbKey(keycode)) = packet else {
+			let Command::IncomingPacket(DaemonToLink::DebugUsbKey(keycode)) = packet else {
 				warn!("invalid packet received: {:?}", packet);
 				continue;
 			};
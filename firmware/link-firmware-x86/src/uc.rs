@@ -287,6 +287,13 @@ pub trait UniqueId {
 	/// Returns the unique ID from the device. Should be derived from a
 	/// SHA256 hash digest.
 	fn unique_id(&self) -> [u8; 32];
+
+	/// Returns the secret half of the device's long-term identity keypair,
+	/// used to authenticate the link to the daemon.
+	///
+	/// Must be derived alongside the unique ID but **must not** be derivable
+	/// from it, as the unique ID is sent to the daemon.
+	fn identity_secret(&self) -> [u8; 32];
 }
 
 /// Handles resetting the device
@@ -191,6 +191,21 @@ impl super::UniqueId for StmUniqueId {
 
 		sha256.finalize().into()
 	}
+
+	fn identity_secret(&self) -> [u8; 32] {
+		use sha2::Digest;
+
+		let mut sha256 = sha2::Sha256::new();
+
+		sha256.update(b"oro-link identity");
+		sha256.update(crate::keys::PROVISIONED_SECRET);
+
+		for i in 0..3 {
+			sha256.update(stm32_metapac::UID.uid(i).read().to_be_bytes());
+		}
+
+		sha256.finalize().into()
+	}
 }
 
 pub fn get_exteth_mac() -> [u8; 6] {
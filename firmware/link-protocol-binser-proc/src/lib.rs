@@ -1,34 +1,77 @@
 extern crate proc_macro;
 
 use proc_macro2::{Span, TokenStream};
-use quote::{quote, TokenStreamExt};
+use quote::{quote, ToTokens, TokenStreamExt};
 use std::collections::HashMap;
 use syn::{
+	bracketed,
 	parse::{Parse, ParseStream},
-	parse_macro_input,
+	parse_macro_input, parse_quote,
 	punctuated::Punctuated,
+	spanned::Spanned,
 	token::{Comma, Eq},
-	Error, Fields, Ident, ItemEnum, LitInt, Meta,
+	Attribute, Data, DataEnum, DataStruct, DeriveInput, Error, Expr, ExprLit, Field, Fields,
+	Generics, Ident, Index, Lit, LitInt, Meta, Type,
 };
 
 #[derive(Default)]
 struct ProtoMeta {
+	/// The keys that were given, for rejecting ones that don't apply.
+	keys: Vec<(&'static str, Span)>,
 	id: Option<u8>,
+	since: Option<u16>,
+	default: bool,
+	deprecated: bool,
+	retired: Vec<u8>,
 }
 
 enum ProtoMetaKV {
 	Id(u8),
+	Since(u16),
+	Default,
+	Deprecated,
+	Retired(Vec<u8>),
+}
+
+impl ProtoMetaKV {
+	fn key(&self) -> &'static str {
+		match self {
+			ProtoMetaKV::Id(_) => "id",
+			ProtoMetaKV::Since(_) => "since",
+			ProtoMetaKV::Default => "default",
+			ProtoMetaKV::Deprecated => "deprecated",
+			ProtoMetaKV::Retired(_) => "retired",
+		}
+	}
 }
 
 impl Parse for ProtoMetaKV {
 	fn parse(input: ParseStream<'_>) -> Result<Self, Error> {
 		let ident: Ident = input.parse()?;
-		let _: Eq = input.parse()?;
 		match ident.to_string().as_str() {
 			"id" => {
+				let _: Eq = input.parse()?;
 				let n: LitInt = input.parse()?;
 				Ok(ProtoMetaKV::Id(n.base10_parse::<u8>()?))
 			}
+			"since" => {
+				let _: Eq = input.parse()?;
+				let n: LitInt = input.parse()?;
+				Ok(ProtoMetaKV::Since(n.base10_parse::<u16>()?))
+			}
+			"default" => Ok(ProtoMetaKV::Default),
+			"deprecated" => Ok(ProtoMetaKV::Deprecated),
+			"retired" => {
+				let _: Eq = input.parse()?;
+				let content;
+				bracketed!(content in input);
+				let ids = Punctuated::<LitInt, Comma>::parse_terminated(&content)?;
+				let ids = ids
+					.iter()
+					.map(|n| n.base10_parse::<u8>())
+					.collect::<Result<_, _>>()?;
+				Ok(ProtoMetaKV::Retired(ids))
+			}
 			_ => Err(Error::new(
 				ident.span(),
 				"unknown link protocol `proto()` field",
@@ -39,14 +82,31 @@ impl Parse for ProtoMetaKV {
 
 impl Parse for ProtoMeta {
 	fn parse(input: ParseStream<'_>) -> Result<Self, Error> {
-		let kvs = Punctuated::<ProtoMetaKV, Comma>::parse_terminated(input)?;
+		let kvs =
+			Punctuated::<(Span, ProtoMetaKV), Comma>::parse_terminated_with(input, |input| {
+				Ok((input.span(), input.parse()?))
+			})?;
 		let mut meta = ProtoMeta::default();
 
-		for kv in kvs {
+		for (span, kv) in kvs {
+			meta.keys.push((kv.key(), span));
+
 			match kv {
 				ProtoMetaKV::Id(id) => {
 					meta.id = Some(id);
 				}
+				ProtoMetaKV::Since(version) => {
+					meta.since = Some(version);
+				}
+				ProtoMetaKV::Default => {
+					meta.default = true;
+				}
+				ProtoMetaKV::Deprecated => {
+					meta.deprecated = true;
+				}
+				ProtoMetaKV::Retired(ids) => {
+					meta.retired.extend(ids);
+				}
 			}
 		}
 
@@ -54,45 +114,496 @@ impl Parse for ProtoMeta {
 	}
 }
 
+impl ProtoMeta {
+	/// Parses the `#[proto(...)]` attributes, if any, rejecting keys
+	/// that don't apply to what they're on.
+	fn from_attrs(
+		attrs: &[Attribute],
+		allowed: &[&str],
+		what: &str,
+	) -> Result<Option<Self>, Error> {
+		let mut proto: Option<Self> = None;
+
+		for attr in attrs {
+			if let Meta::List(attr) = &attr.meta {
+				if attr.path.segments.len() == 1 && attr.path.segments[0].ident == "proto" {
+					let meta = attr.parse_args_with(ProtoMeta::parse)?;
+
+					for (key, span) in &meta.keys {
+						if !allowed.contains(key) {
+							return Err(Error::new(
+								*span,
+								format!("link protocol `proto({key})` is not allowed on {what}"),
+							));
+						}
+					}
+
+					proto = Some(match proto {
+						Some(mut proto) => {
+							proto.keys.extend(meta.keys);
+							proto.id = meta.id.or(proto.id);
+							proto.since = meta.since.or(proto.since);
+							proto.default |= meta.default;
+							proto.deprecated |= meta.deprecated;
+							proto.retired.extend(meta.retired);
+							proto
+						}
+						None => meta,
+					});
+				}
+			}
+		}
+
+		Ok(proto)
+	}
+
+	/// Whether a field may be left out by peers that predate it.
+	fn is_optional(&self) -> bool {
+		self.default || self.since.is_some()
+	}
+}
+
+/// Checks that a message's optional fields all come last, as a field
+/// can only be left out if nothing follows it.
+fn check_trailing(span: Span, optional: bool, seen_optional: &mut bool) -> Result<(), Error> {
+	if *seen_optional && !optional {
+		return Err(Error::new(
+			span,
+			"link protocol fields after a `#[proto(default)]` or `#[proto(since = ...)]` field must have one as well",
+		));
+	}
+
+	*seen_optional |= optional;
+	Ok(())
+}
+
 fn paste<A: ToString, B: ToString>(a: &A, b: &B) -> Ident {
 	let a = a.to_string();
 	let b = b.to_string();
 	Ident::new(&format!("{a}{b}"), Span::call_site())
 }
 
+/// Deserializes a field, attributing any error to it.
+///
+/// Optional fields fall back to their default if the frame ends before
+/// them, i.e. the peer predates them. Only the packet itself ends at the
+/// end of a frame, so fields can't be of types with optional fields.
+fn deserialize_field<N: ToString>(
+	type_name: &str,
+	variant: Option<&Ident>,
+	field: &N,
+	ty: &Type,
+	optional: bool,
+) -> TokenStream {
+	let field = match variant {
+		Some(variant) => format!("{type_name}::{variant}.{}", field.to_string()),
+		None => format!("{type_name}.{}", field.to_string()),
+	};
+
+	let nested =
+		format!("`{field}` has optional fields, so it can only be sent as a packet of its own");
+	let value = quote! {
+		{
+			const {
+				assert!(
+					!<(#ty) as ::link_protocol_binser::Deserialize>::HAS_OPTIONAL_FIELDS,
+					#nested
+				)
+			};
+
+			<(#ty) as ::link_protocol_binser::Deserialize>::deserialize(reader)
+				.await
+				.map_err(|err| err.in_field(#field))?
+		}
+	};
+
+	if optional {
+		quote! {
+			if ::link_protocol_binser::Read::is_exhausted(reader) {
+				<(#ty) as ::core::default::Default>::default()
+			} else {
+				#value
+			}
+		}
+	} else {
+		value
+	}
+}
+
+fn max_encoded_len(ty: &Type) -> TokenStream {
+	quote! {
+		.saturating_add(<(#ty) as ::link_protocol_binser::MaxEncodedLen>::MAX_ENCODED_LEN)
+	}
+}
+
+/// A random value for a field, for the `arbitrary` feature.
+fn generate_field(ty: &Type) -> TokenStream {
+	quote! {
+		<(#ty) as ::link_protocol_binser::arbitrary::Generate<'arbitrary>>::generate(u)?
+	}
+}
+
+/// Implements `Generate` and `Arbitrary` for a message, given the
+/// expression that builds a random one out of `u`. Nothing is emitted
+/// unless the `arbitrary` feature is enabled.
+fn arbitrary_impl(ident: &Ident, generics: &Generics, body: TokenStream) -> TokenStream {
+	if !cfg!(feature = "arbitrary") {
+		return quote! {};
+	}
+
+	let mut with_lifetime = generics.clone();
+	with_lifetime.params.insert(0, parse_quote!('arbitrary));
+	let (generics_pre, _, _) = with_lifetime.split_for_impl();
+	let (_, generics_mid, generics_post) = generics.split_for_impl();
+
+	quote! {
+		#[automatically_derived]
+		impl #generics_pre ::link_protocol_binser::arbitrary::Generate<'arbitrary> for #ident #generics_mid #generics_post {
+			fn generate(u: &mut ::link_protocol_binser::arbitrary::Unstructured<'arbitrary>) -> ::link_protocol_binser::arbitrary::Result<Self> {
+				Ok(#body)
+			}
+		}
+
+		#[automatically_derived]
+		impl #generics_pre ::link_protocol_binser::arbitrary::Arbitrary<'arbitrary> for #ident #generics_mid #generics_post {
+			#[inline]
+			fn arbitrary(u: &mut ::link_protocol_binser::arbitrary::Unstructured<'arbitrary>) -> ::link_protocol_binser::arbitrary::Result<Self> {
+				<Self as ::link_protocol_binser::arbitrary::Generate<'arbitrary>>::generate(u)
+			}
+		}
+	}
+}
+
+/// Picks one of `arms` (each a `usize` match arm) at random.
+fn choose_arm(arms: Vec<TokenStream>) -> TokenStream {
+	let count = arms.len();
+	let mut arms_stream = TokenStream::new();
+	arms_stream.append_all(arms);
+
+	quote! {
+		match u.choose_index(#count)? {
+			#arms_stream
+			_ => unreachable!(),
+		}
+	}
+}
+
+fn field_schema<N: ToString>(name: &N, ty: &Type, proto: &ProtoMeta) -> TokenStream {
+	let name = name.to_string();
+	let ty_tokens = ty;
+	let ty: String = ty
+		.to_token_stream()
+		.to_string()
+		.chars()
+		.filter(|c| !c.is_whitespace())
+		.collect();
+
+	let optional = proto.is_optional();
+	let since = match proto.since {
+		Some(since) => quote! { Some(#since) },
+		None => quote! { None },
+	};
+
+	quote! {
+		::link_protocol_binser::schema::FieldSchema {
+			name: #name,
+			ty: #ty,
+			encoding: <(#ty_tokens) as ::link_protocol_binser::schema::Encoded>::ENCODING,
+			optional: #optional,
+			since: #since,
+		},
+	}
+}
+
+/// Parses a variant field's `#[proto(...)]` attributes, if any.
+fn field_proto(field: &Field, seen_optional: &mut bool) -> Result<ProtoMeta, Error> {
+	let proto =
+		ProtoMeta::from_attrs(&field.attrs, &["since", "default"], "fields")?.unwrap_or_default();
+	check_trailing(field.span(), proto.is_optional(), seen_optional)?;
+	Ok(proto)
+}
+
+/// Struct fields can't be optional: structs are always nested in
+/// another message, so they never end at the end of a frame.
+fn struct_field_proto(field: &Field) -> Result<ProtoMeta, Error> {
+	ProtoMeta::from_attrs(
+		&field.attrs,
+		&[],
+		"struct fields, as structs are always nested in another message; add the field to an enum variant instead",
+	)?;
+	Ok(ProtoMeta::default())
+}
+
+/// Parses an enum's `#[proto(retired = [...])]` attribute, listing the
+/// ids of variants that have been removed.
+fn retired_ids(attrs: &[Attribute]) -> Result<Vec<u8>, Error> {
+	Ok(ProtoMeta::from_attrs(attrs, &["retired"], "enums")?
+		.map(|proto| proto.retired)
+		.unwrap_or_default())
+}
+
+/// Peers built before a variant was removed may still send its id, so
+/// it must never be given to another variant.
+fn check_retired(ident: &Ident, discriminant: u8, retired: &[u8]) -> Result<(), Error> {
+	if retired.contains(&discriminant) {
+		return Err(Error::new(
+			ident.span(),
+			format!(
+				"link protocol enum variant uses `id` {discriminant}, which was retired and can't be reused"
+			),
+		));
+	}
+
+	Ok(())
+}
+
 #[proc_macro_derive(LinkMessage, attributes(proto))]
 pub fn derive_link_protocol_message(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
-	let ast = parse_macro_input!(item as ItemEnum);
+	let ast = parse_macro_input!(item as DeriveInput);
 
-	let enum_ident = ast.ident.clone();
+	match ast.data {
+		Data::Enum(data) => derive_enum(ast.ident, &ast.attrs, ast.generics, data),
+		Data::Struct(data) => derive_struct(ast.ident, ast.generics, data),
+		Data::Union(data) => Error::new(
+			data.union_token.span,
+			"link protocol messages must be enums or structs",
+		)
+		.into_compile_error()
+		.into(),
+	}
+}
 
-	let mut known_discriminants = HashMap::<u8, Ident>::new();
+/// Structs are encoded as their fields, in declaration order, with
+/// no framing of their own.
+fn derive_struct(ident: Ident, generics: Generics, data: DataStruct) -> proc_macro::TokenStream {
+	let type_name = ident.to_string();
+	let mut serialize_statements = Vec::new();
+	let mut field_schemas = Vec::new();
+	let mut field_lens = Vec::new();
 
-	let mut serialize_matches = Vec::new();
-	let mut deserialize_matches = Vec::new();
+	let (construction, generation) = match data.fields {
+		Fields::Named(named) => {
+			let mut field_inits = Vec::new();
+			let mut field_generators = Vec::new();
 
-	for variant in ast.variants {
-		let ident = variant.ident;
+			for field in named.named {
+				let proto = match struct_field_proto(&field) {
+					Ok(proto) => proto,
+					Err(err) => return err.into_compile_error().into(),
+				};
+				let ident = field.ident.unwrap();
+				let fieldtype = &field.ty;
 
-		let mut serialize_statements = Vec::new();
+				field_schemas.push(field_schema(&ident, fieldtype, &proto));
+				field_lens.push(max_encoded_len(fieldtype));
 
-		let mut proto = None;
-		for attr in variant.attrs {
-			if let Meta::List(attr) = attr.meta {
-				if attr.path.segments.len() == 1 && attr.path.segments[0].ident == "proto" {
-					match attr.parse_args_with(ProtoMeta::parse) {
-						Ok(p) => {
-							proto = Some(p);
-						}
-						Err(err) => return err.into_compile_error().into(),
+				serialize_statements.push(quote! {
+					::link_protocol_binser::Serialize::serialize(&self.#ident, writer).await?;
+				});
+
+				let value = deserialize_field(&type_name, None, &ident, fieldtype, false);
+				field_inits.push(quote! {
+					#ident : #value,
+				});
+
+				let value = generate_field(fieldtype);
+				field_generators.push(quote! {
+					#ident : #value,
+				});
+			}
+
+			let mut field_inits_stream = TokenStream::new();
+			field_inits_stream.append_all(field_inits);
+			let mut field_generators_stream = TokenStream::new();
+			field_generators_stream.append_all(field_generators);
+
+			(
+				quote! {
+					Self {#field_inits_stream}
+				},
+				quote! {
+					Self {#field_generators_stream}
+				},
+			)
+		}
+		Fields::Unnamed(fields) => {
+			let mut field_inits = Vec::new();
+			let mut field_generators = Vec::new();
+
+			for (i, field) in fields.unnamed.iter().enumerate() {
+				let proto = match struct_field_proto(field) {
+					Ok(proto) => proto,
+					Err(err) => return err.into_compile_error().into(),
+				};
+				let index = Index::from(i);
+				let fieldtype = &field.ty;
+
+				field_schemas.push(field_schema(&i, fieldtype, &proto));
+				field_lens.push(max_encoded_len(fieldtype));
+
+				serialize_statements.push(quote! {
+					::link_protocol_binser::Serialize::serialize(&self.#index, writer).await?;
+				});
+
+				let value = deserialize_field(&type_name, None, &i, fieldtype, false);
+				field_inits.push(quote! {
+					#value,
+				});
+
+				let value = generate_field(fieldtype);
+				field_generators.push(quote! {
+					#value,
+				});
+			}
+
+			let mut field_inits_stream = TokenStream::new();
+			field_inits_stream.append_all(field_inits);
+			let mut field_generators_stream = TokenStream::new();
+			field_generators_stream.append_all(field_generators);
+
+			(
+				quote! {
+					Self(#field_inits_stream)
+				},
+				quote! {
+					Self(#field_generators_stream)
+				},
+			)
+		}
+		Fields::Unit => {
+			serialize_statements.push(quote! {
+				let _ = writer;
+			});
+
+			(
+				quote! {
+					{
+						let _ = reader;
+						Self
 					}
+				},
+				quote! {
+					{
+						let _ = u;
+						Self
+					}
+				},
+			)
+		}
+	};
+
+	let encoded = encoded_impl(&ident, &generics);
+	let arbitrary = arbitrary_impl(&ident, &generics, generation);
+
+	let (generics_pre, generics_mid, generics_post) = generics.split_for_impl();
+
+	let mut serialize_statements_stream = TokenStream::new();
+	serialize_statements_stream.append_all(serialize_statements);
+	let mut field_schemas_stream = TokenStream::new();
+	field_schemas_stream.append_all(field_schemas);
+	let mut field_lens_stream = TokenStream::new();
+	field_lens_stream.append_all(field_lens);
+
+	quote! {
+		const _: () = {
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::Schema for #ident #generics_mid #generics_post {
+				const SCHEMA: ::link_protocol_binser::schema::MessageSchema = ::link_protocol_binser::schema::MessageSchema {
+					name: #type_name,
+					body: ::link_protocol_binser::schema::SchemaBody::Struct(&[#field_schemas_stream]),
+					retired: &[],
+				};
+			}
+
+			#encoded
+
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::MaxEncodedLen for #ident #generics_mid #generics_post {
+				const MAX_ENCODED_LEN: usize = 0usize #field_lens_stream;
+			}
+
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::Serialize for #ident #generics_mid #generics_post {
+				async fn serialize<W: ::link_protocol_binser::Write>(&self, writer: &mut W) -> Result<(), ::link_protocol_binser::Error<W::Error>> {
+					#serialize_statements_stream
+					Ok(())
 				}
 			}
+
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::Deserialize for #ident #generics_mid #generics_post {
+				async fn deserialize<R: ::link_protocol_binser::Read>(reader: &mut R) -> Result<Self, ::link_protocol_binser::Error<R::Error>> {
+					Ok(#construction)
+				}
+			}
+
+			#arbitrary
+		};
+	}
+	.into()
+}
+
+fn enum_schema(ident: &Ident, variant_schemas: Vec<TokenStream>, retired: &[u8]) -> TokenStream {
+	let name = ident.to_string();
+	let mut variant_schemas_stream = TokenStream::new();
+	variant_schemas_stream.append_all(variant_schemas);
+
+	quote! {
+		const SCHEMA: ::link_protocol_binser::schema::MessageSchema = ::link_protocol_binser::schema::MessageSchema {
+			name: #name,
+			body: ::link_protocol_binser::schema::SchemaBody::Enum(&[#variant_schemas_stream]),
+			retired: &[#(#retired),*],
+		};
+	}
+}
+
+/// Derived messages are fields of other messages by name; their layout
+/// is in their own schema.
+fn encoded_impl(ident: &Ident, generics: &Generics) -> TokenStream {
+	let name = ident.to_string();
+	let (generics_pre, generics_mid, generics_post) = generics.split_for_impl();
+
+	quote! {
+		#[automatically_derived]
+		impl #generics_pre ::link_protocol_binser::schema::Encoded for #ident #generics_mid #generics_post {
+			const ENCODING: ::link_protocol_binser::schema::Encoding = ::link_protocol_binser::schema::Encoding::Message(#name);
 		}
+	}
+}
 
-		let proto = match proto {
-			Some(p) => p,
-			None => {
+fn derive_enum(
+	enum_ident: Ident,
+	attrs: &[Attribute],
+	generics: Generics,
+	data: DataEnum,
+) -> proc_macro::TokenStream {
+	let enum_name = enum_ident.to_string();
+	let mut known_discriminants = HashMap::<u8, Ident>::new();
+	let retired = match retired_ids(attrs) {
+		Ok(retired) => retired,
+		Err(err) => return err.into_compile_error().into(),
+	};
+
+	let mut serialize_matches = Vec::new();
+	let mut deserialize_matches = Vec::new();
+	let mut variant_schemas = Vec::new();
+	let mut variant_lens = Vec::new();
+	let mut deprecated_matches = Vec::new();
+	let mut generate_arms = Vec::new();
+	let mut has_optional_fields = false;
+
+	for variant in data.variants {
+		let ident = variant.ident;
+		let variant_ident = ident.clone();
+
+		let mut serialize_statements = Vec::new();
+		let mut field_schemas = Vec::new();
+		let mut field_lens = Vec::new();
+
+		let proto = match ProtoMeta::from_attrs(&variant.attrs, &["id", "deprecated"], "variants") {
+			Ok(Some(p)) => p,
+			Err(err) => return err.into_compile_error().into(),
+			Ok(None) => {
 				return Error::new(
 					ident.span(),
 					"oro link protocol enum variant missing #[proto(id = ...)] attribute",
@@ -135,34 +646,68 @@ pub fn derive_link_protocol_message(item: proc_macro::TokenStream) -> proc_macro
 			.into();
 		}
 
+		if let Err(err) = check_retired(&ident, discriminant, &retired) {
+			return err.into_compile_error().into();
+		}
+
 		known_discriminants.insert(discriminant, ident.clone());
 
+		if proto.deprecated {
+			let name = ident.to_string();
+			deprecated_matches.push(quote! {
+				#enum_ident :: #ident { .. } => Some(#name),
+			});
+		}
+
+		let mut seen_optional = false;
+
 		serialize_statements.push(quote! {
 			<u8 as ::link_protocol_binser::Serialize>::serialize(&#discriminant, writer).await?;
 		});
 
-		let (destructure, construction) = match variant.fields {
+		let (destructure, construction, generation) = match variant.fields {
 			Fields::Named(named) => {
 				let mut field_inits = Vec::new();
+				let mut field_generators = Vec::new();
 				let mut field_idents = Punctuated::<Ident, Comma>::new();
 
 				for field in named.named {
+					let proto = match field_proto(&field, &mut seen_optional) {
+						Ok(proto) => proto,
+						Err(err) => return err.into_compile_error().into(),
+					};
 					let ident = field.ident.unwrap();
 					let fieldtype = &field.ty;
 
 					field_idents.push(ident.clone());
+					field_schemas.push(field_schema(&ident, fieldtype, &proto));
+					field_lens.push(max_encoded_len(fieldtype));
 
 					serialize_statements.push(quote! {
 						::link_protocol_binser::Serialize::serialize(#ident, writer).await?;
 					});
 
+					let value = deserialize_field(
+						&enum_name,
+						Some(&variant_ident),
+						&ident,
+						fieldtype,
+						proto.is_optional(),
+					);
 					field_inits.push(quote! {
-						#ident : <(#fieldtype) as ::link_protocol_binser::Deserialize>::deserialize(reader).await?,
+						#ident : #value,
+					});
+
+					let value = generate_field(fieldtype);
+					field_generators.push(quote! {
+						#ident : #value,
 					});
 				}
 
 				let mut field_inits_stream = TokenStream::new();
 				field_inits_stream.append_all(field_inits.into_iter());
+				let mut field_generators_stream = TokenStream::new();
+				field_generators_stream.append_all(field_generators);
 
 				(
 					quote! {
@@ -171,29 +716,53 @@ pub fn derive_link_protocol_message(item: proc_macro::TokenStream) -> proc_macro
 					quote! {
 						{#field_inits_stream}
 					},
+					quote! {
+						{#field_generators_stream}
+					},
 				)
 			}
 			Fields::Unnamed(fields) => {
 				let mut field_inits = Vec::new();
+				let mut field_generators = Vec::new();
 				let mut field_idents = Punctuated::<Ident, Comma>::new();
 
 				for (i, field) in fields.unnamed.iter().enumerate() {
+					let proto = match field_proto(field, &mut seen_optional) {
+						Ok(proto) => proto,
+						Err(err) => return err.into_compile_error().into(),
+					};
 					let ident = paste(&"f", &i);
 					let fieldtype = &field.ty;
 
 					field_idents.push(ident.clone());
+					field_schemas.push(field_schema(&i, fieldtype, &proto));
+					field_lens.push(max_encoded_len(fieldtype));
 
 					serialize_statements.push(quote! {
 						::link_protocol_binser::Serialize::serialize(#ident, writer).await?;
 					});
 
+					let value = deserialize_field(
+						&enum_name,
+						Some(&variant_ident),
+						&i,
+						fieldtype,
+						proto.is_optional(),
+					);
 					field_inits.push(quote! {
-						<(#fieldtype) as ::link_protocol_binser::Deserialize>::deserialize(reader).await?,
+						#value,
+					});
+
+					let value = generate_field(fieldtype);
+					field_generators.push(quote! {
+						#value,
 					});
 				}
 
 				let mut field_inits_stream = TokenStream::new();
 				field_inits_stream.append_all(field_inits.into_iter());
+				let mut field_generators_stream = TokenStream::new();
+				field_generators_stream.append_all(field_generators);
 
 				(
 					quote! {
@@ -202,11 +771,16 @@ pub fn derive_link_protocol_message(item: proc_macro::TokenStream) -> proc_macro
 					quote! {
 						(#field_inits_stream)
 					},
+					quote! {
+						(#field_generators_stream)
+					},
 				)
 			}
-			Fields::Unit => (quote! {}, quote! {}),
+			Fields::Unit => (quote! {}, quote! {}, quote! {}),
 		};
 
+		has_optional_fields |= seen_optional;
+
 		let mut serialize_statements_stream = TokenStream::new();
 		serialize_statements_stream.append_all(serialize_statements.into_iter());
 
@@ -221,18 +795,88 @@ pub fn derive_link_protocol_message(item: proc_macro::TokenStream) -> proc_macro
 				#enum_ident :: #ident #construction
 			}
 		});
+
+		let index = generate_arms.len();
+		generate_arms.push(quote! {
+			#index => #enum_ident :: #ident #generation,
+		});
+
+		let name = ident.to_string();
+		let deprecated = proto.deprecated;
+		let mut field_schemas_stream = TokenStream::new();
+		field_schemas_stream.append_all(field_schemas);
+
+		variant_schemas.push(quote! {
+			::link_protocol_binser::schema::VariantSchema {
+				id: #discriminant,
+				name: #name,
+				fields: &[#field_schemas_stream],
+				deprecated: #deprecated,
+			},
+		});
+
+		let mut field_lens_stream = TokenStream::new();
+		field_lens_stream.append_all(field_lens);
+
+		variant_lens.push(quote! {
+			let len = 1usize #field_lens_stream;
+			if len > max {
+				max = len;
+			}
+		});
 	}
 
-	let ident = ast.ident;
-	let (generics_pre, generics_mid, generics_post) = ast.generics.split_for_impl();
+	let ident = enum_ident;
+	let (generics_pre, generics_mid, generics_post) = generics.split_for_impl();
 
 	let mut serialize_matches_stream = TokenStream::new();
 	serialize_matches_stream.append_all(serialize_matches);
 	let mut deserialize_matches_stream = TokenStream::new();
 	deserialize_matches_stream.append_all(deserialize_matches);
+	let schema = enum_schema(&ident, variant_schemas, &retired);
+	let encoded = encoded_impl(&ident, &generics);
+	let mut variant_lens_stream = TokenStream::new();
+	variant_lens_stream.append_all(variant_lens);
+
+	let arbitrary = arbitrary_impl(&ident, &generics, choose_arm(generate_arms));
+
+	let deprecated_variant = if deprecated_matches.is_empty() {
+		quote! {}
+	} else {
+		let mut deprecated_matches_stream = TokenStream::new();
+		deprecated_matches_stream.append_all(deprecated_matches);
+
+		quote! {
+			fn deprecated_variant(&self) -> Option<&'static str> {
+				#[allow(unreachable_patterns)]
+				match self {
+					#deprecated_matches_stream
+					_ => None,
+				}
+			}
+		}
+	};
 
 	quote! {
 		const _: () = {
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::Schema for #ident #generics_mid #generics_post {
+				#schema
+				#deprecated_variant
+			}
+
+			#encoded
+
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::MaxEncodedLen for #ident #generics_mid #generics_post {
+				// The variant id, followed by the largest variant.
+				const MAX_ENCODED_LEN: usize = {
+					let mut max = 1usize;
+					#variant_lens_stream
+					max
+				};
+			}
+
 			#[automatically_derived]
 			impl #generics_pre ::link_protocol_binser::Serialize for #ident #generics_mid #generics_post {
 				async fn serialize<W: ::link_protocol_binser::Write>(&self, writer: &mut W) -> Result<(), ::link_protocol_binser::Error<W::Error>> {
@@ -244,6 +888,8 @@ pub fn derive_link_protocol_message(item: proc_macro::TokenStream) -> proc_macro
 
 			#[automatically_derived]
 			impl #generics_pre ::link_protocol_binser::Deserialize for #ident #generics_mid #generics_post {
+				const HAS_OPTIONAL_FIELDS: bool = #has_optional_fields;
+
 				async fn deserialize<R: ::link_protocol_binser::Read>(reader: &mut R) -> Result<Self, ::link_protocol_binser::Error<R::Error>> {
 					let msg_code = <u8 as ::link_protocol_binser::Deserialize>::deserialize(reader).await?;
 
@@ -257,6 +903,216 @@ pub fn derive_link_protocol_message(item: proc_macro::TokenStream) -> proc_macro
 					)
 				}
 			}
+
+			#arbitrary
+		};
+	}
+	.into()
+}
+
+/// Derives (de)serialization for fieldless `#[repr(u8)]` enums, which
+/// are encoded as their discriminant.
+#[proc_macro_derive(LinkEnum, attributes(proto))]
+pub fn derive_link_protocol_enum(item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+	let ast = parse_macro_input!(item as DeriveInput);
+
+	let data = match ast.data {
+		Data::Enum(data) => data,
+		_ => {
+			return Error::new(
+				ast.ident.span(),
+				"link protocol `LinkEnum` can only be derived for enums",
+			)
+			.into_compile_error()
+			.into();
+		}
+	};
+
+	let is_repr_u8 = ast.attrs.iter().any(|attr| {
+		attr.path().is_ident("repr")
+			&& attr
+				.parse_args::<Ident>()
+				.map(|repr| repr == "u8")
+				.unwrap_or(false)
+	});
+
+	if !is_repr_u8 {
+		return Error::new(
+			ast.ident.span(),
+			"link protocol `LinkEnum` enums must be `#[repr(u8)]`",
+		)
+		.into_compile_error()
+		.into();
+	}
+
+	let mut known_discriminants = HashMap::<u8, Ident>::new();
+	let retired = match retired_ids(&ast.attrs) {
+		Ok(retired) => retired,
+		Err(err) => return err.into_compile_error().into(),
+	};
+
+	let mut serialize_matches = Vec::new();
+	let mut deserialize_matches = Vec::new();
+	let mut variant_schemas = Vec::new();
+	let mut generate_arms = Vec::new();
+
+	for variant in data.variants {
+		let ident = variant.ident;
+
+		if !matches!(variant.fields, Fields::Unit) {
+			return Error::new(
+				ident.span(),
+				"link protocol `LinkEnum` variants cannot have fields; use `LinkMessage` instead",
+			)
+			.into_compile_error()
+			.into();
+		}
+
+		if let Some(attr) = variant
+			.attrs
+			.iter()
+			.find(|attr| attr.path().is_ident("proto"))
+		{
+			return Error::new(
+				attr.path().span(),
+				"link protocol `LinkEnum` variants are identified by their discriminant, and take no `#[proto(...)]` attributes",
+			)
+			.into_compile_error()
+			.into();
+		}
+
+		let discriminant = match variant.discriminant {
+			Some((
+				_,
+				Expr::Lit(ExprLit {
+					lit: Lit::Int(lit), ..
+				}),
+			)) => match lit.base10_parse::<u8>() {
+				Ok(discriminant) => discriminant,
+				Err(err) => return err.into_compile_error().into(),
+			},
+			Some((_, expr)) => {
+				return Error::new(
+					expr.span(),
+					"link protocol enum variant discriminants must be integer literals",
+				)
+				.into_compile_error()
+				.into();
+			}
+			None => {
+				return Error::new(
+					ident.span(),
+					"link protocol enum variant is missing an explicit discriminant (e.g. `= 1`)",
+				)
+				.into_compile_error()
+				.into();
+			}
+		};
+
+		if let Some(existing_ident) = known_discriminants.get(&discriminant) {
+			let existing_ident = existing_ident.to_string();
+			return Error::new(
+				ident.span(),
+				format!(
+					"link protocol enum variant has identical discriminant as another variant `{existing_ident}`"
+				),
+			)
+			.into_compile_error()
+			.into();
+		}
+
+		if discriminant == 0 {
+			return Error::new(
+				ident.span(),
+				"link protocol enum variant discriminants cannot be zero (0)",
+			)
+			.into_compile_error()
+			.into();
+		}
+
+		if let Err(err) = check_retired(&ident, discriminant, &retired) {
+			return err.into_compile_error().into();
+		}
+
+		known_discriminants.insert(discriminant, ident.clone());
+
+		serialize_matches.push(quote! {
+			Self :: #ident => #discriminant,
+		});
+
+		deserialize_matches.push(quote! {
+			#discriminant => Self :: #ident,
+		});
+
+		let index = generate_arms.len();
+		generate_arms.push(quote! {
+			#index => Self :: #ident,
+		});
+
+		let name = ident.to_string();
+		variant_schemas.push(quote! {
+			::link_protocol_binser::schema::VariantSchema {
+				id: #discriminant,
+				name: #name,
+				fields: &[],
+				deprecated: false,
+			},
+		});
+	}
+
+	let ident = ast.ident;
+	let (generics_pre, generics_mid, generics_post) = ast.generics.split_for_impl();
+
+	let mut serialize_matches_stream = TokenStream::new();
+	serialize_matches_stream.append_all(serialize_matches);
+	let mut deserialize_matches_stream = TokenStream::new();
+	deserialize_matches_stream.append_all(deserialize_matches);
+	let schema = enum_schema(&ident, variant_schemas, &retired);
+	let encoded = encoded_impl(&ident, &ast.generics);
+	let arbitrary = arbitrary_impl(&ident, &ast.generics, choose_arm(generate_arms));
+
+	quote! {
+		const _: () = {
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::Schema for #ident #generics_mid #generics_post {
+				#schema
+			}
+
+			#encoded
+
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::MaxEncodedLen for #ident #generics_mid #generics_post {
+				const MAX_ENCODED_LEN: usize = 1;
+			}
+
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::Serialize for #ident #generics_mid #generics_post {
+				async fn serialize<W: ::link_protocol_binser::Write>(&self, writer: &mut W) -> Result<(), ::link_protocol_binser::Error<W::Error>> {
+					let discriminant: u8 = match self {
+						#serialize_matches_stream
+					};
+
+					<u8 as ::link_protocol_binser::Serialize>::serialize(&discriminant, writer).await
+				}
+			}
+
+			#[automatically_derived]
+			impl #generics_pre ::link_protocol_binser::Deserialize for #ident #generics_mid #generics_post {
+				async fn deserialize<R: ::link_protocol_binser::Read>(reader: &mut R) -> Result<Self, ::link_protocol_binser::Error<R::Error>> {
+					let discriminant = <u8 as ::link_protocol_binser::Deserialize>::deserialize(reader).await?;
+
+					Ok(
+						match discriminant {
+							#deserialize_matches_stream
+							_ => {
+								return Err(::link_protocol_binser::Error::InvalidEnumeration);
+							}
+						}
+					)
+				}
+			}
+
+			#arbitrary
 		};
 	}
 	.into()
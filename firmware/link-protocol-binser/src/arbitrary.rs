@@ -0,0 +1,87 @@
+//! Random message generation, for property tests and fuzzing.
+//!
+//! With the `arbitrary` feature, the derives also implement
+//! [`Arbitrary`] for every message. Values are built from [`Generate`],
+//! which (unlike `Arbitrary` itself) can be implemented here for the
+//! `heapless` containers; generated strings and vectors never exceed
+//! their capacity, and strings are always valid UTF-8.
+
+pub use ::arbitrary::{Arbitrary, Result, Unstructured};
+
+/// Builds a random value that can be encoded. Implemented by the derives
+/// for every message, and used by them for each of a message's fields.
+pub trait Generate<'a>: Sized {
+	fn generate(u: &mut Unstructured<'a>) -> Result<Self>;
+}
+
+macro_rules! impl_generate_via_arbitrary {
+	($($ty:ty),* $(,)?) => {
+		$(
+			impl<'a> Generate<'a> for $ty {
+				#[inline]
+				fn generate(u: &mut Unstructured<'a>) -> Result<Self> {
+					<$ty as Arbitrary<'a>>::arbitrary(u)
+				}
+			}
+		)*
+	};
+}
+
+impl_generate_via_arbitrary!(bool, u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<'a, const SZ: usize> Generate<'a> for [u8; SZ] {
+	#[inline]
+	fn generate(u: &mut Unstructured<'a>) -> Result<Self> {
+		let mut bytes = [0; SZ];
+		u.fill_buffer(&mut bytes)?;
+		Ok(bytes)
+	}
+}
+
+impl<'a, T: Generate<'a>> Generate<'a> for Option<T> {
+	fn generate(u: &mut Unstructured<'a>) -> Result<Self> {
+		Ok(if bool::arbitrary(u)? {
+			Some(T::generate(u)?)
+		} else {
+			None
+		})
+	}
+}
+
+#[cfg(feature = "heapless")]
+impl<'a, const SZ: usize> Generate<'a> for heapless::String<SZ> {
+	fn generate(u: &mut Unstructured<'a>) -> Result<Self> {
+		let mut string = heapless::String::new();
+		// Stops at the first character that doesn't fit, which keeps
+		// multi-byte characters near the capacity in the mix.
+		while !u.is_empty() && bool::arbitrary(u)? {
+			if string.push(char::arbitrary(u)?).is_err() {
+				break;
+			}
+		}
+		Ok(string)
+	}
+}
+
+#[cfg(feature = "heapless")]
+impl<'a, T: Generate<'a>, const SZ: usize> Generate<'a> for heapless::Vec<T, SZ> {
+	fn generate(u: &mut Unstructured<'a>) -> Result<Self> {
+		let mut vec = heapless::Vec::new();
+		while !vec.is_full() && !u.is_empty() && bool::arbitrary(u)? {
+			// Can't fail; it was checked for room above.
+			let _ = vec.push(T::generate(u)?);
+		}
+		Ok(vec)
+	}
+}
+
+#[cfg(feature = "heapless")]
+impl<'a, const N: usize> Generate<'a> for crate::BlobChunk<N> {
+	fn generate(u: &mut Unstructured<'a>) -> Result<Self> {
+		Ok(Self {
+			offset: u32::generate(u)?,
+			total: u32::generate(u)?,
+			data: heapless::Vec::generate(u)?,
+		})
+	}
+}
@@ -0,0 +1,351 @@
+//! Streaming binary payloads, for transfers too large to buffer.
+//!
+//! On a plain stream, a blob is sent as a `u32` length followed by that
+//! many bytes. Unlike the other types here, a blob is never held in
+//! memory in full; it's written straight from a borrowed slice (see
+//! [`Blob`]) or copied from a [`Read`] source in chunks (see
+//! [`write_blob`]), and handed to a sink in chunks as it's received (see
+//! [`read_blob`]).
+//!
+//! Such a blob is far too large for a packet, though. Over a channel,
+//! blobs are instead split into [`BlobChunk`]s (see [`BlobChunks`] and
+//! [`StreamChunks`]), each of which fits in a packet of its own, and put
+//! back together on the other end by a [`Reassembler`].
+
+use crate::{Deserialize, Error, MaxEncodedLen, MaybeFormat, Read, Serialize, Write};
+#[cfg(feature = "heapless")]
+use core::convert::Infallible;
+
+/// How many bytes are copied at a time between sources, sinks and streams.
+pub const BLOB_CHUNK_LEN: usize = 256;
+
+/// A blob sent straight from a borrowed slice. Must be at most
+/// `u32::MAX` bytes long, and so can't be part of a packet; use
+/// [`BlobChunks`] for those.
+#[derive(Debug, Clone, Copy)]
+pub struct Blob<'a>(pub &'a [u8]);
+
+impl Serialize for Blob<'_> {
+	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+		let len = u32::try_from(self.0.len()).map_err(|_| Error::ArrayTooLong {
+			len: self.0.len(),
+			max: u32::MAX as usize,
+		})?;
+		len.serialize(writer).await?;
+		writer.write(self.0).await
+	}
+}
+
+impl MaxEncodedLen for Blob<'_> {
+	const MAX_ENCODED_LEN: usize = 4usize.saturating_add(u32::MAX as usize);
+}
+
+/// Errors from copying a blob between two streams.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+pub enum BlobError<R, W>
+where
+	R: MaybeFormat,
+	W: MaybeFormat,
+{
+	#[cfg_attr(feature = "thiserror", error("failed to read the blob: {0}"))]
+	Read(Error<R>),
+	#[cfg_attr(feature = "thiserror", error("failed to write the blob: {0}"))]
+	Write(Error<W>),
+}
+
+/// Sends exactly `len` bytes read from `source` as a blob.
+pub async fn write_blob<W: Write, S: Read>(
+	writer: &mut W,
+	source: &mut S,
+	len: u32,
+) -> Result<(), BlobError<S::Error, W::Error>> {
+	len.serialize(writer).await.map_err(BlobError::Write)?;
+	copy(source, writer, len as usize).await
+}
+
+/// Receives a blob, writing its bytes to `sink` as they arrive.
+/// Returns the length of the blob.
+pub async fn read_blob<R: Read, S: Write>(
+	reader: &mut R,
+	sink: &mut S,
+) -> Result<u32, BlobError<R::Error, S::Error>> {
+	let len = u32::deserialize(reader).await.map_err(BlobError::Read)?;
+	copy(reader, sink, len as usize).await?;
+	Ok(len)
+}
+
+async fn copy<R: Read, W: Write>(
+	reader: &mut R,
+	writer: &mut W,
+	mut remaining: usize,
+) -> Result<(), BlobError<R::Error, W::Error>> {
+	let mut chunk = [0u8; BLOB_CHUNK_LEN];
+
+	while remaining > 0 {
+		let len = remaining.min(BLOB_CHUNK_LEN);
+		reader
+			.read(&mut chunk[..len])
+			.await
+			.map_err(BlobError::Read)?;
+		writer
+			.write(&chunk[..len])
+			.await
+			.map_err(BlobError::Write)?;
+		remaining -= len;
+	}
+
+	Ok(())
+}
+
+/// A sink that hands each chunk to a callback, for use with [`read_blob`].
+pub struct FnSink<F>(pub F);
+
+impl<F, E> Write for FnSink<F>
+where
+	F: FnMut(&[u8]) -> Result<(), E>,
+	E: MaybeFormat,
+{
+	type Error = E;
+
+	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<Self::Error>> {
+		(self.0)(buf).map_err(Error::Io)
+	}
+
+	async fn flush(&mut self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
+
+/// A piece of a blob, small enough to be sent in a single packet. Each
+/// chunk says where it goes in the blob and how long the blob is, so
+/// that the receiver can tell when chunks go missing or the blob ends
+/// early.
+#[cfg(feature = "heapless")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlobChunk<const N: usize> {
+	/// Where in the blob the chunk's data starts.
+	pub offset: u32,
+	/// The length of the whole blob.
+	pub total: u32,
+	pub data: heapless::Vec<u8, N>,
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> Serialize for BlobChunk<N> {
+	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+		self.offset.serialize(writer).await?;
+		self.total.serialize(writer).await?;
+		self.data.serialize(writer).await
+	}
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> Deserialize for BlobChunk<N> {
+	async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
+		Ok(Self {
+			offset: u32::deserialize(reader).await?,
+			total: u32::deserialize(reader).await?,
+			data: heapless::Vec::deserialize(reader).await?,
+		})
+	}
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> MaxEncodedLen for BlobChunk<N> {
+	const MAX_ENCODED_LEN: usize = 8usize.saturating_add(heapless::Vec::<u8, N>::MAX_ENCODED_LEN);
+}
+
+/// Splits a borrowed blob into chunks of at most `N` bytes. Even an
+/// empty blob has a (single, empty) chunk, so that the receiver still
+/// hears about it.
+#[cfg(feature = "heapless")]
+pub struct BlobChunks<'a, const N: usize> {
+	blob: &'a [u8],
+	offset: usize,
+	done: bool,
+}
+
+#[cfg(feature = "heapless")]
+impl<'a, const N: usize> BlobChunks<'a, N> {
+	/// Fails if the blob is longer than `u32::MAX` bytes.
+	pub fn new(blob: &'a [u8]) -> Result<Self, Error<Infallible>> {
+		if u32::try_from(blob.len()).is_err() {
+			return Err(Error::ArrayTooLong {
+				len: blob.len(),
+				max: u32::MAX as usize,
+			});
+		}
+
+		Ok(Self {
+			blob,
+			offset: 0,
+			done: false,
+		})
+	}
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> Iterator for BlobChunks<'_, N> {
+	type Item = BlobChunk<N>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None;
+		}
+
+		let len = (self.blob.len() - self.offset).min(N);
+		let chunk = BlobChunk {
+			// Both fit; the length was checked in `new`.
+			offset: self.offset as u32,
+			total: self.blob.len() as u32,
+			// Can't fail; `len` is at most `N`.
+			data: heapless::Vec::from_slice(&self.blob[self.offset..self.offset + len])
+				.unwrap_or_default(),
+		};
+
+		self.offset += len;
+		self.done = self.offset == self.blob.len();
+		Some(chunk)
+	}
+}
+
+/// Splits exactly `total` bytes read from a source into chunks of at
+/// most `N` bytes, like [`BlobChunks`] does for borrowed blobs.
+#[cfg(feature = "heapless")]
+pub struct StreamChunks<S: Read, const N: usize> {
+	source: S,
+	offset: u32,
+	total: u32,
+	done: bool,
+}
+
+#[cfg(feature = "heapless")]
+impl<S: Read, const N: usize> StreamChunks<S, N> {
+	pub fn new(source: S, total: u32) -> Self {
+		Self {
+			source,
+			offset: 0,
+			total,
+			done: false,
+		}
+	}
+
+	/// Reads the next chunk, if there are any left.
+	pub async fn next(&mut self) -> Result<Option<BlobChunk<N>>, Error<S::Error>> {
+		if self.done {
+			return Ok(None);
+		}
+
+		let len = ((self.total - self.offset) as usize).min(N);
+		let mut data = heapless::Vec::new();
+		// Can't fail; `len` is at most `N`.
+		let _ = data.resize_default(len);
+		self.source.read(&mut data[..]).await?;
+
+		let chunk = BlobChunk {
+			offset: self.offset,
+			total: self.total,
+			data,
+		};
+
+		self.offset += len as u32;
+		self.done = self.offset == self.total;
+		Ok(Some(chunk))
+	}
+}
+
+/// Errors from putting a blob back together.
+#[cfg(feature = "heapless")]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+pub enum ReassemblyError<S: MaybeFormat> {
+	/// A chunk was missed, repeated or sent out of order.
+	#[cfg_attr(
+		feature = "thiserror",
+		error("expected a blob chunk at offset {expected}, got one at {offset}")
+	)]
+	OutOfOrder { expected: u32, offset: u32 },
+	/// A chunk disagreed with the earlier ones about how long the blob is.
+	#[cfg_attr(
+		feature = "thiserror",
+		error("blob chunk says the blob is {total} bytes, rather than {expected}")
+	)]
+	LengthMismatch { expected: u32, total: u32 },
+	/// A chunk ran past the end of the blob.
+	#[cfg_attr(
+		feature = "thiserror",
+		error("blob chunk runs past the end of the {total} byte blob")
+	)]
+	Overrun { total: u32 },
+	#[cfg_attr(feature = "thiserror", error("failed to write the blob: {0}"))]
+	Sink(Error<S>),
+}
+
+/// Puts a blob back together from its [`BlobChunk`]s, handing the data
+/// to a sink as the chunks arrive. Chunks must arrive in order.
+#[cfg(feature = "heapless")]
+#[derive(Debug, Default)]
+pub struct Reassembler {
+	received: u32,
+	total: Option<u32>,
+}
+
+#[cfg(feature = "heapless")]
+impl Reassembler {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// How many bytes of the blob have been received so far.
+	#[inline]
+	pub fn received(&self) -> u32 {
+		self.received
+	}
+
+	/// Whether the whole blob has been received.
+	#[inline]
+	pub fn is_complete(&self) -> bool {
+		self.total == Some(self.received)
+	}
+
+	/// Writes a chunk's data to `sink`, returning whether or not the blob
+	/// is now complete. Nothing is written if the chunk doesn't follow on
+	/// from the previous one.
+	pub async fn push<S: Write, const N: usize>(
+		&mut self,
+		chunk: &BlobChunk<N>,
+		sink: &mut S,
+	) -> Result<bool, ReassemblyError<S::Error>> {
+		let total = *self.total.get_or_insert(chunk.total);
+		if chunk.total != total {
+			return Err(ReassemblyError::LengthMismatch {
+				expected: total,
+				total: chunk.total,
+			});
+		}
+
+		if chunk.offset != self.received {
+			return Err(ReassemblyError::OutOfOrder {
+				expected: self.received,
+				offset: chunk.offset,
+			});
+		}
+
+		let received = u32::try_from(chunk.data.len())
+			.ok()
+			.and_then(|len| self.received.checked_add(len))
+			.filter(|&received| received <= total)
+			.ok_or(ReassemblyError::Overrun { total })?;
+
+		sink.write(&chunk.data)
+			.await
+			.map_err(ReassemblyError::Sink)?;
+		self.received = received;
+
+		Ok(self.is_complete())
+	}
+}
@@ -0,0 +1,215 @@
+//! Blocking (de)serialization, for tools and tests that don't otherwise
+//! need an executor.
+//!
+//! The encoding logic is the same as for async streams; the futures are
+//! simply driven to completion on the spot by [`block_on`]. That only
+//! works for readers and writers that never wait, i.e. in-memory buffers
+//! and the blocking adapters in this module, which are the only ones
+//! [`SerializeBlocking`] and [`DeserializeBlocking`] accept.
+
+use crate::{Deserialize, Error, Serialize, SliceReader, SliceWriter};
+use core::{
+	convert::Infallible,
+	future::Future,
+	pin::pin,
+	task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+
+const NOOP_WAKER_VTABLE: RawWakerVTable =
+	RawWakerVTable::new(noop_raw_waker, |_| {}, |_| {}, |_| {});
+
+fn noop_raw_waker(_: *const ()) -> RawWaker {
+	RawWaker::new(core::ptr::null(), &NOOP_WAKER_VTABLE)
+}
+
+/// Runs `future` to completion on the current thread, in a single poll.
+///
+/// # Panics
+///
+/// If the future waits on anything, e.g. because it reads from a socket.
+/// Nothing would ever wake it again, so rather than spinning forever,
+/// waiting is treated as a bug.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+	// SAFETY: the vtable's functions don't touch the (null) data pointer.
+	let waker = unsafe { Waker::from_raw(noop_raw_waker(core::ptr::null())) };
+	let mut cx = Context::from_waker(&waker);
+
+	match pin!(future).poll(&mut cx) {
+		Poll::Ready(output) => output,
+		Poll::Pending => panic!(
+			"a blocking (de)serialization waited; only readers and writers that never wait can be used"
+		),
+	}
+}
+
+/// Decodes a value from the start of `buf`, returning it along with
+/// the number of bytes it took up.
+pub fn decode_from_slice<T: Deserialize>(buf: &[u8]) -> Result<(T, usize), Error<Infallible>> {
+	let mut reader = SliceReader::new(buf);
+	let value = block_on(T::deserialize(&mut reader))?;
+	Ok((value, buf.len() - reader.remaining()))
+}
+
+/// Encodes a value into a new `Vec`.
+#[cfg(feature = "std")]
+pub fn encode_to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>, Error<Infallible>> {
+	let mut writer = VecWriter(Vec::new());
+	block_on(value.serialize(&mut writer))?;
+	Ok(writer.0)
+}
+
+/// Appends to a `Vec`. `Vec` itself can't implement [`crate::Write`],
+/// as it already does through the `async-std` adapter.
+#[cfg(feature = "std")]
+pub struct VecWriter(pub Vec<u8>);
+
+#[cfg(feature = "std")]
+impl crate::Write for VecWriter {
+	type Error = Infallible;
+
+	#[inline]
+	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<Self::Error>> {
+		self.0.extend_from_slice(buf);
+		Ok(())
+	}
+
+	#[inline]
+	async fn flush(&mut self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
+
+/// Adapts a blocking `std::io` stream. Use with [`block_on`] or the
+/// `*_blocking` methods.
+#[cfg(feature = "std")]
+pub struct StdIo<T>(pub T);
+
+#[cfg(feature = "std")]
+impl<T: std::io::Read> crate::Read for StdIo<T> {
+	type Error = std::io::Error;
+
+	#[inline]
+	async fn read(&mut self, buf: &mut [u8]) -> Result<(), Error<Self::Error>> {
+		self.0.read_exact(buf).map_err(Error::Io)
+	}
+}
+
+#[cfg(feature = "std")]
+impl<T: std::io::Write> crate::Write for StdIo<T> {
+	type Error = std::io::Error;
+
+	#[inline]
+	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<Self::Error>> {
+		self.0.write_all(buf).map_err(Error::Io)
+	}
+
+	#[inline]
+	async fn flush(&mut self) -> Result<(), Self::Error> {
+		self.0.flush()
+	}
+}
+
+/// Adapts a blocking `embedded_io` stream. Use with [`block_on`] or the
+/// `*_blocking` methods.
+#[cfg(feature = "embedded-io-blocking")]
+pub struct EmbeddedIo<T>(pub T);
+
+#[cfg(feature = "embedded-io-blocking")]
+impl<T> crate::Read for EmbeddedIo<T>
+where
+	T: embedded_io::Read,
+	<T as embedded_io::ErrorType>::Error: crate::MaybeFormat,
+{
+	type Error = embedded_io::ReadExactError<<T as embedded_io::ErrorType>::Error>;
+
+	#[inline]
+	async fn read(&mut self, buf: &mut [u8]) -> Result<(), Error<Self::Error>> {
+		self.0.read_exact(buf).map_err(Error::Io)
+	}
+}
+
+#[cfg(feature = "embedded-io-blocking")]
+impl<T> crate::Write for EmbeddedIo<T>
+where
+	T: embedded_io::Write,
+	<T as embedded_io::ErrorType>::Error: crate::MaybeFormat,
+{
+	type Error = <T as embedded_io::ErrorType>::Error;
+
+	#[inline]
+	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<Self::Error>> {
+		self.0.write_all(buf).map_err(Error::Io)
+	}
+
+	#[inline]
+	async fn flush(&mut self) -> Result<(), Self::Error> {
+		self.0.flush()
+	}
+}
+
+mod sealed {
+	pub trait Sealed {}
+}
+
+/// Writers that never wait, and so can be written to with
+/// [`SerializeBlocking`].
+pub trait BlockingWrite: crate::Write + sealed::Sealed {}
+
+/// Readers that never wait, and so can be read from with
+/// [`DeserializeBlocking`].
+pub trait BlockingRead: crate::Read + sealed::Sealed {}
+
+impl sealed::Sealed for SliceReader<'_> {}
+impl BlockingRead for SliceReader<'_> {}
+
+impl sealed::Sealed for SliceWriter<'_> {}
+impl BlockingWrite for SliceWriter<'_> {}
+
+#[cfg(feature = "std")]
+impl sealed::Sealed for VecWriter {}
+#[cfg(feature = "std")]
+impl BlockingWrite for VecWriter {}
+
+#[cfg(feature = "std")]
+impl<T> sealed::Sealed for StdIo<T> {}
+#[cfg(feature = "std")]
+impl<T: std::io::Read> BlockingRead for StdIo<T> {}
+#[cfg(feature = "std")]
+impl<T: std::io::Write> BlockingWrite for StdIo<T> {}
+
+#[cfg(feature = "embedded-io-blocking")]
+impl<T> sealed::Sealed for EmbeddedIo<T> {}
+#[cfg(feature = "embedded-io-blocking")]
+impl<T> BlockingRead for EmbeddedIo<T>
+where
+	T: embedded_io::Read,
+	<T as embedded_io::ErrorType>::Error: crate::MaybeFormat,
+{
+}
+#[cfg(feature = "embedded-io-blocking")]
+impl<T> BlockingWrite for EmbeddedIo<T>
+where
+	T: embedded_io::Write,
+	<T as embedded_io::ErrorType>::Error: crate::MaybeFormat,
+{
+}
+
+/// Blocking counterpart to [`Serialize`], for any type that implements it.
+pub trait SerializeBlocking: Serialize {
+	/// Writes `self` to a writer that never waits (see the module docs).
+	fn serialize_blocking<W: BlockingWrite>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+		block_on(self.serialize(writer))
+	}
+}
+
+impl<T: Serialize> SerializeBlocking for T {}
+
+/// Blocking counterpart to [`Deserialize`], for any type that implements it.
+pub trait DeserializeBlocking: Deserialize {
+	/// Reads a value from a reader that never waits (see the module docs).
+	fn deserialize_blocking<R: BlockingRead>(reader: &mut R) -> Result<Self, Error<R::Error>> {
+		block_on(Self::deserialize(reader))
+	}
+}
+
+impl<T: Deserialize> DeserializeBlocking for T {}
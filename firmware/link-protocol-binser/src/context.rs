@@ -0,0 +1,160 @@
+//! Where in a message a decoding error occurred.
+//!
+//! Derived messages wrap errors from their fields in [`Error::InField`],
+//! so that logs read like `DaemonToLink::StartTestSession.author: string
+//! too long (300 > 255)` rather than just the error itself.
+
+use crate::{Error, MaybeFormat};
+use core::fmt;
+
+/// How many levels of nested messages a [`FieldPath`] keeps track of.
+/// Beyond that, the outermost ones are left out.
+const MAX_DEPTH: usize = 4;
+
+/// The path from the outermost message down to the field that
+/// failed to decode.
+///
+/// Each segment names a field along with the message (and variant) it
+/// belongs to, e.g. `DaemonToLink::StartTestSession.author`; tuple fields are
+/// named by their index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldPath {
+	/// Innermost first, since that's the order they're added in.
+	segments: [&'static str; MAX_DEPTH],
+	len: u8,
+	truncated: bool,
+}
+
+impl FieldPath {
+	fn new(segment: &'static str) -> Self {
+		Self {
+			// Only the first `len` are meaningful.
+			segments: [segment; MAX_DEPTH],
+			len: 1,
+			truncated: false,
+		}
+	}
+
+	/// Adds the field that contains the current outermost one.
+	fn push_outer(&mut self, segment: &'static str) {
+		if usize::from(self.len) == MAX_DEPTH {
+			self.truncated = true;
+		} else {
+			self.segments[usize::from(self.len)] = segment;
+			self.len += 1;
+		}
+	}
+
+	/// The fields in the path, outermost first.
+	pub fn segments(&self) -> impl Iterator<Item = &'static str> + '_ {
+		self.segments[..usize::from(self.len)].iter().rev().copied()
+	}
+
+	/// Whether the outermost fields were left out for being too deep.
+	pub fn is_truncated(&self) -> bool {
+		self.truncated
+	}
+}
+
+impl fmt::Display for FieldPath {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.truncated {
+			f.write_str("... > ")?;
+		}
+
+		for (i, segment) in self.segments().enumerate() {
+			if i > 0 {
+				f.write_str(" > ")?;
+			}
+			f.write_str(segment)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for FieldPath {
+	fn format(&self, f: defmt::Formatter<'_>) {
+		if self.truncated {
+			defmt::write!(f, "... > ");
+		}
+
+		for (i, segment) in self.segments().enumerate() {
+			if i > 0 {
+				defmt::write!(f, " > ");
+			}
+			defmt::write!(f, "{=str}", segment);
+		}
+	}
+}
+
+/// The errors that are attributed to a field in [`Error::InField`]; see
+/// [`Error`] for what each means. I/O and authentication errors aren't
+/// the field's fault, and are never wrapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+pub enum FieldError {
+	#[cfg_attr(feature = "thiserror", error("string too long ({len} > {max})"))]
+	StringTooLong { len: usize, max: usize },
+	#[cfg_attr(feature = "thiserror", error("array too long ({len} > {max})"))]
+	ArrayTooLong { len: usize, max: usize },
+	#[cfg_attr(feature = "thiserror", error("unknown message code {0}"))]
+	InvalidMessageCode(u8),
+	#[cfg_attr(feature = "thiserror", error("invalid enum variant"))]
+	InvalidEnumeration,
+	#[cfg_attr(feature = "thiserror", error("malformed utf-8"))]
+	MalformedString,
+	#[cfg_attr(feature = "thiserror", error("unexpected EOF"))]
+	Eof,
+}
+
+impl FieldError {
+	/// The error as it would be without the field context. (Not a `From`
+	/// impl, as that would conflict with `Error`'s own for I/O errors.)
+	pub fn into_error<E: MaybeFormat>(self) -> Error<E> {
+		match self {
+			FieldError::StringTooLong { len, max } => Error::StringTooLong { len, max },
+			FieldError::ArrayTooLong { len, max } => Error::ArrayTooLong { len, max },
+			FieldError::InvalidMessageCode(code) => Error::InvalidMessageCode(code),
+			FieldError::InvalidEnumeration => Error::InvalidEnumeration,
+			FieldError::MalformedString => Error::MalformedString,
+			FieldError::Eof => Error::Eof,
+		}
+	}
+}
+
+impl<E: MaybeFormat> Error<E> {
+	/// Attributes the error to a field of a message, named as in
+	/// [`FieldPath`]. Used by the derives.
+	pub fn in_field(self, field: &'static str) -> Self {
+		let error = match self {
+			Error::InField { mut path, error } => {
+				path.push_outer(field);
+				return Error::InField { path, error };
+			}
+			Error::StringTooLong { len, max } => FieldError::StringTooLong { len, max },
+			Error::ArrayTooLong { len, max } => FieldError::ArrayTooLong { len, max },
+			Error::InvalidMessageCode(code) => FieldError::InvalidMessageCode(code),
+			Error::InvalidEnumeration => FieldError::InvalidEnumeration,
+			Error::MalformedString => FieldError::MalformedString,
+			Error::Eof => FieldError::Eof,
+			Error::Unauthenticated | Error::Io(_) => return self,
+		};
+
+		Error::InField {
+			path: FieldPath::new(field),
+			error,
+		}
+	}
+
+	/// Strips the field context from the error, if it has any, for
+	/// matching on what went wrong regardless of where.
+	pub fn without_context(self) -> Self {
+		match self {
+			Error::InField { error, .. } => error.into_error(),
+			error => error,
+		}
+	}
+}
@@ -1,17 +1,32 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 #![allow(async_fn_in_trait)]
 
+#[cfg(feature = "arbitrary")]
+pub mod arbitrary;
 #[cfg(feature = "async-std")]
 mod async_std;
+mod blob;
+pub mod blocking;
+mod context;
 #[cfg(feature = "embedded-io")]
 mod embedded_io;
-
-use core::str::FromStr;
+pub mod schema;
+mod slice;
+#[cfg(feature = "tokio")]
+mod tokio;
 
 #[cfg(feature = "defmt")]
 use defmt::Format;
 
-pub use link_protocol_binser_proc::LinkMessage;
+#[cfg(feature = "tokio")]
+pub use self::tokio::TokioIo;
+pub use blob::{read_blob, write_blob, Blob, BlobError, FnSink, BLOB_CHUNK_LEN};
+#[cfg(feature = "heapless")]
+pub use blob::{BlobChunk, BlobChunks, Reassembler, ReassemblyError, StreamChunks};
+pub use context::{FieldError, FieldPath};
+pub use link_protocol_binser_proc::{LinkEnum, LinkMessage};
+pub use schema::Schema;
+pub use slice::{SliceReader, SliceWriter};
 
 #[cfg(feature = "std")]
 pub trait MaybeError: std::error::Error {}
@@ -38,29 +53,32 @@ impl<T> MaybeFormat for T where T: MaybeError {}
 #[cfg_attr(feature = "defmt", derive(Format))]
 #[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
 pub enum Error<IoError: MaybeFormat> {
+	#[cfg_attr(feature = "thiserror", error("string too long ({len} > {max})"))]
+	StringTooLong { len: usize, max: usize },
+	#[cfg_attr(feature = "thiserror", error("array too long ({len} > {max})"))]
+	ArrayTooLong { len: usize, max: usize },
 	#[cfg_attr(
 		feature = "thiserror",
-		error("a sent or received string was too long to fit into a fixed buffer")
-	)]
-	StringTooLong,
-	#[cfg_attr(
-		feature = "thiserror",
-		error("a sent or received array was too long to fit into a fixed buffer")
-	)]
-	ArrayTooLong,
-	#[cfg_attr(
-		feature = "thiserror",
-		error("the packet refers to an unknown message code")
+		error("the packet refers to an unknown message code ({0})")
 	)]
 	InvalidMessageCode(u8),
 	#[cfg_attr(feature = "thiserror", error("an invalid enum variant was specified"))]
 	InvalidEnumeration,
 	#[cfg_attr(feature = "thiserror", error("a string failed to decode as utf-8"))]
 	MalformedString,
+	#[cfg_attr(
+		feature = "thiserror",
+		error("a received record failed authentication")
+	)]
+	Unauthenticated,
 	#[cfg_attr(feature = "thiserror", error("unexpected EOF"))]
 	Eof,
 	#[cfg_attr(feature = "thiserror", error("io error occurred: {0}"))]
 	Io(IoError),
+	/// A field of a derived message failed to decode. Nested messages
+	/// extend the `path` rather than wrapping this again.
+	#[cfg_attr(feature = "thiserror", error("{path}: {error}"))]
+	InField { path: FieldPath, error: FieldError },
 }
 
 impl<E: MaybeFormat> From<E> for Error<E> {
@@ -83,23 +101,101 @@ pub trait Read {
 
 	/// Read exactly `buf.len()` bytes into `buf`.
 	async fn read(&mut self, buf: &mut [u8]) -> Result<(), Error<Self::Error>>;
+
+	/// Whether the message being read is known to end here, i.e. the
+	/// reader is at the end of its frame. Used to leave out optional
+	/// trailing fields; only framed readers (e.g. [`SliceReader`]) can
+	/// tell, so streams always return `false`.
+	fn is_exhausted(&self) -> bool {
+		false
+	}
 }
 
 pub trait Serialize {
 	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>>;
+
+	/// Serializes a container's items one after another. Overridden by
+	/// `u8` to write them all at once.
+	async fn serialize_items<W: Write>(
+		items: &[Self],
+		writer: &mut W,
+	) -> Result<(), Error<W::Error>>
+	where
+		Self: Sized,
+	{
+		for item in items {
+			item.serialize(writer).await?;
+		}
+		Ok(())
+	}
 }
 
 pub trait Deserialize
 where
 	Self: Sized,
 {
+	/// Whether the type ends in optional fields (see
+	/// [`Read::is_exhausted`]). Those can only be left out at the end of
+	/// a frame, so such types are sent as packets of their own and never
+	/// nested in another message.
+	const HAS_OPTIONAL_FIELDS: bool = false;
+
 	async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>>;
+
+	/// Deserializes `len` items onto the end of `items`. Overridden by
+	/// `u8` to read them all at once.
+	#[cfg(feature = "heapless")]
+	async fn deserialize_items<R: Read, const SZ: usize>(
+		reader: &mut R,
+		items: &mut heapless::Vec<Self, SZ>,
+		len: usize,
+	) -> Result<(), Error<R::Error>> {
+		for _ in 0..len {
+			items
+				.push(Self::deserialize(reader).await?)
+				.map_err(|_| Error::ArrayTooLong {
+					len: items.len() + 1,
+					max: SZ,
+				})?;
+		}
+		Ok(())
+	}
+}
+
+/// Types with an upper bound on the size of their encoding, for sizing
+/// buffers at compile time.
+///
+/// Unbounded types (e.g. `std`'s `String` and `Vec`) report `usize::MAX`.
+/// Lengths are summed with saturating arithmetic, so anything containing
+/// them reports `usize::MAX` as well.
+pub trait MaxEncodedLen {
+	const MAX_ENCODED_LEN: usize;
 }
 
+/// Fixed-size types are always encoded as their in-memory size.
+macro_rules! impl_fixed_len {
+	($($ty:ty),* $(,)?) => {
+		$(
+			impl MaxEncodedLen for $ty {
+				const MAX_ENCODED_LEN: usize = core::mem::size_of::<$ty>();
+			}
+		)*
+	};
+}
+
+impl_fixed_len!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64, bool);
+
 impl Serialize for u8 {
 	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
 		writer.write(&[*self]).await
 	}
+
+	async fn serialize_items<W: Write>(
+		items: &[Self],
+		writer: &mut W,
+	) -> Result<(), Error<W::Error>> {
+		writer.write(items).await
+	}
 }
 
 impl Deserialize for u8 {
@@ -108,6 +204,23 @@ impl Deserialize for u8 {
 		reader.read(&mut buf).await?;
 		Ok(buf[0])
 	}
+
+	// Reads straight into the vector's own storage.
+	#[cfg(feature = "heapless")]
+	async fn deserialize_items<R: Read, const SZ: usize>(
+		reader: &mut R,
+		items: &mut heapless::Vec<Self, SZ>,
+		len: usize,
+	) -> Result<(), Error<R::Error>> {
+		let start = items.len();
+		items
+			.resize_default(start + len)
+			.map_err(|_| Error::ArrayTooLong {
+				len: start + len,
+				max: SZ,
+			})?;
+		reader.read(&mut items[start..]).await
+	}
 }
 
 impl Serialize for u16 {
@@ -190,6 +303,30 @@ impl Deserialize for f64 {
 	}
 }
 
+/// Signed integers are sent as their big-endian two's complement bytes.
+macro_rules! impl_signed {
+	($($ty:ty),* $(,)?) => {
+		$(
+			impl Serialize for $ty {
+				async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+					let bytes = self.to_be_bytes();
+					writer.write(&bytes).await
+				}
+			}
+
+			impl Deserialize for $ty {
+				async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
+					let mut buf = [0u8; core::mem::size_of::<$ty>()];
+					reader.read(&mut buf).await?;
+					Ok(<$ty>::from_be_bytes(buf))
+				}
+			}
+		)*
+	};
+}
+
+impl_signed!(i8, i16, i32, i64);
+
 impl<const SZ: usize> Serialize for [u8; SZ] {
 	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
 		writer.write(&self[..]).await?;
@@ -197,6 +334,10 @@ impl<const SZ: usize> Serialize for [u8; SZ] {
 	}
 }
 
+impl<const SZ: usize> MaxEncodedLen for [u8; SZ] {
+	const MAX_ENCODED_LEN: usize = SZ;
+}
+
 impl<const SZ: usize> Deserialize for [u8; SZ] {
 	async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
 		let mut r = [0u8; SZ];
@@ -220,7 +361,68 @@ impl Deserialize for bool {
 	}
 }
 
-#[cfg(feature = "heapless")]
+/// Options are sent as a `0` (`None`) or `1` (`Some`) byte, followed
+/// by the value if there is one.
+impl<T: Serialize> Serialize for Option<T> {
+	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+		match self {
+			None => writer.write(&[0]).await,
+			Some(value) => {
+				writer.write(&[1]).await?;
+				value.serialize(writer).await
+			}
+		}
+	}
+}
+
+impl<T: MaxEncodedLen> MaxEncodedLen for Option<T> {
+	const MAX_ENCODED_LEN: usize = 1usize.saturating_add(T::MAX_ENCODED_LEN);
+}
+
+impl<T: Deserialize> Deserialize for Option<T> {
+	async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
+		match u8::deserialize(reader).await? {
+			0 => Ok(None),
+			1 => Ok(Some(T::deserialize(reader).await?)),
+			_ => Err(Error::InvalidEnumeration),
+		}
+	}
+}
+
+/// Tuples are sent as their elements, in order.
+macro_rules! impl_tuple {
+	($(($($name:ident : $index:tt),+)),* $(,)?) => {
+		$(
+			impl<$($name: Serialize),+> Serialize for ($($name,)+) {
+				async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+					$(self.$index.serialize(writer).await?;)+
+					Ok(())
+				}
+			}
+
+			impl<$($name: Deserialize),+> Deserialize for ($($name,)+) {
+				async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
+					Ok(($($name::deserialize(reader).await?,)+))
+				}
+			}
+
+			impl<$($name: MaxEncodedLen),+> MaxEncodedLen for ($($name,)+) {
+				const MAX_ENCODED_LEN: usize = 0usize $(.saturating_add($name::MAX_ENCODED_LEN))+;
+			}
+		)*
+	};
+}
+
+impl_tuple!(
+	(A: 0),
+	(A: 0, B: 1),
+	(A: 0, B: 1, C: 2),
+	(A: 0, B: 1, C: 2, D: 3),
+	(A: 0, B: 1, C: 2, D: 3, E: 4),
+	(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5),
+);
+
+#[cfg(any(feature = "heapless", feature = "std"))]
 const fn num_bytes_for_size<const SZ: usize>() -> usize {
 	const U8_MAX: usize = u8::MAX as usize;
 	const U8_UPPER: usize = (u8::MAX as usize) + 1;
@@ -233,85 +435,157 @@ const fn num_bytes_for_size<const SZ: usize>() -> usize {
 	}
 }
 
-#[cfg(feature = "heapless")]
-impl<const SZ: usize> Serialize for heapless::String<SZ> {
-	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
-		let bytes = self.as_bytes();
-		let len = bytes.len();
+/// Writes the length prefix of a container that holds at most `SZ` items.
+/// Fails for anything longer than the prefix can describe (which only
+/// unbounded containers can be), rather than truncating the length.
+#[cfg(any(feature = "heapless", feature = "std"))]
+async fn write_len<W: Write, const SZ: usize>(
+	writer: &mut W,
+	len: usize,
+) -> Result<(), Error<W::Error>> {
+	let max = SZ.min(u32::MAX as usize);
+	if len > max {
+		return Err(Error::ArrayTooLong { len, max });
+	}
 
-		debug_assert!(len <= SZ);
-		debug_assert!(len <= u32::MAX as usize);
+	let num_bytes = num_bytes_for_size::<SZ>();
+	let len_bytes = (len as u32).to_be_bytes();
+	writer.write(&len_bytes[(4 - num_bytes)..]).await
+}
+
+/// Reads the length prefix of a container that holds at most `SZ` items.
+#[cfg(any(feature = "heapless", feature = "std"))]
+async fn read_len<R: Read, const SZ: usize>(reader: &mut R) -> Result<usize, Error<R::Error>> {
+	let num_bytes = num_bytes_for_size::<SZ>();
 
-		let num_bytes = num_bytes_for_size::<SZ>();
+	let mut len_bytes = [0u8; 4];
+	reader.read(&mut len_bytes[4 - num_bytes..]).await?;
 
-		let len_bytes = (len as u32).to_be_bytes();
+	Ok(u32::from_be_bytes(len_bytes) as usize)
+}
 
-		writer.write(&len_bytes[(4 - num_bytes)..]).await?;
+/// Unbounded containers are prefixed like the largest bounded ones.
+#[cfg(feature = "std")]
+const UNBOUNDED: usize = u32::MAX as usize;
+
+#[cfg(feature = "heapless")]
+impl<const SZ: usize> Serialize for heapless::String<SZ> {
+	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+		let bytes = self.as_bytes();
+		write_len::<W, SZ>(writer, bytes.len()).await?;
 		writer.write(bytes).await
 	}
 }
 
+#[cfg(feature = "heapless")]
+impl<const SZ: usize> MaxEncodedLen for heapless::String<SZ> {
+	const MAX_ENCODED_LEN: usize = num_bytes_for_size::<SZ>().saturating_add(SZ);
+}
+
 #[cfg(feature = "heapless")]
 impl<const SZ: usize> Deserialize for heapless::String<SZ> {
 	async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
-		let num_bytes = num_bytes_for_size::<SZ>();
-
-		let mut len_bytes = [0u8; 4];
-		reader.read(&mut len_bytes[4 - num_bytes..]).await?;
-
-		let len = u32::from_be_bytes(len_bytes) as usize;
+		let len = read_len::<R, SZ>(reader).await?;
 
 		if len > SZ {
-			return Err(Error::StringTooLong);
+			return Err(Error::StringTooLong { len, max: SZ });
 		}
 
-		let mut buffer = [0u8; SZ];
-		reader.read(&mut buffer[..len]).await?;
+		// Read straight into the string's own storage rather than
+		// a second buffer on the stack.
+		let mut bytes = heapless::Vec::<u8, SZ>::new();
+		// Can't fail; the length was checked above.
+		let _ = bytes.resize_default(len);
+		reader.read(&mut bytes[..]).await?;
 
-		let utf8 = core::str::from_utf8(&buffer[0..len]).map_err(|_| Error::MalformedString)?;
-		Ok(heapless::String::from_str(utf8).unwrap())
+		heapless::String::from_utf8(bytes).map_err(|_| Error::MalformedString)
 	}
 }
 
 #[cfg(feature = "heapless")]
-impl<const SZ: usize> Serialize for heapless::Vec<u8, SZ> {
+impl<T: Serialize, const SZ: usize> Serialize for heapless::Vec<T, SZ> {
 	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
-		let bytes = self.as_slice();
-		let len = bytes.len();
+		write_len::<W, SZ>(writer, self.len()).await?;
+		T::serialize_items(self, writer).await
+	}
+}
 
-		debug_assert!(len <= SZ);
-		debug_assert!(len <= u32::MAX as usize);
+#[cfg(feature = "heapless")]
+impl<T: MaxEncodedLen, const SZ: usize> MaxEncodedLen for heapless::Vec<T, SZ> {
+	const MAX_ENCODED_LEN: usize =
+		num_bytes_for_size::<SZ>().saturating_add(SZ.saturating_mul(T::MAX_ENCODED_LEN));
+}
+
+#[cfg(feature = "heapless")]
+impl<T: Deserialize, const SZ: usize> Deserialize for heapless::Vec<T, SZ> {
+	async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
+		let len = read_len::<R, SZ>(reader).await?;
 
-		let num_bytes = num_bytes_for_size::<SZ>();
+		if len > SZ {
+			return Err(Error::ArrayTooLong { len, max: SZ });
+		}
 
-		let len_bytes = (len as u32).to_be_bytes();
+		let mut r = heapless::Vec::<T, SZ>::new();
+		T::deserialize_items(reader, &mut r, len).await?;
+		Ok(r)
+	}
+}
 
-		writer.write(&len_bytes[(4 - num_bytes)..]).await?;
-		writer.write(bytes).await
+#[cfg(feature = "std")]
+impl Serialize for String {
+	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+		write_len::<W, UNBOUNDED>(writer, self.len()).await?;
+		writer.write(self.as_bytes()).await
 	}
 }
 
-#[cfg(feature = "heapless")]
-impl<const SZ: usize> Deserialize for heapless::Vec<u8, SZ> {
+#[cfg(feature = "std")]
+impl MaxEncodedLen for String {
+	const MAX_ENCODED_LEN: usize = usize::MAX;
+}
+
+#[cfg(feature = "std")]
+impl Deserialize for String {
 	async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
-		let num_bytes = num_bytes_for_size::<SZ>();
+		let len = read_len::<R, UNBOUNDED>(reader).await?;
+
+		// Read in chunks rather than trusting the peer's length up front.
+		let mut bytes = Vec::new();
+		let mut chunk = [0u8; 256];
+		while bytes.len() < len {
+			let n = (len - bytes.len()).min(chunk.len());
+			reader.read(&mut chunk[..n]).await?;
+			bytes.extend_from_slice(&chunk[..n]);
+		}
 
-		let mut len_bytes = [0u8; 4];
-		reader.read(&mut len_bytes[4 - num_bytes..]).await?;
+		String::from_utf8(bytes).map_err(|_| Error::MalformedString)
+	}
+}
 
-		let len = u32::from_be_bytes(len_bytes) as usize;
+#[cfg(feature = "std")]
+impl<T: Serialize> Serialize for Vec<T> {
+	async fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error<W::Error>> {
+		write_len::<W, UNBOUNDED>(writer, self.len()).await?;
+		T::serialize_items(self, writer).await
+	}
+}
 
-		if len > SZ {
-			return Err(Error::StringTooLong);
-		}
+#[cfg(feature = "std")]
+impl<T> MaxEncodedLen for Vec<T> {
+	const MAX_ENCODED_LEN: usize = usize::MAX;
+}
 
-		let mut r = heapless::Vec::<u8, SZ>::new();
-		let mut_slice = unsafe {
-			r.set_len(len);
-			::core::slice::from_raw_parts_mut(r.as_mut_ptr(), SZ)
-		};
+#[cfg(feature = "std")]
+impl<T: Deserialize> Deserialize for Vec<T> {
+	async fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error<R::Error>> {
+		let len = read_len::<R, UNBOUNDED>(reader).await?;
 
-		reader.read(&mut mut_slice[..len]).await?;
+		// Every item is at least a byte, so don't trust the peer's
+		// length for anything more than a modest reservation.
+		let mut r = Vec::with_capacity(len.min(256));
+		for _ in 0..len {
+			r.push(T::deserialize(reader).await?);
+		}
 
 		Ok(r)
 	}
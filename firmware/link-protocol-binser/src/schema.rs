@@ -0,0 +1,228 @@
+//! Static descriptions of how derived messages are laid out on the wire.
+//!
+//! Every type deriving `LinkMessage` or `LinkEnum` implements [`Schema`],
+//! which tools can use to dump the protocol and compare it between builds.
+
+/// A type with a static wire schema.
+pub trait Schema {
+	const SCHEMA: MessageSchema;
+
+	/// The name of the variant, if `self` is one marked
+	/// `#[proto(deprecated)]`. Such variants still decode, but peers
+	/// should stop sending them.
+	fn deprecated_variant(&self) -> Option<&'static str> {
+		None
+	}
+}
+
+/// The wire schema of a single message type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageSchema {
+	/// The name of the type, without its module path.
+	pub name: &'static str,
+	pub body: SchemaBody,
+	/// The ids of removed enum variants, listed in `#[proto(retired =
+	/// [...])]`, which older peers may still send. Always empty for
+	/// structs.
+	pub retired: &'static [u8],
+}
+
+impl MessageSchema {
+	/// Whether this is an enum with a variant of the given id.
+	pub const fn has_variant_id(&self, id: u8) -> bool {
+		let SchemaBody::Enum(variants) = self.body else {
+			return false;
+		};
+
+		let mut i = 0;
+		while i < variants.len() {
+			if variants[i].id == id {
+				return true;
+			}
+			i += 1;
+		}
+
+		false
+	}
+
+	/// Whether this and `other` are both enums with a variant id in common.
+	pub const fn shares_variant_ids_with(&self, other: &MessageSchema) -> bool {
+		let SchemaBody::Enum(variants) = self.body else {
+			return false;
+		};
+
+		let mut i = 0;
+		while i < variants.len() {
+			if other.has_variant_id(variants[i].id) {
+				return true;
+			}
+			i += 1;
+		}
+
+		false
+	}
+}
+
+/// The layout of a message type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaBody {
+	/// Fields encoded in order, with no framing of their own.
+	Struct(&'static [FieldSchema]),
+	/// A `u8` variant id, followed by that variant's fields.
+	Enum(&'static [VariantSchema]),
+}
+
+/// A single enum variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VariantSchema {
+	pub id: u8,
+	pub name: &'static str,
+	pub fields: &'static [FieldSchema],
+	/// Marked `#[proto(deprecated)]`.
+	pub deprecated: bool,
+}
+
+/// A single field. Tuple fields are named by their index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldSchema {
+	pub name: &'static str,
+	/// The field's type as written in the source, with whitespace removed.
+	pub ty: &'static str,
+	/// How the field is laid out on the wire.
+	pub encoding: Encoding,
+	/// Marked `#[proto(default)]` or `#[proto(since = ...)]`, i.e. it
+	/// falls back to its default when a peer leaves it out.
+	pub optional: bool,
+	/// The protocol version the field was added in, if known.
+	pub since: Option<u16>,
+}
+
+/// How a value is laid out on the wire. Types that encode alike (e.g.
+/// `u8` and `[u8; 1]`) may still differ here, as long as peers wouldn't
+/// misread one as the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	/// A big-endian integer, `bytes` wide.
+	Int { bytes: u8, signed: bool },
+	/// A big-endian IEEE 754 float, `bytes` wide.
+	Float { bytes: u8 },
+	/// A `0` or `1` byte.
+	Bool,
+	/// Exactly this many bytes, with no length prefix.
+	Bytes(usize),
+	/// A `0` (`None`) or `1` (`Some`) byte, followed by the value if
+	/// there is one.
+	Option(&'static Encoding),
+	/// Each element in order.
+	Tuple(&'static [Encoding]),
+	/// A big-endian length, `prefix` bytes wide, followed by up to `max`
+	/// items.
+	List {
+		prefix: u8,
+		max: usize,
+		item: &'static Encoding,
+	},
+	/// Like a list of bytes, but they must be UTF-8.
+	Text { prefix: u8, max: usize },
+	/// A derived message, laid out as described by its own schema.
+	Message(&'static str),
+}
+
+/// A type that can be a field of a derived message.
+pub trait Encoded {
+	const ENCODING: Encoding;
+}
+
+macro_rules! impl_encoded {
+	($($ty:ty => $encoding:expr),* $(,)?) => {
+		$(
+			impl Encoded for $ty {
+				const ENCODING: Encoding = $encoding;
+			}
+		)*
+	};
+}
+
+impl_encoded!(
+	u8 => Encoding::Int { bytes: 1, signed: false },
+	u16 => Encoding::Int { bytes: 2, signed: false },
+	u32 => Encoding::Int { bytes: 4, signed: false },
+	u64 => Encoding::Int { bytes: 8, signed: false },
+	i8 => Encoding::Int { bytes: 1, signed: true },
+	i16 => Encoding::Int { bytes: 2, signed: true },
+	i32 => Encoding::Int { bytes: 4, signed: true },
+	i64 => Encoding::Int { bytes: 8, signed: true },
+	f32 => Encoding::Float { bytes: 4 },
+	f64 => Encoding::Float { bytes: 8 },
+	bool => Encoding::Bool,
+);
+
+impl<const SZ: usize> Encoded for [u8; SZ] {
+	const ENCODING: Encoding = Encoding::Bytes(SZ);
+}
+
+impl<T: Encoded> Encoded for Option<T> {
+	const ENCODING: Encoding = Encoding::Option(&T::ENCODING);
+}
+
+macro_rules! impl_encoded_tuple {
+	($(($($name:ident),+)),* $(,)?) => {
+		$(
+			impl<$($name: Encoded),+> Encoded for ($($name,)+) {
+				const ENCODING: Encoding = Encoding::Tuple(&[$($name::ENCODING),+]);
+			}
+		)*
+	};
+}
+
+impl_encoded_tuple!(
+	(A),
+	(A, B),
+	(A, B, C),
+	(A, B, C, D),
+	(A, B, C, D, E),
+	(A, B, C, D, E, F),
+);
+
+#[cfg(feature = "heapless")]
+impl<const SZ: usize> Encoded for heapless::String<SZ> {
+	const ENCODING: Encoding = Encoding::Text {
+		prefix: crate::num_bytes_for_size::<SZ>() as u8,
+		max: SZ,
+	};
+}
+
+#[cfg(feature = "heapless")]
+impl<T: Encoded, const SZ: usize> Encoded for heapless::Vec<T, SZ> {
+	const ENCODING: Encoding = Encoding::List {
+		prefix: crate::num_bytes_for_size::<SZ>() as u8,
+		max: SZ,
+		item: &T::ENCODING,
+	};
+}
+
+#[cfg(feature = "heapless")]
+impl<const N: usize> Encoded for crate::BlobChunk<N> {
+	const ENCODING: Encoding = Encoding::Tuple(&[
+		u32::ENCODING,
+		u32::ENCODING,
+		<heapless::Vec<u8, N>>::ENCODING,
+	]);
+}
+
+#[cfg(feature = "std")]
+impl Encoded for String {
+	const ENCODING: Encoding = Encoding::Text {
+		prefix: crate::num_bytes_for_size::<{ crate::UNBOUNDED }>() as u8,
+		max: crate::UNBOUNDED,
+	};
+}
+
+#[cfg(feature = "std")]
+impl<T: Encoded> Encoded for Vec<T> {
+	const ENCODING: Encoding = Encoding::List {
+		prefix: crate::num_bytes_for_size::<{ crate::UNBOUNDED }>() as u8,
+		max: crate::UNBOUNDED,
+		item: &T::ENCODING,
+	};
+}
@@ -0,0 +1,98 @@
+use crate::{Error, Read, Write};
+use core::convert::Infallible;
+
+/// Reads from an in-memory buffer. Reading past the end of the
+/// buffer results in [`Error::Eof`].
+pub struct SliceReader<'a> {
+	buf: &'a [u8],
+	cursor: usize,
+}
+
+impl<'a> SliceReader<'a> {
+	pub fn new(buf: &'a [u8]) -> Self {
+		Self { buf, cursor: 0 }
+	}
+
+	/// The number of bytes that haven't been read yet.
+	#[inline]
+	pub fn remaining(&self) -> usize {
+		self.buf.len() - self.cursor
+	}
+}
+
+impl<'a> Read for SliceReader<'a> {
+	type Error = Infallible;
+
+	async fn read(&mut self, buf: &mut [u8]) -> Result<(), Error<Self::Error>> {
+		if buf.len() > self.remaining() {
+			return Err(Error::Eof);
+		}
+
+		buf.copy_from_slice(&self.buf[self.cursor..self.cursor + buf.len()]);
+		self.cursor += buf.len();
+		Ok(())
+	}
+
+	fn is_exhausted(&self) -> bool {
+		self.remaining() == 0
+	}
+}
+
+/// Writes into an in-memory buffer. Writing past the end of the
+/// buffer results in [`Error::ArrayTooLong`].
+pub struct SliceWriter<'a> {
+	buf: &'a mut [u8],
+	cursor: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+	pub fn new(buf: &'a mut [u8]) -> Self {
+		Self { buf, cursor: 0 }
+	}
+
+	/// The number of bytes written so far.
+	#[inline]
+	pub fn written(&self) -> usize {
+		self.cursor
+	}
+}
+
+impl<'a> Write for SliceWriter<'a> {
+	type Error = Infallible;
+
+	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<Self::Error>> {
+		if buf.len() > self.buf.len() - self.cursor {
+			return Err(Error::ArrayTooLong {
+				len: self.cursor + buf.len(),
+				max: self.buf.len(),
+			});
+		}
+
+		self.buf[self.cursor..self.cursor + buf.len()].copy_from_slice(buf);
+		self.cursor += buf.len();
+		Ok(())
+	}
+
+	#[inline]
+	async fn flush(&mut self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
+
+impl Error<Infallible> {
+	/// Converts an error from an in-memory reader or writer, which can't
+	/// have I/O errors, into an error for any other reader or writer.
+	pub fn widen<E: crate::MaybeFormat>(self) -> Error<E> {
+		match self {
+			Error::StringTooLong { len, max } => Error::StringTooLong { len, max },
+			Error::ArrayTooLong { len, max } => Error::ArrayTooLong { len, max },
+			Error::InvalidMessageCode(code) => Error::InvalidMessageCode(code),
+			Error::InvalidEnumeration => Error::InvalidEnumeration,
+			Error::MalformedString => Error::MalformedString,
+			Error::Unauthenticated => Error::Unauthenticated,
+			Error::Eof => Error::Eof,
+			Error::Io(never) => match never {},
+			Error::InField { path, error } => Error::InField { path, error },
+		}
+	}
+}
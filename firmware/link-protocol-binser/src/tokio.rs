@@ -0,0 +1,42 @@
+use crate::{Error, Read, Write};
+use std::io::Error as IoError;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Adapts a tokio stream. Unlike `async-std` streams, which implement
+/// [`Read`] and [`Write`] directly, tokio streams need wrapping so that
+/// both runtimes can be enabled at once.
+pub struct TokioIo<T>(pub T);
+
+impl<T> Read for TokioIo<T>
+where
+	T: AsyncRead + Unpin,
+{
+	type Error = IoError;
+
+	#[inline]
+	async fn read(&mut self, buf: &mut [u8]) -> Result<(), Error<Self::Error>> {
+		AsyncReadExt::read_exact(&mut self.0, buf)
+			.await
+			.map(|_| ())
+			.map_err(Error::Io)
+	}
+}
+
+impl<T> Write for TokioIo<T>
+where
+	T: AsyncWrite + Unpin,
+{
+	type Error = IoError;
+
+	#[inline]
+	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<Self::Error>> {
+		AsyncWriteExt::write_all(&mut self.0, buf)
+			.await
+			.map_err(Error::Io)
+	}
+
+	#[inline]
+	async fn flush(&mut self) -> Result<(), Self::Error> {
+		AsyncWriteExt::flush(&mut self.0).await
+	}
+}
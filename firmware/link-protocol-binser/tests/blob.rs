@@ -0,0 +1,228 @@
+use core::convert::Infallible;
+use link_protocol_binser::{
+	blocking::{block_on, decode_from_slice, encode_to_vec},
+	read_blob, write_blob, Blob, BlobChunk, BlobChunks, BlobError, Error, FnSink, MaxEncodedLen,
+	Reassembler, ReassemblyError, SliceReader, StreamChunks, BLOB_CHUNK_LEN,
+};
+
+/// Longer than a few copy chunks, and not a multiple of their length.
+fn blob() -> Vec<u8> {
+	(0..BLOB_CHUNK_LEN * 3 + 17).map(|i| i as u8).collect()
+}
+
+/// A sink that collects everything written to it.
+fn collect(out: &mut Vec<u8>) -> FnSink<impl FnMut(&[u8]) -> Result<(), Infallible> + '_> {
+	FnSink(move |buf: &[u8]| {
+		out.extend_from_slice(buf);
+		Ok(())
+	})
+}
+
+/// The stream encoding of a blob: its length, then its bytes.
+fn encoded(blob: &[u8]) -> Vec<u8> {
+	let mut encoded = (blob.len() as u32).to_be_bytes().to_vec();
+	encoded.extend_from_slice(blob);
+	encoded
+}
+
+#[test]
+fn blobs_roundtrip() {
+	let blob = blob();
+	let bytes = encode_to_vec(&Blob(&blob)).unwrap();
+	assert_eq!(bytes, encoded(&blob));
+
+	let mut received = Vec::new();
+	let len = block_on(read_blob(
+		&mut SliceReader::new(&bytes),
+		&mut collect(&mut received),
+	))
+	.unwrap();
+
+	assert_eq!(len as usize, blob.len());
+	assert_eq!(received, blob);
+}
+
+#[test]
+fn write_blob_copies_exactly_len_bytes() {
+	let blob = blob();
+	let mut source = SliceReader::new(&blob);
+	let mut written = Vec::new();
+
+	block_on(write_blob(&mut collect(&mut written), &mut source, 300)).unwrap();
+
+	assert_eq!(written, encoded(&blob[..300]));
+	assert_eq!(source.remaining(), blob.len() - 300);
+}
+
+#[test]
+fn write_blob_reports_short_sources() {
+	let blob = blob();
+	let mut written = Vec::new();
+
+	let result = block_on(write_blob(
+		&mut collect(&mut written),
+		&mut SliceReader::new(&blob),
+		blob.len() as u32 + 1,
+	));
+
+	assert!(matches!(result, Err(BlobError::Read(Error::Eof))));
+}
+
+#[test]
+fn read_blob_reports_truncated_input() {
+	let blob = blob();
+	let bytes = encoded(&blob);
+	let truncated = &bytes[..bytes.len() - 1];
+	let mut received = Vec::new();
+
+	let result = block_on(read_blob(
+		&mut SliceReader::new(truncated),
+		&mut collect(&mut received),
+	));
+
+	assert!(matches!(result, Err(BlobError::Read(Error::Eof))));
+	// Whole copy chunks are handed over as they arrive.
+	assert_eq!(received, blob[..BLOB_CHUNK_LEN * 3]);
+}
+
+#[test]
+fn read_blob_reports_sink_errors() {
+	let bytes = encoded(&blob());
+	let mut sink = FnSink(|_: &[u8]| Err(std::fmt::Error));
+
+	let result = block_on(read_blob(&mut SliceReader::new(&bytes), &mut sink));
+
+	assert!(matches!(result, Err(BlobError::Write(Error::Io(_)))));
+}
+
+/// Reassembles `chunks`, each of which goes through an encode/decode
+/// roundtrip first. Returns `None` if the blob was cut short.
+fn reassemble<const N: usize>(
+	chunks: impl IntoIterator<Item = BlobChunk<N>>,
+) -> Result<Option<Vec<u8>>, ReassemblyError<Infallible>> {
+	let mut reassembler = Reassembler::new();
+	let mut received = Vec::new();
+
+	for chunk in chunks {
+		let bytes = encode_to_vec(&chunk).unwrap();
+		assert!(bytes.len() <= BlobChunk::<N>::MAX_ENCODED_LEN);
+		let (decoded, _) = decode_from_slice::<BlobChunk<N>>(&bytes).unwrap();
+		assert_eq!(decoded, chunk);
+
+		block_on(reassembler.push(&decoded, &mut collect(&mut received)))?;
+		assert_eq!(reassembler.received() as usize, received.len());
+	}
+
+	Ok(reassembler.is_complete().then_some(received))
+}
+
+#[test]
+fn chunks_reassemble() {
+	let blob = blob();
+	let chunks = BlobChunks::<64>::new(&blob).unwrap().collect::<Vec<_>>();
+
+	assert_eq!(chunks.len(), blob.len().div_ceil(64));
+	assert!(
+		chunks
+			.iter()
+			.all(|chunk| chunk.total as usize == blob.len())
+	);
+	assert_eq!(reassemble(chunks).unwrap(), Some(blob));
+}
+
+#[test]
+fn empty_blobs_have_a_single_chunk() {
+	let chunks = BlobChunks::<64>::new(&[]).unwrap().collect::<Vec<_>>();
+
+	assert_eq!(
+		chunks,
+		[BlobChunk {
+			offset: 0,
+			total: 0,
+			data: heapless::Vec::new(),
+		}]
+	);
+	assert_eq!(reassemble(chunks).unwrap(), Some(Vec::new()));
+}
+
+#[test]
+fn stream_chunks_reassemble() {
+	let blob = blob();
+	let mut stream = StreamChunks::<_, 64>::new(SliceReader::new(&blob), blob.len() as u32);
+
+	let mut chunks = Vec::new();
+	while let Some(chunk) = block_on(stream.next()).unwrap() {
+		chunks.push(chunk);
+	}
+
+	assert_eq!(
+		chunks,
+		BlobChunks::<64>::new(&blob).unwrap().collect::<Vec<_>>()
+	);
+	assert_eq!(reassemble(chunks).unwrap(), Some(blob));
+}
+
+#[test]
+fn stream_chunks_report_truncated_sources() {
+	let blob = blob();
+	let mut stream = StreamChunks::<_, 64>::new(SliceReader::new(&blob), blob.len() as u32 + 1);
+
+	let result = loop {
+		match block_on(stream.next()) {
+			Ok(Some(_)) => {}
+			result => break result,
+		}
+	};
+
+	assert!(matches!(result, Err(Error::Eof)));
+}
+
+#[test]
+fn reassembly_rejects_missing_chunks() {
+	let blob = blob();
+	let mut chunks = BlobChunks::<64>::new(&blob).unwrap().collect::<Vec<_>>();
+	chunks.remove(3);
+
+	assert!(matches!(
+		reassemble(chunks),
+		Err(ReassemblyError::OutOfOrder {
+			expected: 192,
+			offset: 256,
+		})
+	));
+}
+
+#[test]
+fn reassembly_rejects_length_mismatches() {
+	let blob = blob();
+	let mut chunks = BlobChunks::<64>::new(&blob).unwrap().collect::<Vec<_>>();
+	chunks[2].total += 1;
+
+	assert!(matches!(
+		reassemble(chunks),
+		Err(ReassemblyError::LengthMismatch { expected, total }) if total == expected + 1
+	));
+}
+
+#[test]
+fn reassembly_rejects_overruns() {
+	let chunk = BlobChunk::<64> {
+		offset: 0,
+		total: 8,
+		data: heapless::Vec::from_slice(&[0; 9]).unwrap(),
+	};
+
+	assert!(matches!(
+		reassemble([chunk]),
+		Err(ReassemblyError::Overrun { total: 8 })
+	));
+}
+
+#[test]
+fn reassembly_notices_truncated_blobs() {
+	let blob = blob();
+	let mut chunks = BlobChunks::<64>::new(&blob).unwrap().collect::<Vec<_>>();
+	chunks.pop();
+
+	assert_eq!(reassemble(chunks).unwrap(), None);
+}
@@ -0,0 +1,106 @@
+use core::{future::poll_fn, task::Poll};
+use link_protocol_binser::{
+	blocking::{
+		block_on, decode_from_slice, encode_to_vec, DeserializeBlocking, EmbeddedIo,
+		SerializeBlocking, StdIo, VecWriter,
+	},
+	Error, SliceWriter,
+};
+use std::io::{Cursor, ErrorKind};
+
+type Value = (u8, u16, u32, heapless::String<16>, Option<u64>);
+
+fn value() -> Value {
+	(1, 2, 3, "four".try_into().unwrap(), Some(5))
+}
+
+#[test]
+fn encodes_to_vecs() {
+	assert_eq!(encode_to_vec(&0x0102_0304u32).unwrap(), [1, 2, 3, 4]);
+
+	let mut buf = [0u8; 64];
+	let mut writer = SliceWriter::new(&mut buf);
+	value().serialize_blocking(&mut writer).unwrap();
+	let written = writer.written();
+
+	assert_eq!(encode_to_vec(&value()).unwrap(), buf[..written]);
+
+	let mut writer = VecWriter(Vec::new());
+	value().serialize_blocking(&mut writer).unwrap();
+	assert_eq!(writer.0, buf[..written]);
+}
+
+#[test]
+fn decodes_from_the_start_of_slices() {
+	let mut bytes = encode_to_vec(&value()).unwrap();
+	let len = bytes.len();
+	bytes.extend_from_slice(b"trailing");
+
+	assert_eq!(decode_from_slice::<Value>(&bytes).unwrap(), (value(), len));
+}
+
+#[test]
+fn decoding_truncated_slices_fails() {
+	let bytes = encode_to_vec(&value()).unwrap();
+
+	assert!(matches!(
+		decode_from_slice::<Value>(&bytes[..bytes.len() - 1]),
+		Err(Error::Eof)
+	));
+}
+
+#[test]
+fn std_io_roundtrips() {
+	let mut writer = StdIo(Vec::new());
+	value().serialize_blocking(&mut writer).unwrap();
+	assert_eq!(writer.0, encode_to_vec(&value()).unwrap());
+
+	let mut reader = StdIo(Cursor::new(writer.0));
+	assert_eq!(Value::deserialize_blocking(&mut reader).unwrap(), value());
+}
+
+#[test]
+fn std_io_reports_errors() {
+	let bytes = encode_to_vec(&value()).unwrap();
+	let mut reader = StdIo(&bytes[..bytes.len() - 1]);
+
+	assert!(matches!(
+		Value::deserialize_blocking(&mut reader),
+		Err(Error::Io(err)) if err.kind() == ErrorKind::UnexpectedEof
+	));
+}
+
+#[test]
+fn embedded_io_roundtrips() {
+	let mut writer = EmbeddedIo(Vec::new());
+	value().serialize_blocking(&mut writer).unwrap();
+	assert_eq!(writer.0, encode_to_vec(&value()).unwrap());
+
+	let mut reader = EmbeddedIo(&writer.0[..]);
+	assert_eq!(Value::deserialize_blocking(&mut reader).unwrap(), value());
+}
+
+#[test]
+fn embedded_io_reports_errors() {
+	let bytes = encode_to_vec(&value()).unwrap();
+	let mut reader = EmbeddedIo(&bytes[..bytes.len() - 1]);
+
+	assert!(matches!(
+		Value::deserialize_blocking(&mut reader),
+		Err(Error::Io(embedded_io::ReadExactError::UnexpectedEof))
+	));
+}
+
+#[test]
+#[should_panic(expected = "a blocking (de)serialization waited")]
+fn waiting_is_a_bug() {
+	let mut polled = false;
+	block_on(poll_fn(|_| {
+		if polled {
+			Poll::Ready(())
+		} else {
+			polled = true;
+			Poll::Pending
+		}
+	}));
+}
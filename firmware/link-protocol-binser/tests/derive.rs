@@ -0,0 +1,6 @@
+//! Messages the derives refuse, with the errors they give.
+
+#[test]
+fn rejects_invalid_messages() {
+	trybuild::TestCases::new().compile_fail("tests/derive/*.rs");
+}
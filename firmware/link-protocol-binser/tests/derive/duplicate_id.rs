@@ -0,0 +1,11 @@
+use link_protocol_binser::LinkMessage;
+
+#[derive(LinkMessage)]
+enum Message {
+	#[proto(id = 1)]
+	Ping,
+	#[proto(id = 1)]
+	Pong,
+}
+
+fn main() {}
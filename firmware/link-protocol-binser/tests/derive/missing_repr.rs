@@ -0,0 +1,9 @@
+use link_protocol_binser::LinkEnum;
+
+#[derive(LinkEnum)]
+enum State {
+	On = 1,
+	Off = 2,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use link_protocol_binser::LinkEnum;
+
+const ON: u8 = 1;
+
+#[derive(LinkEnum)]
+#[repr(u8)]
+enum State {
+	On = ON,
+	Off = 2,
+}
+
+fn main() {}
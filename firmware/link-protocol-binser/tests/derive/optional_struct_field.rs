@@ -0,0 +1,10 @@
+use link_protocol_binser::LinkMessage;
+
+#[derive(LinkMessage)]
+struct Hello {
+	version: u16,
+	#[proto(since = 2)]
+	flags: u16,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use link_protocol_binser::LinkEnum;
+
+#[derive(LinkEnum)]
+#[repr(u8)]
+enum State {
+	#[proto(id = 3)]
+	On = 1,
+	Off = 2,
+}
+
+fn main() {}
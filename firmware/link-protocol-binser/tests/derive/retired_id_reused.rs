@@ -0,0 +1,12 @@
+use link_protocol_binser::LinkMessage;
+
+#[derive(LinkMessage)]
+#[proto(retired = [2])]
+enum Message {
+	#[proto(id = 1)]
+	Ping,
+	#[proto(id = 2)]
+	Pong,
+}
+
+fn main() {}
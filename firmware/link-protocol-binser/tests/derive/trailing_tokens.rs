@@ -0,0 +1,9 @@
+use link_protocol_binser::LinkMessage;
+
+#[derive(LinkMessage)]
+enum Message {
+	#[proto(id = 1 deprecated)]
+	Ping,
+}
+
+fn main() {}
@@ -0,0 +1,85 @@
+use link_protocol_binser::{
+	blocking::{block_on, decode_from_slice, encode_to_vec},
+	Deserialize, Error, Read, Serialize, SliceReader, Write,
+};
+
+/// Records how many bytes each write was.
+#[derive(Default)]
+struct WriteLog(Vec<usize>);
+
+impl Write for WriteLog {
+	type Error = core::convert::Infallible;
+
+	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<Self::Error>> {
+		self.0.push(buf.len());
+		Ok(())
+	}
+
+	async fn flush(&mut self) -> Result<(), Self::Error> {
+		Ok(())
+	}
+}
+
+/// Records how many bytes each read was.
+struct ReadLog<'a> {
+	reader: SliceReader<'a>,
+	reads: Vec<usize>,
+}
+
+impl Read for ReadLog<'_> {
+	type Error = core::convert::Infallible;
+
+	async fn read(&mut self, buf: &mut [u8]) -> Result<(), Error<Self::Error>> {
+		self.reads.push(buf.len());
+		self.reader.read(buf).await
+	}
+}
+
+fn bytes() -> heapless::Vec<u8, 300> {
+	(0..300).map(|i| i as u8).collect()
+}
+
+#[test]
+fn byte_vectors_are_written_at_once() {
+	let mut writer = WriteLog::default();
+	block_on(bytes().serialize(&mut writer)).unwrap();
+	assert_eq!(writer.0, [2, 300]);
+
+	let mut writer = WriteLog::default();
+	block_on(bytes().to_vec().serialize(&mut writer)).unwrap();
+	assert_eq!(writer.0, [4, 300]);
+}
+
+#[test]
+fn byte_vectors_are_read_at_once() {
+	let encoded = encode_to_vec(&bytes()).unwrap();
+	let mut reader = ReadLog {
+		reader: SliceReader::new(&encoded),
+		reads: Vec::new(),
+	};
+	assert_eq!(
+		block_on(heapless::Vec::<u8, 300>::deserialize(&mut reader)).unwrap(),
+		bytes()
+	);
+	assert_eq!(reader.reads, [2, 300]);
+}
+
+#[test]
+fn other_vectors_are_encoded_item_by_item() {
+	let values: heapless::Vec<u16, 4> = [1, 2, 0x0304].into_iter().collect();
+	let encoded = encode_to_vec(&values).unwrap();
+	assert_eq!(encoded, [3, 0, 1, 0, 2, 3, 4]);
+	assert_eq!(
+		decode_from_slice(&encoded).unwrap(),
+		(values, encoded.len())
+	);
+}
+
+#[test]
+fn byte_vectors_reject_overlong_lengths() {
+	// A length of 5 for a vector that holds at most 4.
+	assert!(matches!(
+		decode_from_slice::<heapless::Vec<u8, 4>>(&[5, 1, 2, 3, 4, 5]),
+		Err(Error::ArrayTooLong { len: 5, max: 4 })
+	));
+}
@@ -0,0 +1,153 @@
+//! Peers on either side of a protocol change, as two versions of the
+//! same message.
+use link_protocol_binser::{
+	blocking::{decode_from_slice, encode_to_vec},
+	schema::SchemaBody,
+	Deserialize, Error, Schema,
+};
+
+mod v1 {
+	use link_protocol_binser::LinkMessage;
+
+	#[derive(Debug, PartialEq, LinkMessage)]
+	pub enum Message {
+		#[proto(id = 1)]
+		Hello { name: u8 },
+		#[proto(id = 2)]
+		Old(u16),
+		#[proto(id = 3)]
+		Gone,
+	}
+}
+
+mod v2 {
+	use link_protocol_binser::LinkMessage;
+
+	#[derive(Debug, PartialEq, LinkMessage)]
+	#[proto(retired = [3])]
+	pub enum Message {
+		#[proto(id = 1)]
+		Hello {
+			name: u8,
+			#[proto(since = 2)]
+			flags: u16,
+			#[proto(default)]
+			verbose: bool,
+		},
+		#[proto(id = 2, deprecated)]
+		Old(u16),
+		#[proto(id = 4)]
+		New,
+	}
+}
+
+#[test]
+fn new_peers_default_fields_old_peers_leave_out() {
+	let bytes = encode_to_vec(&v1::Message::Hello { name: 7 }).unwrap();
+
+	assert_eq!(
+		decode_from_slice::<v2::Message>(&bytes).unwrap(),
+		(
+			v2::Message::Hello {
+				name: 7,
+				flags: 0,
+				verbose: false
+			},
+			bytes.len()
+		)
+	);
+}
+
+#[test]
+fn old_peers_ignore_fields_they_predate() {
+	let bytes = encode_to_vec(&v2::Message::Hello {
+		name: 7,
+		flags: 0x0102,
+		verbose: true,
+	})
+	.unwrap();
+
+	// The new fields are left unread, at the end of the frame.
+	assert_eq!(
+		decode_from_slice::<v1::Message>(&bytes).unwrap(),
+		(v1::Message::Hello { name: 7 }, 2)
+	);
+}
+
+#[test]
+fn optional_fields_are_only_left_out_at_the_end() {
+	let bytes = encode_to_vec(&v2::Message::Hello {
+		name: 7,
+		flags: 0x0102,
+		verbose: true,
+	})
+	.unwrap();
+
+	// A frame that ends partway through an optional field is truncated,
+	// rather than from an older peer.
+	assert!(matches!(
+		decode_from_slice::<v2::Message>(&bytes[..3]),
+		Err(Error::InField { .. })
+	));
+}
+
+#[test]
+fn deprecated_variants_still_decode() {
+	let bytes = encode_to_vec(&v1::Message::Old(42)).unwrap();
+	let (message, _) = decode_from_slice::<v2::Message>(&bytes).unwrap();
+	assert_eq!(message, v2::Message::Old(42));
+	assert_eq!(message.deprecated_variant(), Some("Old"));
+
+	let bytes = encode_to_vec(&v2::Message::Old(42)).unwrap();
+	let (message, _) = decode_from_slice::<v1::Message>(&bytes).unwrap();
+	assert_eq!(message, v1::Message::Old(42));
+
+	let hello = v2::Message::Hello {
+		name: 7,
+		flags: 0,
+		verbose: false,
+	};
+	assert_eq!(hello.deprecated_variant(), None);
+}
+
+#[test]
+fn retired_and_unknown_ids_are_rejected() {
+	let bytes = encode_to_vec(&v1::Message::Gone).unwrap();
+	assert!(matches!(
+		decode_from_slice::<v2::Message>(&bytes),
+		Err(Error::InvalidMessageCode(3))
+	));
+
+	let bytes = encode_to_vec(&v2::Message::New).unwrap();
+	assert!(matches!(
+		decode_from_slice::<v1::Message>(&bytes),
+		Err(Error::InvalidMessageCode(4))
+	));
+}
+
+#[test]
+fn schemas_describe_versions() {
+	assert_eq!(
+		[
+			<v1::Message as Deserialize>::HAS_OPTIONAL_FIELDS,
+			<v2::Message as Deserialize>::HAS_OPTIONAL_FIELDS,
+		],
+		[false, true]
+	);
+
+	assert_eq!(v1::Message::SCHEMA.retired, &[] as &[u8]);
+	assert_eq!(v2::Message::SCHEMA.retired, &[3]);
+
+	let SchemaBody::Enum(variants) = v2::Message::SCHEMA.body else {
+		panic!("messages are enums");
+	};
+	let fields = variants[0].fields;
+	assert_eq!(
+		fields
+			.iter()
+			.map(|f| (f.optional, f.since))
+			.collect::<Vec<_>>(),
+		[(false, None), (true, Some(2)), (true, None)]
+	);
+	assert!(variants[1].deprecated);
+}
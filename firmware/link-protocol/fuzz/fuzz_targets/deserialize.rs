@@ -0,0 +1,24 @@
+//! Feeds raw bytes to each packet decoder. Anything that decodes must
+//! re-encode and decode to the same packet.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use link_protocol::{
+	blocking::{decode_from_slice, encode_to_vec},
+	ClientToDaemon, Control, DaemonToClient, DaemonToLink, Deserialize, LinkToDaemon, Serialize,
+};
+
+fn check<T: Serialize + Deserialize + PartialEq + core::fmt::Debug>(data: &[u8]) {
+	if let Ok((packet, _)) = decode_from_slice::<T>(data) {
+		let encoded = encode_to_vec(&packet).unwrap();
+		assert_eq!(decode_from_slice::<T>(&encoded).unwrap().0, packet);
+	}
+}
+
+fuzz_target!(|data: &[u8]| {
+	check::<Control>(data);
+	check::<LinkToDaemon>(data);
+	check::<DaemonToLink>(data);
+	check::<ClientToDaemon>(data);
+	check::<DaemonToClient>(data);
+});
@@ -0,0 +1,40 @@
+//! Generates packets of every direction and checks that they decode
+//! to themselves, within their maximum encoded length.
+#![no_main]
+
+use libfuzzer_sys::{
+	arbitrary::{self, Arbitrary},
+	fuzz_target,
+};
+use link_protocol::{
+	blocking::{decode_from_slice, encode_to_vec},
+	ClientToDaemon, Control, DaemonToClient, DaemonToLink, LinkToDaemon, MaxEncodedLen,
+};
+
+#[derive(Arbitrary, Debug)]
+enum Packet {
+	Control(Control),
+	LinkToDaemon(LinkToDaemon),
+	DaemonToLink(DaemonToLink),
+	ClientToDaemon(ClientToDaemon),
+	DaemonToClient(DaemonToClient),
+}
+
+macro_rules! check {
+	($ty:ty, $packet:expr) => {{
+		let encoded = encode_to_vec(&$packet).unwrap();
+		assert!(encoded.len() <= <$ty>::MAX_ENCODED_LEN);
+		assert_eq!(
+			decode_from_slice::<$ty>(&encoded).unwrap(),
+			($packet, encoded.len())
+		);
+	}};
+}
+
+fuzz_target!(|packet: Packet| match packet {
+	Packet::Control(packet) => check!(Control, packet),
+	Packet::LinkToDaemon(packet) => check!(LinkToDaemon, packet),
+	Packet::DaemonToLink(packet) => check!(DaemonToLink, packet),
+	Packet::ClientToDaemon(packet) => check!(ClientToDaemon, packet),
+	Packet::DaemonToClient(packet) => check!(DaemonToClient, packet),
+});
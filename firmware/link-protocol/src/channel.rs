@@ -1,24 +1,30 @@
+mod replies;
+
 use crate::{
-	macros::{debug, error, trace},
-	Deserialize, Error, Packet, Read, Serialize, Write,
-};
-use aes::{
-	cipher::{BlockDecrypt, BlockEncrypt, KeyInit},
-	Aes256Dec, Aes256Enc,
+	macros::{debug, error, trace, warning},
+	Capabilities, ClientToDaemon, Control, DaemonToClient, DaemonToLink, Deserialize, Direction,
+	Error, FieldError, LinkToDaemon, MaxEncodedLen, Read, Serialize, Write, MIN_PROTOCOL_VERSION,
+	PROTOCOL_VERSION,
 };
-#[cfg(feature = "async-std")]
-use async_std::sync::Mutex;
-use core::ops::DerefMut;
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use core::{convert::Infallible, marker::PhantomData};
 use curve25519::{curve25519, curve25519_pk, curve25519_sk};
-use link_protocol_binser::MaybeFormat;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use futures::lock::Mutex;
+use hkdf::Hkdf;
+use link_protocol_binser::{MaybeFormat, SliceReader, SliceWriter};
 use rand_core::RngCore;
+use replies::Replies;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub use replies::{PendingReply, ReplyError, RequestError};
+use sha2::{Digest, Sha256};
 #[cfg(feature = "embassy")]
 type Mutex<T> = ::embassy_sync::mutex::Mutex<::embassy_sync::blocking_mutex::raw::NoopRawMutex, T>;
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
-pub enum RWError<R, W>
+pub enum NegotiationError<R, W>
 where
 	R: MaybeFormat,
 	W: MaybeFormat,
@@ -33,26 +39,196 @@ where
 		error("an error occurred writing to the peer: {0}")
 	)]
 	Write(W),
+	/// The handshake was aborted because one of the sides did not
+	/// trust the other's identity key (or the other side could not
+	/// prove it holds the secret for the key it presented).
+	#[cfg_attr(
+		feature = "thiserror",
+		error("the {0} side rejected the peer's identity key")
+	)]
+	Rejected(Side),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Side {
 	Client,
 	Server,
 }
 
+impl Side {
+	/// The side on the other end of the connection.
+	pub fn peer(self) -> Self {
+		match self {
+			Side::Client => Side::Server,
+			Side::Server => Side::Client,
+		}
+	}
+}
+
+impl core::fmt::Display for Side {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			Side::Client => f.write_str("client"),
+			Side::Server => f.write_str("server"),
+		}
+	}
+}
+
+/// A long-term curve25519 keypair that identifies a peer across
+/// connections. Links derive theirs from their unique ID; daemons
+/// are provisioned with one.
+#[derive(Clone)]
+pub struct Identity {
+	secret: [u8; 32],
+	public: [u8; 32],
+}
+
+impl Identity {
+	/// Creates an identity from a 32 byte secret. The secret is clamped
+	/// and thus doesn't need to be a valid curve25519 key beforehand.
+	pub fn from_secret(secret: [u8; 32]) -> Self {
+		let secret = curve25519_sk(secret);
+		let public = curve25519_pk(secret);
+		Self { secret, public }
+	}
+
+	/// The public key of the identity, which is what the other side
+	/// should pin or allow.
+	#[inline]
+	pub fn public_key(&self) -> [u8; 32] {
+		self.public
+	}
+}
+
+/// Decides whether or not a peer's identity key is trusted.
+pub trait TrustPolicy {
+	fn is_trusted(&self, public_key: &[u8; 32]) -> bool;
+}
+
+/// A single, pinned key.
+impl TrustPolicy for [u8; 32] {
+	fn is_trusted(&self, public_key: &[u8; 32]) -> bool {
+		keys_equal(self, public_key)
+	}
+}
+
+/// An allowlist of keys.
+impl TrustPolicy for [[u8; 32]] {
+	fn is_trusted(&self, public_key: &[u8; 32]) -> bool {
+		// Deliberately not short-circuiting.
+		self.iter()
+			.fold(false, |trusted, key| keys_equal(key, public_key) | trusted)
+	}
+}
+
+/// Trusts any peer, no matter its key. Only use this for transports
+/// that are already access controlled by some other means (e.g. local
+/// unix sockets).
+pub struct TrustAny;
+
+impl TrustPolicy for TrustAny {
+	#[inline]
+	fn is_trusted(&self, _public_key: &[u8; 32]) -> bool {
+		true
+	}
+}
+
+fn keys_equal(a: &[u8; 32], b: &[u8; 32]) -> bool {
+	a.iter()
+		.zip(b.iter())
+		.fold(0u8, |acc, (a, b)| acc | (a ^ b))
+		== 0
+}
+
+/// The u-coordinates of the curve25519 points of small order (and their
+/// non-canonical encodings). A key exchange with any of them yields an
+/// all-zero shared secret, no matter our own secret.
+const LOW_ORDER_POINTS: [[u8; 32]; 7] = [
+	// 0 and 1.
+	point(0, 0, 0),
+	point(1, 0, 0),
+	// The two points of order 8.
+	[
+		0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+		0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+		0xb8, 0x00,
+	],
+	[
+		0x5f, 0x9c, 0x95, 0xbc, 0xa3, 0x50, 0x8c, 0x24, 0xb1, 0xd0, 0xb1, 0x55, 0x9c, 0x83, 0xef,
+		0x5b, 0x04, 0x44, 0x5c, 0xc4, 0x58, 0x1c, 0x8e, 0x86, 0xd8, 0x22, 0x4e, 0xdd, 0xd0, 0x9f,
+		0x11, 0x57,
+	],
+	// p - 1, p and p + 1.
+	point(0xec, 0xff, 0x7f),
+	point(0xed, 0xff, 0x7f),
+	point(0xee, 0xff, 0x7f),
+];
+
+/// A little-endian key made up of its lowest byte, its highest byte,
+/// and `fill` in between.
+const fn point(lowest: u8, fill: u8, highest: u8) -> [u8; 32] {
+	let mut point = [fill; 32];
+	point[0] = lowest;
+	point[31] = highest;
+	point
+}
+
+/// Whether a peer's public key is one that must never be accepted: the
+/// all-zero key, or any other point of small order. The top bit is
+/// ignored by curve25519, so it's ignored here too.
+fn is_low_order(public_key: &[u8; 32]) -> bool {
+	let mut masked = *public_key;
+	masked[31] &= 0x7f;
+	LOW_ORDER_POINTS
+		.iter()
+		.fold(false, |low, point| keys_equal(point, &masked) | low)
+}
+
+/// Whether a key exchange produced the all-zero shared secret, which
+/// anyone could have computed.
+fn is_zero(shared: &[u8; 32]) -> bool {
+	keys_equal(shared, &[0; 32])
+}
+
+const VERDICT_REJECT: u8 = 0;
+const VERDICT_ACCEPT: u8 = 1;
+
 /// Negotiates a connection with a stream reader/writer, forming an
 /// encrypted channel and returning a command sender/receiver usable
-/// to send and receive AES-encrypted packets.
-pub async fn negotiate<W: Write, R: Read, Rng: RngCore>(
+/// to send and receive packets sealed with ChaCha20-Poly1305. The
+/// sender sends `S` packets, and the receiver receives `Rx` packets.
+///
+/// Both sides present their long-term [`Identity`] alongside an ephemeral
+/// key. The channel key mixes in both identities, so a peer that can't
+/// prove ownership of the identity key it presented fails the key
+/// confirmation step. Each side checks the other's identity key against
+/// `trust` and fails closed, telling the peer which side rejected it.
+/// Low-order keys (including the all-zero key) are always rejected, as
+/// is an exchange that produces an all-zero shared secret.
+pub async fn negotiate<
+	W: Write,
+	R: Read,
+	S: Direction,
+	Rx: Direction,
+	Rng: RngCore,
+	T: TrustPolicy + ?Sized,
+>(
 	mut sock_writer: W,
 	mut sock_reader: R,
 	rng: &mut Rng,
 	side: Side,
-) -> Result<(PacketSender<W>, PacketReceiver<R>), RWError<Error<R::Error>, Error<W::Error>>> {
+	identity: &Identity,
+	trust: &T,
+) -> Result<
+	(PacketSender<W, S>, PacketReceiver<R, Rx>),
+	NegotiationError<Error<R::Error>, Error<W::Error>>,
+> {
 	debug!("link-proto: beginning encryption negotiation");
 
 	let mut sk = [0u8; 32];
 	let mut their_pk = [0u8; 32];
+	let mut their_identity = [0u8; 32];
 
 	rng.fill_bytes(&mut sk);
 	let sk = curve25519_sk(sk);
@@ -68,200 +244,876 @@ pub async fn negotiate<W: Write, R: Read, Rng: RngCore>(
 	for side in side_order {
 		match side {
 			Side::Client => {
-				debug!("link-proto: writing public key");
-				sock_writer.write(&pk[..]).await.map_err(RWError::Write)?;
+				debug!("link-proto: writing public keys");
+				sock_writer
+					.write(&pk[..])
+					.await
+					.map_err(NegotiationError::Write)?;
+				sock_writer
+					.write(&identity.public[..])
+					.await
+					.map_err(NegotiationError::Write)?;
 				sock_writer
 					.flush()
 					.await
-					.map_err(|err| RWError::Write(Error::Io(err)))?;
-				debug!("link-proto: wrote public key");
+					.map_err(|err| NegotiationError::Write(Error::Io(err)))?;
+				debug!("link-proto: wrote public keys");
 			}
 			Side::Server => {
-				debug!("link-proto: reading public key");
+				debug!("link-proto: reading public keys");
 				sock_reader
 					.read(&mut their_pk[..])
 					.await
-					.map_err(RWError::Read)?;
-				debug!("link-proto: read public key");
+					.map_err(NegotiationError::Read)?;
+				sock_reader
+					.read(&mut their_identity[..])
+					.await
+					.map_err(NegotiationError::Read)?;
+				debug!("link-proto: read public keys");
 			}
 		}
 	}
 
-	let key = curve25519(sk, their_pk);
+	// The static-ephemeral terms are what bind the channel key to
+	// the identities; they're ordered by role so that both sides
+	// arrive at the same key.
+	let ee = curve25519(sk, their_pk);
+	let (client_es, client_se) = match side {
+		Side::Client => (
+			curve25519(sk, their_identity),
+			curve25519(identity.secret, their_pk),
+		),
+		Side::Server => (
+			curve25519(identity.secret, their_pk),
+			curve25519(sk, their_identity),
+		),
+	};
+	let (client_pk, server_pk) = match side {
+		Side::Client => (pk, their_pk),
+		Side::Server => (their_pk, pk),
+	};
+
+	// Low-order keys would make the exchange's output predictable, which
+	// would let anyone pass for a peer whose key is one of them (e.g. a
+	// pinned key that was never configured); they're rejected like any
+	// other untrusted key.
+	let trusted = if is_low_order(&their_pk) || is_low_order(&their_identity) {
+		warning!("link-proto: peer presented a low-order public key");
+		false
+	} else if [ee, client_es, client_se].iter().any(is_zero) {
+		warning!("link-proto: key exchange produced an all-zero shared secret");
+		false
+	} else if !trust.is_trusted(&their_identity) {
+		warning!("link-proto: peer presented an untrusted identity key");
+		false
+	} else {
+		true
+	};
+
+	let key: [u8; 32] = Sha256::new()
+		.chain_update(b"oro-link channel key")
+		.chain_update(ee)
+		.chain_update(client_es)
+		.chain_update(client_se)
+		.chain_update(client_pk)
+		.chain_update(server_pk)
+		.finalize()
+		.into();
 	debug!("link-proto: generated shared key");
 
-	let enc = Aes256Enc::new_from_slice(&key[..]).unwrap();
-	let dec = Aes256Dec::new_from_slice(&key[..]).unwrap();
-	debug!("link-proto: generated encryption instances");
+	// Same ordering trick as above; the client speaks first.
+	for step in side_order {
+		match step {
+			Side::Client => {
+				debug!("link-proto: writing identity verdict");
+				if trusted {
+					sock_writer
+						.write(&[VERDICT_ACCEPT])
+						.await
+						.map_err(NegotiationError::Write)?;
+					sock_writer
+						.write(&confirmation_tag(&key, side)[..])
+						.await
+						.map_err(NegotiationError::Write)?;
+				} else {
+					sock_writer
+						.write(&[VERDICT_REJECT])
+						.await
+						.map_err(NegotiationError::Write)?;
+				}
+				sock_writer
+					.flush()
+					.await
+					.map_err(|err| NegotiationError::Write(Error::Io(err)))?;
+
+				if !trusted {
+					return Err(NegotiationError::Rejected(side));
+				}
+			}
+			Side::Server => {
+				debug!("link-proto: reading identity verdict");
+				let mut verdict = [0u8; 1];
+				sock_reader
+					.read(&mut verdict[..])
+					.await
+					.map_err(NegotiationError::Read)?;
+
+				if verdict[0] != VERDICT_ACCEPT {
+					error!("link-proto: peer rejected our identity key");
+					return Err(NegotiationError::Rejected(side.peer()));
+				}
+
+				let mut their_tag = [0u8; 32];
+				sock_reader
+					.read(&mut their_tag[..])
+					.await
+					.map_err(NegotiationError::Read)?;
+
+				if !keys_equal(&their_tag, &confirmation_tag(&key, side.peer())) {
+					error!("link-proto: peer failed to prove ownership of its identity key");
+
+					// Let the peer know, if we haven't already said anything.
+					if side == Side::Server {
+						sock_writer
+							.write(&[VERDICT_REJECT])
+							.await
+							.map_err(NegotiationError::Write)?;
+						sock_writer
+							.flush()
+							.await
+							.map_err(|err| NegotiationError::Write(Error::Io(err)))?;
+					}
+
+					return Err(NegotiationError::Rejected(side));
+				}
+			}
+		}
+	}
+
+	debug!("link-proto: identities verified");
+
+	// Each direction gets its own key so that the per-direction
+	// record counters never produce the same key/nonce pair.
+	let (send_key, receive_key) = match side {
+		Side::Client => (
+			direction_key(&key, b"oro-link client->server"),
+			direction_key(&key, b"oro-link server->client"),
+		),
+		Side::Server => (
+			direction_key(&key, b"oro-link server->client"),
+			direction_key(&key, b"oro-link client->server"),
+		),
+	};
+	debug!("link-proto: derived record keys");
+
+	// Rekeys draw their ephemeral keys from this, so that they don't
+	// need an rng at hand (see `RecordSender::ephemeral`).
+	let mut seed = [0u8; 32];
+	rng.fill_bytes(&mut seed);
+
+	let replies = Replies::default();
 
 	Ok((
-		PacketSender::new(sock_writer, enc),
-		PacketReceiver::new(sock_reader, dec),
+		PacketSender::new(sock_writer, send_key, seed, replies.clone()),
+		PacketReceiver::new(sock_reader, receive_key, replies),
 	))
 }
 
-pub struct PacketSender<W: Write> {
-	sock: Mutex<BlockSender<W>>,
+fn direction_key(key: &[u8; 32], info: &[u8]) -> [u8; 32] {
+	let mut out = [0u8; 32];
+	Hkdf::<Sha256>::new(None, &key[..])
+		.expand(info, &mut out[..])
+		.unwrap();
+	out
+}
+
+/// Derives the next key for a direction from its current key and the
+/// result of a [`Control::Rekey`] exchange. Mixing in the current key
+/// means the exchange alone isn't enough to recover the new key, and
+/// keeps the two directions' keys apart.
+fn next_key(key: &[u8; 32], shared: &[u8; 32]) -> [u8; 32] {
+	let mut out = [0u8; 32];
+	Hkdf::<Sha256>::new(Some(&key[..]), &shared[..])
+		.expand(b"oro-link rekey", &mut out[..])
+		.unwrap();
+	out
 }
 
-impl<W: Write> PacketSender<W> {
-	fn new(sock: W, tls: Aes256Enc) -> Self {
+/// Proves to the other side that `side` derived the same key,
+/// which it can only do if it holds the secret for its identity.
+fn confirmation_tag(key: &[u8; 32], side: Side) -> [u8; 32] {
+	Sha256::new()
+		.chain_update(b"oro-link key confirmation")
+		.chain_update(key)
+		.chain_update(match side {
+			Side::Client => b"client",
+			Side::Server => b"server",
+		})
+		.finalize()
+		.into()
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+pub enum HelloError<R, W>
+where
+	R: MaybeFormat,
+	W: MaybeFormat,
+{
+	#[cfg_attr(
+		feature = "thiserror",
+		error("an error occurred reading from the peer: {0}")
+	)]
+	Read(R),
+	#[cfg_attr(
+		feature = "thiserror",
+		error("an error occurred writing to the peer: {0}")
+	)]
+	Write(W),
+	/// The peer sent something other than a hello packet first; it's
+	/// most likely running a version from before hellos were introduced.
+	#[cfg_attr(
+		feature = "thiserror",
+		error(
+			"the peer did not send a hello packet; it is likely running an incompatible version"
+		)
+	)]
+	NoHello,
+	#[cfg_attr(
+		feature = "thiserror",
+		error("incompatible protocol versions (ours: {ours}, theirs: {theirs})")
+	)]
+	VersionMismatch { ours: u16, theirs: u16 },
+	#[cfg_attr(
+		feature = "thiserror",
+		error("the peer is missing required capabilities: {0}")
+	)]
+	MissingCapabilities(Capabilities),
+}
+
+/// Exchanges [`Control::Hello`]s over a freshly negotiated channel. Must
+/// be called by both sides before any other packets are sent.
+///
+/// Each side advertises the capabilities it `offers`. Fails if the
+/// peer is older than [`MIN_PROTOCOL_VERSION`] or doesn't offer all of
+/// the `required` capabilities; otherwise returns the capabilities
+/// both sides support.
+pub async fn exchange_hello<W: Write, R: Read, S: Direction, Rx: Direction>(
+	sender: &PacketSender<W, S>,
+	receiver: &mut PacketReceiver<R, Rx>,
+	offers: Capabilities,
+	required: Capabilities,
+) -> Result<Capabilities, HelloError<Error<R::Error>, Error<W::Error>>> {
+	debug!("link-proto: sending hello");
+	sender
+		.send_control(Control::Hello {
+			version: PROTOCOL_VERSION,
+			capabilities: offers,
+		})
+		.await
+		.map_err(HelloError::Write)?;
+
+	let (version, theirs) = match receiver.receive().await {
+		Ok(Incoming::Control(Control::Hello {
+			version,
+			capabilities,
+		})) => (version, capabilities),
+		Ok(_) | Err(Error::InvalidMessageCode(_)) => {
+			error!("link-proto: peer did not send a hello packet");
+			return Err(HelloError::NoHello);
+		}
+		Err(err) => return Err(HelloError::Read(err)),
+	};
+	debug!("link-proto: received hello (version {})", version);
+
+	// Anything at or above our minimum is framed the same way we frame
+	// things, so packets one side doesn't know about are simply skipped.
+	// Peers newer than us are responsible for not requiring anything of
+	// us we don't understand.
+	if version < MIN_PROTOCOL_VERSION {
+		error!(
+			"link-proto: peer speaks protocol version {}, but we need at least {}",
+			version, MIN_PROTOCOL_VERSION
+		);
+		return Err(HelloError::VersionMismatch {
+			ours: PROTOCOL_VERSION,
+			theirs: version,
+		});
+	}
+
+	let missing = required.difference(theirs);
+	if !missing.is_empty() {
+		return Err(HelloError::MissingCapabilities(missing));
+	}
+
+	Ok(offers.intersection(theirs))
+}
+
+/// The maximum size of a single record's plaintext. Each packet is
+/// framed and sealed into exactly one record, so this must be larger
+/// than the largest encoding of any packet plus its frame header.
+///
+/// This is deliberately larger than today's largest packet, so that
+/// larger packets from newer peers can still be received (and skipped).
+const RECORD_MAX_LEN: usize = 1024;
+/// The size of the `[length: u16][seq: u16]` header in front of each packet.
+const FRAME_HEADER_LEN: usize = 4;
+/// The size of the Poly1305 tag after each record's ciphertext.
+const TAG_LEN: usize = 16;
+/// The most bytes a single sealed record takes up on the wire, i.e. its
+/// length prefix, ciphertext and tag. Socket buffers should hold at least
+/// one of these.
+pub const SEALED_RECORD_MAX_LEN: usize = 2 + RECORD_MAX_LEN + TAG_LEN;
+
+const _: () = assert!(
+	FRAME_HEADER_LEN + Control::MAX_ENCODED_LEN <= RECORD_MAX_LEN
+		&& FRAME_HEADER_LEN + LinkToDaemon::MAX_ENCODED_LEN <= RECORD_MAX_LEN
+		&& FRAME_HEADER_LEN + DaemonToLink::MAX_ENCODED_LEN <= RECORD_MAX_LEN
+		&& FRAME_HEADER_LEN + ClientToDaemon::MAX_ENCODED_LEN <= RECORD_MAX_LEN
+		&& FRAME_HEADER_LEN + DaemonToClient::MAX_ENCODED_LEN <= RECORD_MAX_LEN,
+	"the largest packet no longer fits into a single record"
+);
+
+/// Builds the nonce for the record with the given counter value.
+/// Counters start at zero and are never reused for a given key; a
+/// replayed, dropped or reordered record thus fails authentication.
+fn record_nonce(counter: u64) -> Nonce {
+	let mut nonce = Nonce::default();
+	nonce[4..].copy_from_slice(&counter.to_be_bytes());
+	nonce
+}
+
+/// How long a key may be used for before [`PacketSender::rekey_if_due`]
+/// (or, for the byte budget, sending a packet) starts replacing it.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+	/// How many bytes may be sent under a single key.
+	pub max_bytes: u64,
+	/// How many milliseconds a single key may be used for.
+	pub max_millis: u64,
+}
+
+impl Default for RekeyPolicy {
+	fn default() -> Self {
+		Self {
+			max_bytes: 64 * 1024 * 1024,
+			max_millis: 60 * 60 * 1000,
+		}
+	}
+}
+
+/// A packet received by a [`PacketReceiver`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Incoming<P> {
+	/// One of the packets of the receiver's direction.
+	Packet(P),
+	/// A control packet the channel didn't handle itself. A
+	/// [`Control::Rekey`] must be passed on to
+	/// [`PacketSender::answer_rekey`] before receiving anything else.
+	Control(Control),
+	/// A request this side doesn't understand (e.g. a packet from a newer
+	/// peer), which should be answered with a [`Control::Nack`] for
+	/// [`NackReason::Unsupported`](crate::NackReason::Unsupported) so the peer isn't left waiting. Only
+	/// returned by [`PacketReceiver::receive_request`].
+	Unsupported { seq: u16 },
+}
+
+/// Sends `P` packets, along with [`Control`] packets.
+pub struct PacketSender<W: Write, P: Direction> {
+	sock: Mutex<RecordSender<W>>,
+	#[cfg_attr(not(any(feature = "async-std", feature = "tokio")), allow(dead_code))]
+	replies: Replies,
+	_direction: PhantomData<fn(P)>,
+}
+
+impl<W: Write, P: Direction> PacketSender<W, P> {
+	fn new(sock: W, key: [u8; 32], seed: [u8; 32], replies: Replies) -> Self {
 		Self {
-			sock: Mutex::new(BlockSender {
+			sock: Mutex::new(RecordSender {
 				sock,
-				tls,
-				block: [0; 16],
-				cursor: 0,
+				cipher: ChaCha20Poly1305::new(&key.into()),
+				key,
+				seed,
+				offered: None,
+				policy: None,
+				counter: 0,
+				bytes_since_rekey: 0,
+				keyed_at: None,
+				next_seq: 1,
+				buffer: [0; RECORD_MAX_LEN],
 			}),
+			replies,
+			_direction: PhantomData,
 		}
 	}
 
-	pub async fn send(&mut self, packet: Packet) -> Result<(), Error<W::Error>> {
+	/// Starts switching both directions over to new keys, unless that's
+	/// already under way. Only call this for peers with
+	/// [`Capabilities::REKEY`].
+	///
+	/// This offers the peer a fresh ephemeral key in a [`Control::Rekey`],
+	/// which it answers with one of its own (see
+	/// [`PacketSender::answer_rekey`]). Each side then derives its new
+	/// send key from the exchange between the two, and switches to it
+	/// right after telling the other with a [`Control::Rekeyed`], so
+	/// nothing in flight is lost.
+	pub async fn rekey<Rng: RngCore>(&self, rng: &mut Rng) -> Result<(), Error<W::Error>> {
 		let mut sock = self.sock.lock().await;
-		packet.serialize(sock.deref_mut()).await?;
-		sock.flush().await
+		sock.reseed(rng);
+		if sock.offered.is_none() {
+			sock.offer_rekey().await?;
+		}
+		Ok(())
 	}
-}
 
-struct BlockSender<W: Write> {
-	sock: W,
-	tls: Aes256Enc,
-	block: [u8; 16],
-	cursor: usize,
-}
-
-impl<W: Write> BlockSender<W> {
-	async fn flush(&mut self) -> Result<(), Error<W::Error>> {
-		// Flush any remaining contents in the last block
-		// Kind of a hack to DRY up the sending sequence...
-		if self.cursor > 0 {
-			for _ in self.cursor..self.block.len() {
-				<Self as Write>::write(self, &[0]).await?;
-			}
+	/// Calls [`PacketSender::rekey`] if the current key has been used
+	/// for longer than the `policy` allows, returning whether or not it
+	/// did. Must be called regularly (e.g. along with heartbeats) with a
+	/// monotonic millisecond timestamp. Once this has been called, the
+	/// byte budget is also checked whenever a packet is sent.
+	pub async fn rekey_if_due<Rng: RngCore>(
+		&self,
+		rng: &mut Rng,
+		policy: &RekeyPolicy,
+		now_millis: u64,
+	) -> Result<bool, Error<W::Error>> {
+		let mut sock = self.sock.lock().await;
+		sock.reseed(rng);
+		sock.policy = Some(*policy);
+		let keyed_at = *sock.keyed_at.get_or_insert(now_millis);
+		if sock.offered.is_some()
+			|| (sock.bytes_since_rekey < policy.max_bytes
+				&& now_millis.saturating_sub(keyed_at) < policy.max_millis)
+		{
+			return Ok(false);
 		}
 
-		Ok(self.sock.flush().await?)
+		debug!(
+			"link-proto: rekeying after {} bytes",
+			sock.bytes_since_rekey
+		);
+		sock.offer_rekey().await?;
+		Ok(true)
 	}
-}
 
-impl<W: Write> Write for BlockSender<W> {
-	type Error = W::Error;
+	/// Answers a [`Control::Rekey`] that `receiver` handed over, which
+	/// must happen before anything else is received. Switches our send
+	/// key, and has `receiver` switch to the peer's new key as soon as
+	/// the peer does.
+	///
+	/// Fails if the peer's ephemeral key is of low order, or the exchange
+	/// with it produces an all-zero shared secret.
+	pub async fn answer_rekey<R: Read, Rx: Direction>(
+		&self,
+		receiver: &mut PacketReceiver<R, Rx>,
+		public_key: &[u8; 32],
+	) -> Result<(), Error<W::Error>> {
+		let mut sock = self.sock.lock().await;
+		let shared = sock.answer_rekey(public_key).await?;
+		receiver.sock.get_mut().next_shared = Some(shared);
+		Ok(())
+	}
 
-	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<W::Error>> {
-		let mut remaining = buf.len();
-		let mut off = 0;
+	/// Sends a packet without expecting a reply.
+	pub async fn send(&self, packet: P) -> Result<(), Error<W::Error>> {
+		let mut sock = self.sock.lock().await;
+		sock.send(&packet, 0).await
+	}
 
-		while remaining > 0 {
-			debug_assert!(self.cursor <= self.block.len());
+	/// Sends a control packet, e.g. a reply to one of the peer's requests.
+	pub async fn send_control(&self, packet: Control) -> Result<(), Error<W::Error>> {
+		let mut sock = self.sock.lock().await;
+		sock.send(&packet, 0).await
+	}
+
+	/// Sends a packet with a fresh sequence ID, which the peer is expected
+	/// to [`Control::Ack`] or [`Control::Nack`]. The returned handle can be
+	/// used to wait for that reply.
+	#[cfg(any(feature = "async-std", feature = "tokio"))]
+	pub async fn send_request(&self, packet: P) -> Result<PendingReply, Error<W::Error>> {
+		let mut sock = self.sock.lock().await;
+		let seq = sock.next_seq();
+		// Registered before sending so that a quick reply isn't missed.
+		let pending = self.replies.register(seq);
+		sock.send(&packet, seq).await?;
+		Ok(pending)
+	}
 
-			let to_write = remaining.min(self.block.len() - self.cursor);
-			self.block[self.cursor..self.cursor + to_write]
-				.copy_from_slice(&buf[off..off + to_write]);
-			remaining -= to_write;
-			off += to_write;
-			self.cursor += to_write;
+	/// Sends a request and waits up to `timeout` for the peer's reply.
+	#[cfg(any(feature = "async-std", feature = "tokio"))]
+	pub async fn request(
+		&self,
+		packet: P,
+		timeout: core::time::Duration,
+	) -> Result<(), RequestError<Error<W::Error>>> {
+		self.send_request(packet)
+			.await
+			.map_err(RequestError::Write)?
+			.wait(timeout)
+			.await
+			.map_err(RequestError::Reply)
+	}
+}
 
-			if self.cursor >= self.block.len() {
-				debug_assert_eq!(self.cursor, self.block.len());
+/// Seals each packet into an authenticated record of the form
+/// `[length: u16][ciphertext][tag]`, where `length` is the length
+/// of the ciphertext and is authenticated as associated data.
+struct RecordSender<W: Write> {
+	sock: W,
+	cipher: ChaCha20Poly1305,
+	key: [u8; 32],
+	/// What ephemeral keys for rekeys are drawn from.
+	seed: [u8; 32],
+	/// The secret for the ephemeral key we offered the peer, while we
+	/// wait for its answer.
+	offered: Option<[u8; 32]>,
+	/// Set by [`PacketSender::rekey_if_due`], for checking the byte
+	/// budget as packets are sent.
+	policy: Option<RekeyPolicy>,
+	counter: u64,
+	bytes_since_rekey: u64,
+	keyed_at: Option<u64>,
+	next_seq: u16,
+	buffer: [u8; RECORD_MAX_LEN],
+}
 
-				self.tls.encrypt_block((&mut self.block[..]).into());
+impl<W: Write> RecordSender<W> {
+	/// Allocates a request sequence ID. Zero means "no reply expected"
+	/// and is skipped when wrapping around.
+	#[cfg_attr(not(any(feature = "async-std", feature = "tokio")), allow(dead_code))]
+	fn next_seq(&mut self) -> u16 {
+		let seq = self.next_seq;
+		self.next_seq = self.next_seq.checked_add(1).unwrap_or(1);
+		seq
+	}
 
-				self.sock
-					.write(&self.block[..])
-					.await
-					.map_err(|#[allow(unused)] err| {
-						error!("link-proto: failed to write block: {:?}", err);
-						Error::Eof
-					})?;
+	async fn send<M: Serialize>(&mut self, packet: &M, seq: u16) -> Result<(), Error<W::Error>> {
+		self.write_record(packet, seq).await?;
 
-				self.cursor = 0;
-			}
+		// Heartbeats may be too far apart to catch a burst of traffic.
+		if self.offered.is_none()
+			&& self
+				.policy
+				.is_some_and(|policy| self.bytes_since_rekey >= policy.max_bytes)
+		{
+			debug!(
+				"link-proto: rekeying after {} bytes",
+				self.bytes_since_rekey
+			);
+			self.offer_rekey().await?;
 		}
+		Ok(())
+	}
+
+	async fn write_record<M: Serialize>(
+		&mut self,
+		packet: &M,
+		seq: u16,
+	) -> Result<(), Error<W::Error>> {
+		let mut writer = SliceWriter::new(&mut self.buffer[FRAME_HEADER_LEN..]);
+		packet.serialize(&mut writer).await.map_err(Error::widen)?;
+		let frame_len = writer.written();
+		self.buffer[..2].copy_from_slice(&(frame_len as u16).to_be_bytes());
+		self.buffer[2..FRAME_HEADER_LEN].copy_from_slice(&seq.to_be_bytes());
+		let len = FRAME_HEADER_LEN + frame_len;
+
+		let len_bytes = (len as u16).to_be_bytes();
+		let tag = self
+			.cipher
+			.encrypt_in_place_detached(
+				&record_nonce(self.counter),
+				&len_bytes[..],
+				&mut self.buffer[..len],
+			)
+			.map_err(|_| Error::ArrayTooLong {
+				len,
+				max: RECORD_MAX_LEN,
+			})?;
+		self.counter = self
+			.counter
+			.checked_add(1)
+			.expect("link-proto: record counter exhausted");
+
+		self.bytes_since_rekey += (len_bytes.len() + len + tag.len()) as u64;
+
+		self.sock.write(&len_bytes[..]).await?;
+		self.sock.write(&self.buffer[..len]).await?;
+		self.sock.write(&tag[..]).await?;
+		Ok(self.sock.flush().await?)
+	}
+
+	/// Mixes fresh randomness into the seed.
+	fn reseed<Rng: RngCore>(&mut self, rng: &mut Rng) {
+		let mut fresh = [0u8; 32];
+		rng.fill_bytes(&mut fresh);
+		Hkdf::<Sha256>::new(Some(&self.seed[..]), &fresh[..])
+			.expand(b"oro-link reseed", &mut self.seed[..])
+			.unwrap();
+	}
 
+	/// Draws the secret for a fresh ephemeral key from the seed, and
+	/// ratchets the seed forward so the secret can't be recovered later.
+	fn ephemeral(&mut self) -> [u8; 32] {
+		let seed = Hkdf::<Sha256>::from_prk(&self.seed[..]).unwrap();
+		let mut sk = [0u8; 32];
+		seed.expand(b"oro-link ephemeral", &mut sk[..]).unwrap();
+		seed.expand(b"oro-link seed", &mut self.seed[..]).unwrap();
+		curve25519_sk(sk)
+	}
+
+	async fn offer_rekey(&mut self) -> Result<(), Error<W::Error>> {
+		let sk = self.ephemeral();
+		self.write_record(
+			&Control::Rekey {
+				public_key: curve25519_pk(sk),
+			},
+			0,
+		)
+		.await?;
+		self.offered = Some(sk);
 		Ok(())
 	}
 
-	#[inline]
-	async fn flush(&mut self) -> Result<(), Self::Error> {
-		W::flush(&mut self.sock).await
+	/// Completes a rekey with the peer's ephemeral key, returning the
+	/// shared secret for the receive side.
+	async fn answer_rekey(&mut self, public_key: &[u8; 32]) -> Result<[u8; 32], Error<W::Error>> {
+		if is_low_order(public_key) {
+			error!("link-proto: peer offered a low-order rekey key");
+			return Err(Error::Unauthenticated);
+		}
+
+		// If we offered a key too (even if at the same time as the peer),
+		// the peer takes ours as its answer, just like we take its key.
+		let sk = match self.offered.take() {
+			Some(sk) => sk,
+			None => {
+				let sk = self.ephemeral();
+				self.write_record(
+					&Control::Rekey {
+						public_key: curve25519_pk(sk),
+					},
+					0,
+				)
+				.await?;
+				sk
+			}
+		};
+
+		let shared = curve25519(sk, *public_key);
+		if is_zero(&shared) {
+			error!("link-proto: rekey produced an all-zero shared secret");
+			return Err(Error::Unauthenticated);
+		}
+
+		// Sealed with the old key; the peer switches right after opening it.
+		self.write_record(&Control::Rekeyed, 0).await?;
+
+		self.key = next_key(&self.key, &shared);
+		self.cipher = ChaCha20Poly1305::new(&self.key.into());
+		self.counter = 0;
+		self.bytes_since_rekey = 0;
+		self.keyed_at = None;
+		debug!("link-proto: switched to new send key");
+		Ok(shared)
 	}
 }
 
-pub struct PacketReceiver<R: Read> {
-	sock: Mutex<BlockReceiver<R>>,
+/// Receives `P` packets, along with [`Control`] packets.
+pub struct PacketReceiver<R: Read, P: Direction> {
+	sock: Mutex<RecordReceiver<R>>,
+	replies: Replies,
+	_direction: PhantomData<fn() -> P>,
 }
 
-impl<R: Read> PacketReceiver<R> {
-	fn new(sock: R, tls: Aes256Dec) -> Self {
+impl<R: Read, P: Direction> PacketReceiver<R, P> {
+	fn new(sock: R, key: [u8; 32], replies: Replies) -> Self {
 		Self {
-			sock: Mutex::new(BlockReceiver {
+			sock: Mutex::new(RecordReceiver {
 				sock,
-				tls,
-				cursor: 16, // must start as >= the block size!
-				block: [0; 16],
+				cipher: ChaCha20Poly1305::new(&key.into()),
+				key,
+				next_shared: None,
+				counter: 0,
+				buffer: [0; RECORD_MAX_LEN],
 			}),
+			replies,
+			_direction: PhantomData,
 		}
 	}
 
-	pub async fn receive(&mut self) -> Result<Packet, Error<R::Error>> {
-		let mut sock = self.sock.lock().await;
-		let msg = Packet::deserialize(sock.deref_mut()).await?;
-		sock.cursor = sock.block.len(); // force a fresh read for the next packet.
-		Ok(msg)
+	/// Receives the next packet. Packets with IDs or enumeration values
+	/// this side doesn't know about (e.g. from a newer peer) are logged
+	/// and skipped.
+	#[inline]
+	pub async fn receive(&mut self) -> Result<Incoming<P>, Error<R::Error>> {
+		loop {
+			match self.receive_request().await? {
+				(_, Incoming::Unsupported { .. }) => {}
+				(_, packet) => return Ok(packet),
+			}
+		}
 	}
-}
 
-struct BlockReceiver<R: Read> {
-	sock: R,
-	tls: Aes256Dec,
-	cursor: usize,
-	block: [u8; 16],
+	/// Like [`PacketReceiver::receive`], but also returns the packet's
+	/// sequence ID if the peer expects an [`Control::Ack`] or
+	/// [`Control::Nack`] in reply.
+	///
+	/// On std targets, replies to this side's own requests are routed to
+	/// their `PendingReply` handles instead of being returned. The peer's
+	/// [`Control::Rekeyed`] is never returned; it's applied to the channel
+	/// directly. Requests that are skipped for being unknown are returned
+	/// as [`Incoming::Unsupported`].
+	pub async fn receive_request(&mut self) -> Result<(Option<u16>, Incoming<P>), Error<R::Error>> {
+		let mut sock = self.sock.lock().await;
+		loop {
+			let record = sock.receive().await?;
+			let (seq, packet) = read_frame(record).await.map_err(Error::widen)?;
+			match packet {
+				Ok(Incoming::Control(Control::Rekeyed)) => {
+					sock.switch_key()?;
+					continue;
+				}
+				Ok(Incoming::Control(packet)) => {
+					if let Some(packet) = self.replies.resolve(packet) {
+						return Ok((seq, Incoming::Control(packet)));
+					}
+					continue;
+				}
+				Ok(packet) => return Ok((seq, packet)),
+				Err(Error::InvalidMessageCode(_code)) => {
+					warning!(
+						"link-proto: skipping packet with unknown message code {}",
+						_code
+					);
+				}
+				// The frame is intact; the peer is just using a value we don't know about.
+				Err(Error::InvalidEnumeration) => {
+					warning!("link-proto: skipping packet with unknown enumeration value");
+				}
+				// Likewise, but further down in one of the packet's fields.
+				Err(Error::InField {
+					path: _path,
+					error: FieldError::InvalidEnumeration | FieldError::InvalidMessageCode(_),
+				}) => {
+					warning!(
+						"link-proto: skipping packet with unknown value in {}",
+						_path
+					);
+				}
+				Err(err) => return Err(err.widen()),
+			}
+
+			if let Some(seq) = seq {
+				return Ok((Some(seq), Incoming::Unsupported { seq }));
+			}
+		}
+	}
 }
 
-impl<R: Read> Read for BlockReceiver<R> {
-	type Error = R::Error;
+/// Decodes the sequence ID and packet from a `[length: u16][seq: u16][packet]`
+/// frame. The sequence ID is returned even if the packet fails to decode,
+/// so that the peer can be told.
+async fn read_frame<P: Direction>(
+	record: &[u8],
+) -> Result<(Option<u16>, Result<Incoming<P>, Error<Infallible>>), Error<Infallible>> {
+	let mut reader = SliceReader::new(record);
+	let frame_len = u16::deserialize(&mut reader).await? as usize;
+	let seq = u16::deserialize(&mut reader).await?;
+	if frame_len > reader.remaining() {
+		error!(
+			"link-proto: frame claims {} bytes but only {} were sent",
+			frame_len,
+			reader.remaining()
+		);
+		return Err(Error::Eof);
+	}
 
-	async fn read(&mut self, buf: &mut [u8]) -> Result<(), Error<R::Error>> {
-		let mut remaining = buf.len();
-		let mut off = 0;
+	let frame = &record[FRAME_HEADER_LEN..FRAME_HEADER_LEN + frame_len];
+	Ok(((seq != 0).then_some(seq), read_packet(frame).await))
+}
 
-		while remaining > 0 {
-			trace!(
-				"link-proto: read(): remaining={} off={} cursor={}",
-				remaining,
-				off,
-				self.cursor
-			);
+/// Decodes a frame's packet. Anything in the frame after the packet is
+/// ignored, which leaves room for newer peers to append fields to existing
+/// packets; likewise, optional fields at the end of a packet that an older
+/// peer left out are defaulted.
+///
+/// Control packets are told apart from `P`'s by their id, which no
+/// direction shares with them.
+async fn read_packet<P: Direction>(frame: &[u8]) -> Result<Incoming<P>, Error<Infallible>> {
+	let mut reader = SliceReader::new(frame);
+	if frame.first().is_some_and(|&id| Control::is_control_id(id)) {
+		Ok(Incoming::Control(Control::deserialize(&mut reader).await?))
+	} else {
+		let packet = P::deserialize(&mut reader).await?;
+		if let Some(_variant) = packet.deprecated_variant() {
+			warning!("link-proto: peer sent deprecated packet {}", _variant);
+		}
+		Ok(Incoming::Packet(packet))
+	}
+}
 
-			if self.cursor >= self.block.len() {
-				debug_assert_eq!(self.cursor, self.block.len());
+/// Opens records sealed by a [`RecordSender`]. Nothing is handed to
+/// the deserializer until the record's tag has been verified.
+struct RecordReceiver<R: Read> {
+	sock: R,
+	cipher: ChaCha20Poly1305,
+	key: [u8; 32],
+	/// The shared secret of a rekey we answered, until the peer switches.
+	next_shared: Option<[u8; 32]>,
+	counter: u64,
+	buffer: [u8; RECORD_MAX_LEN],
+}
 
-				trace!("link-proto: read(): reading 16 bytes from stream");
+impl<R: Read> RecordReceiver<R> {
+	async fn receive(&mut self) -> Result<&[u8], Error<R::Error>> {
+		let mut len_bytes = [0u8; 2];
+		self.sock.read(&mut len_bytes[..]).await?;
+		let len = u16::from_be_bytes(len_bytes) as usize;
+		trace!("link-proto: receiving record of {} bytes", len);
 
-				self.sock
-					.read(&mut self.block[..])
-					.await
-					.map_err(|#[allow(unused)] err| {
-						error!("link-proto: failed reading block from socket: {:?}", err);
-						Error::Eof
-					})?;
+		if len > RECORD_MAX_LEN {
+			error!("link-proto: peer sent an oversized record ({} bytes)", len);
+			return Err(Error::ArrayTooLong {
+				len,
+				max: RECORD_MAX_LEN,
+			});
+		}
 
-				self.cursor = 0;
+		let mut tag = Tag::default();
+		self.sock.read(&mut self.buffer[..len]).await?;
+		self.sock.read(&mut tag[..]).await?;
 
-				trace!("link-proto: decrypting bytes from stream");
+		self.cipher
+			.decrypt_in_place_detached(
+				&record_nonce(self.counter),
+				&len_bytes[..],
+				&mut self.buffer[..len],
+				&tag,
+			)
+			.map_err(|_| {
+				error!("link-proto: record failed authentication");
+				Error::Unauthenticated
+			})?;
+		self.counter = self
+			.counter
+			.checked_add(1)
+			.expect("link-proto: record counter exhausted");
 
-				self.tls.decrypt_block((&mut self.block[..]).into());
-			}
+		Ok(&self.buffer[..len])
+	}
 
-			let to_write = remaining.min(self.block.len() - self.cursor);
-			trace!("link-proto: to write: {}", to_write);
-			buf[off..off + to_write]
-				.copy_from_slice(&self.block[self.cursor..self.cursor + to_write]);
-			remaining -= to_write;
-			off += to_write;
-			self.cursor += to_write;
-		}
+	fn switch_key(&mut self) -> Result<(), Error<R::Error>> {
+		let Some(shared) = self.next_shared.take() else {
+			error!("link-proto: peer switched keys without a rekey exchange");
+			return Err(Error::Unauthenticated);
+		};
 
+		self.key = next_key(&self.key, &shared);
+		self.cipher = ChaCha20Poly1305::new(&self.key.into());
+		self.counter = 0;
+		debug!("link-proto: switched to new receive key");
 		Ok(())
 	}
 }
@@ -0,0 +1,151 @@
+//! Matches [`Control::Ack`]s and [`Control::Nack`]s up with the requests
+//! they answer. Only std targets (`async-std` or `tokio`) wait on replies;
+//! everywhere else, the replies are handed to the caller like any other
+//! packet.
+
+use crate::Control;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use crate::{macros::warning, NackReason};
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use core::time::Duration;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use futures::{
+	channel::oneshot::{channel as make_oneshot_channel, Receiver, Sender},
+	future::{self, Either},
+};
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use futures_timer::Delay;
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+type Outcome = Result<(), NackReason>;
+
+#[derive(Clone, Default)]
+pub(super) struct Replies {
+	#[cfg(any(feature = "async-std", feature = "tokio"))]
+	pending: Arc<Mutex<HashMap<u16, Sender<Outcome>>>>,
+}
+
+impl Replies {
+	/// Hands a reply to whoever is waiting on it. Returns the packet if
+	/// it should be passed on to the caller instead.
+	#[cfg(any(feature = "async-std", feature = "tokio"))]
+	pub(super) fn resolve(&self, packet: Control) -> Option<Control> {
+		let (seq, outcome) = match packet {
+			Control::Ack { seq } => (seq, Ok(())),
+			Control::Nack { seq, reason } => (seq, Err(reason)),
+			packet => return Some(packet),
+		};
+
+		match self.pending.lock().unwrap().remove(&seq) {
+			Some(waiter) => {
+				// The waiter may have given up already; that's fine.
+				let _ = waiter.send(outcome);
+			}
+			None => {
+				warning!(
+					"link-proto: dropping reply to unknown or expired request {}",
+					seq
+				);
+			}
+		}
+
+		None
+	}
+
+	#[cfg(not(any(feature = "async-std", feature = "tokio")))]
+	#[inline]
+	pub(super) fn resolve(&self, packet: Control) -> Option<Control> {
+		Some(packet)
+	}
+
+	#[cfg(any(feature = "async-std", feature = "tokio"))]
+	pub(super) fn register(&self, seq: u16) -> PendingReply {
+		let (sender, receiver) = make_oneshot_channel();
+		self.pending.lock().unwrap().insert(seq, sender);
+		PendingReply {
+			seq,
+			receiver,
+			replies: self.clone(),
+		}
+	}
+}
+
+/// A request that has been sent, but not yet replied to.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+pub struct PendingReply {
+	seq: u16,
+	receiver: Receiver<Outcome>,
+	replies: Replies,
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+impl PendingReply {
+	/// The sequence ID the request was sent with.
+	#[inline]
+	pub fn seq(&self) -> u16 {
+		self.seq
+	}
+
+	/// Waits for the peer to reply to the request. Replies are only
+	/// picked up while the [`super::PacketReceiver`] is being polled.
+	pub async fn wait(mut self, timeout: Duration) -> Result<(), ReplyError> {
+		match recv_timeout(&mut self.receiver, timeout).await {
+			Some(Ok(())) => Ok(()),
+			Some(Err(reason)) => Err(ReplyError::Rejected(reason)),
+			None => Err(ReplyError::TimedOut),
+		}
+	}
+}
+
+/// Waits for the outcome without relying on either runtime's timers, so
+/// that the channel works the same under both.
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+async fn recv_timeout(receiver: &mut Receiver<Outcome>, timeout: Duration) -> Option<Outcome> {
+	match future::select(receiver, Delay::new(timeout)).await {
+		Either::Left((outcome, _)) => outcome.ok(),
+		Either::Right(_) => None,
+	}
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+impl Drop for PendingReply {
+	fn drop(&mut self) {
+		self.replies.pending.lock().unwrap().remove(&self.seq);
+	}
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+pub enum ReplyError {
+	#[cfg_attr(feature = "thiserror", error("the peer rejected the request: {0}"))]
+	Rejected(NackReason),
+	#[cfg_attr(
+		feature = "thiserror",
+		error("the peer did not reply to the request in time")
+	)]
+	TimedOut,
+}
+
+#[cfg(any(feature = "async-std", feature = "tokio"))]
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+pub enum RequestError<W>
+where
+	W: link_protocol_binser::MaybeFormat,
+{
+	#[cfg_attr(
+		feature = "thiserror",
+		error("an error occurred writing to the peer: {0}")
+	)]
+	Write(W),
+	#[cfg_attr(feature = "thiserror", error("{0}"))]
+	Reply(ReplyError),
+}
@@ -0,0 +1,154 @@
+//! Dead-peer detection via [`Control::Ping`]/[`Control::Pong`].
+//!
+//! This only keeps the books; the caller owns the timer. Call
+//! [`Heartbeat::tick`] once per heartbeat interval and send the ping it
+//! returns, and hand every [`Control::Pong`] received to [`Heartbeat::pong`].
+//! Pings from the peer should be answered with a pong carrying the same
+//! nonce.
+
+use crate::Control;
+
+/// The peer hasn't answered enough pings in a row to still be
+/// considered alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+#[cfg_attr(
+	feature = "thiserror",
+	error("the peer missed {missed} heartbeats in a row")
+)]
+pub struct PeerDead {
+	pub missed: u8,
+}
+
+pub struct Heartbeat {
+	max_missed: u8,
+	missed: u8,
+	next_nonce: u32,
+	/// The ping that hasn't been answered yet, and when it was sent.
+	outstanding: Option<(u32, u64)>,
+	rtt_millis: Option<u64>,
+}
+
+impl Heartbeat {
+	/// Creates a new heartbeat that declares the peer dead after
+	/// `max_missed` consecutive unanswered pings.
+	pub fn new(max_missed: u8) -> Self {
+		Self {
+			max_missed: max_missed.max(1),
+			missed: 0,
+			next_nonce: 0,
+			outstanding: None,
+			rtt_millis: None,
+		}
+	}
+
+	/// Must be called once per heartbeat interval. Returns the ping to
+	/// send, or an error if the peer has missed too many of them.
+	pub fn tick(&mut self, millis: u64) -> Result<Control, PeerDead> {
+		if self.outstanding.is_some() {
+			self.missed = self.missed.saturating_add(1);
+			if self.missed >= self.max_missed {
+				return Err(PeerDead {
+					missed: self.missed,
+				});
+			}
+		}
+
+		let nonce = self.next_nonce;
+		self.next_nonce = self.next_nonce.wrapping_add(1);
+		self.outstanding = Some((nonce, millis));
+
+		Ok(Control::Ping { nonce })
+	}
+
+	/// Records a pong from the peer.
+	pub fn pong(&mut self, nonce: u32, millis: u64) {
+		match self.outstanding {
+			Some((outstanding, sent)) if outstanding == nonce => {
+				self.rtt_millis = Some(millis.saturating_sub(sent));
+				self.outstanding = None;
+				self.missed = 0;
+			}
+			// A late answer to an earlier ping still proves the peer is
+			// alive, but says nothing useful about the latency.
+			_ if self.next_nonce.wrapping_sub(nonce) <= u32::from(self.max_missed) => {
+				self.missed = 0;
+			}
+			_ => {}
+		}
+	}
+
+	/// The round trip time of the most recently answered ping.
+	#[inline]
+	pub fn rtt_millis(&self) -> Option<u64> {
+		self.rtt_millis
+	}
+
+	/// The number of pings in a row that have gone unanswered.
+	#[inline]
+	pub fn missed(&self) -> u8 {
+		self.missed
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn nonce(ping: Control) -> u32 {
+		match ping {
+			Control::Ping { nonce } => nonce,
+			other => panic!("expected a ping, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn declares_the_peer_dead_after_max_missed_pings() {
+		let mut heartbeat = Heartbeat::new(3);
+		heartbeat.tick(0).unwrap();
+		heartbeat.tick(1000).unwrap();
+		heartbeat.tick(2000).unwrap();
+		assert_eq!(heartbeat.missed(), 2);
+
+		assert_eq!(heartbeat.tick(3000), Err(PeerDead { missed: 3 }));
+	}
+
+	#[test]
+	fn pongs_reset_missed_and_measure_the_round_trip() {
+		let mut heartbeat = Heartbeat::new(3);
+		heartbeat.tick(0).unwrap();
+		let ping = nonce(heartbeat.tick(1000).unwrap());
+		assert_eq!(heartbeat.missed(), 1);
+		assert_eq!(heartbeat.rtt_millis(), None);
+
+		heartbeat.pong(ping, 1040);
+		assert_eq!(heartbeat.missed(), 0);
+		assert_eq!(heartbeat.rtt_millis(), Some(40));
+
+		// Answered, so the next tick doesn't count as a miss.
+		heartbeat.tick(2000).unwrap();
+		assert_eq!(heartbeat.missed(), 0);
+	}
+
+	#[test]
+	fn late_pongs_reset_missed_but_not_the_round_trip() {
+		let mut heartbeat = Heartbeat::new(3);
+		let first = nonce(heartbeat.tick(0).unwrap());
+		heartbeat.pong(first, 30);
+		let second = nonce(heartbeat.tick(1000).unwrap());
+		heartbeat.tick(2000).unwrap();
+		assert_eq!(heartbeat.missed(), 1);
+
+		heartbeat.pong(second, 2500);
+		assert_eq!(heartbeat.missed(), 0);
+		assert_eq!(heartbeat.rtt_millis(), Some(30));
+	}
+
+	#[test]
+	fn always_allows_at_least_one_missed_ping() {
+		let mut heartbeat = Heartbeat::new(0);
+		heartbeat.tick(0).unwrap();
+		assert_eq!(heartbeat.tick(1000), Err(PeerDead { missed: 1 }));
+	}
+}
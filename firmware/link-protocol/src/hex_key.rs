@@ -0,0 +1,50 @@
+//! Serializes 32 byte keys, UIDs and tokens as hex strings rather than
+//! arrays of numbers, for use with `#[serde(with = "hex_key")]`.
+
+use core::fmt;
+use serde::{de, Deserializer, Serializer};
+
+const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+pub fn serialize<S: Serializer>(key: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+	let mut hex = [0u8; 64];
+	for (i, byte) in key.iter().enumerate() {
+		hex[i * 2] = DIGITS[usize::from(byte >> 4)];
+		hex[i * 2 + 1] = DIGITS[usize::from(byte & 0xF)];
+	}
+
+	serializer.serialize_str(core::str::from_utf8(&hex).expect("hex is ASCII"))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+	deserializer.deserialize_str(KeyVisitor)
+}
+
+struct KeyVisitor;
+
+impl de::Visitor<'_> for KeyVisitor {
+	type Value = [u8; 32];
+
+	fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("64 hex digits")
+	}
+
+	fn visit_str<E: de::Error>(self, hex: &str) -> Result<Self::Value, E> {
+		let hex = hex.as_bytes();
+		if hex.len() != 64 {
+			return Err(E::invalid_length(hex.len(), &self));
+		}
+
+		let mut key = [0u8; 32];
+		for (byte, pair) in key.iter_mut().zip(hex.chunks_exact(2)) {
+			let digit = |c: u8| {
+				(c as char)
+					.to_digit(16)
+					.ok_or_else(|| E::invalid_value(de::Unexpected::Char(c as char), &self))
+			};
+			*byte = (digit(pair[0])? << 4 | digit(pair[1])?) as u8;
+		}
+
+		Ok(key)
+	}
+}
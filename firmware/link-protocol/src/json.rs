@@ -0,0 +1,11 @@
+//! Renders packets (or any other protocol type) as JSON, for logs,
+//! dashboards and tests.
+
+use serde::Serialize;
+
+/// Renders `value` as a single line of JSON, without a trailing newline.
+pub fn to_line<T: Serialize>(value: &T) -> String {
+	// Protocol types have no maps, so they can't have non-string keys,
+	// which is the only way serializing to a string can fail.
+	serde_json::to_string(value).expect("protocol types always serialize to JSON")
+}
@@ -0,0 +1,49 @@
+//! Hex-encoded curve25519 keys, as they're passed via the environment.
+use std::str::FromStr;
+
+#[derive(Debug)]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+pub enum Error {
+	#[cfg_attr(feature = "thiserror", error("key is not valid hex: {0}"))]
+	Hex(hex::FromHexError),
+	#[cfg_attr(feature = "thiserror", error("key must be exactly 32 bytes"))]
+	Length,
+}
+
+impl From<hex::FromHexError> for Error {
+	fn from(err: hex::FromHexError) -> Self {
+		Self::Hex(err)
+	}
+}
+
+/// A single 32 byte key.
+#[derive(Clone)]
+pub struct Key(pub [u8; 32]);
+
+impl FromStr for Key {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		hex::decode(s.trim())?
+			.try_into()
+			.map(Self)
+			.map_err(|_| Error::Length)
+	}
+}
+
+/// A comma separated list of keys.
+#[derive(Clone, Default)]
+pub struct KeyList(pub Vec<[u8; 32]>);
+
+impl FromStr for KeyList {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		s.split(',')
+			.map(str::trim)
+			.filter(|k| !k.is_empty())
+			.map(|k| k.parse::<Key>().map(|k| k.0))
+			.collect::<Result<_, _>>()
+			.map(Self)
+	}
+}
@@ -1,40 +1,201 @@
 //! This defines the protocol for communication between the Link and the Daemon.
-//! Messages are framed with a 16-bit unsigned length prefix.
+//! Messages are framed with a 16-bit unsigned length prefix, allowing peers to
+//! skip over packets they don't know about.
 //!
-//! TODO: Implement `derive(LinkEnum)` which performs serialization/deserialization
-//! TODO: based on discriminator, replace it for the trivial enums where `LinkMessage`
-//! TODO: is currently being (ab)used. Alternatively, allow `LinkMessage` to pick up
-//! TODO: on `#[repr(u8)]` etc. and enforce they are fieldless variants with unique
-//! TODO: discriminators and do that instead. I don't remember why I didn't add
-//! TODO: discriminators before, actually...
+//! Packets are split by the direction they travel in (e.g. [`LinkToDaemon`]),
+//! and channels are typed by direction so that nothing is sent the wrong way.
+//! The [`Control`] packets the channel itself relies on travel in every
+//! direction, alongside the others.
+//!
+//! Data-carrying enums derive `LinkMessage` and tag each variant with
+//! `#[proto(id = ...)]`; fieldless enums derive `LinkEnum` instead and are
+//! encoded by their `#[repr(u8)]` discriminant.
+//!
+//! To keep older peers working, fields added to an existing variant go at
+//! the end and are marked `#[proto(since = ...)]` (or `#[proto(default)]`);
+//! older peers leave them out, and they decode as their `Default`. Only
+//! the end of a frame tells that they were left out, so only packets can
+//! have them, never the messages nested in packets. Variants on their way
+//! out are marked `#[proto(deprecated)]`, and still decode with a warning.
+//! Once removed, their ids are listed in the enum's `#[proto(retired =
+//! [...])]` so they're never given to another variant.
 #![cfg_attr(not(feature = "std"), no_std)]
 #![allow(clippy::large_enum_variant, async_fn_in_trait)]
 
 #[cfg(feature = "channels")]
 pub mod channel;
+pub mod heartbeat;
+#[cfg(feature = "serde")]
+mod hex_key;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "keys")]
+pub mod keys;
 #[cfg(feature = "channels")]
 mod macros;
+#[cfg(feature = "pipe")]
+pub mod pipe;
+pub mod resume;
+#[cfg(feature = "transcript")]
+pub mod transcript;
 
 use heapless::{String, Vec};
-use link_protocol_binser::LinkMessage;
-pub use link_protocol_binser::{Deserialize, Error, Read, Serialize, Write};
+#[cfg(feature = "arbitrary")]
+pub use link_protocol_binser::arbitrary;
+#[cfg(feature = "tokio")]
+pub use link_protocol_binser::TokioIo;
+pub use link_protocol_binser::{
+	blocking, schema, Deserialize, Error, FieldError, FieldPath, MaxEncodedLen, Read, Schema,
+	Serialize, Write,
+};
+use link_protocol_binser::{LinkEnum, LinkMessage};
+
+/// The version of the protocol spoken by this crate. Must be bumped
+/// whenever packets are added or changed.
+pub const PROTOCOL_VERSION: u16 = 8;
+/// The oldest protocol version this crate can still talk to. Must be
+/// bumped whenever the wire format changes in a way older peers can't
+/// handle (i.e. anything that isn't skippable by the framing).
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+
+/// The wire schema of every message type in the protocol, for dumping
+/// and comparing between builds (see the `link-schema` tool).
+pub const SCHEMAS: &[schema::MessageSchema] = &[
+	Control::SCHEMA,
+	LinkToDaemon::SCHEMA,
+	DaemonToLink::SCHEMA,
+	ClientToDaemon::SCHEMA,
+	DaemonToClient::SCHEMA,
+	NackReason::SCHEMA,
+	FailSafePolicy::SCHEMA,
+	Capabilities::SCHEMA,
+	Scene::SCHEMA,
+	LogEntry::SCHEMA,
+	PowerState::SCHEMA,
+];
+
+/// The packets one direction of a connection carries, e.g. [`LinkToDaemon`].
+/// Channels are typed by the direction of each of their halves, so that
+/// a packet can't be sent the wrong way.
+///
+/// Packets keep the same id in every direction they're sent in, and no
+/// direction uses the ids of the [`Control`] packets, which the channel
+/// sends alongside them.
+pub trait Direction: Serialize + Deserialize + MaxEncodedLen + Schema {}
+
+impl Direction for LinkToDaemon {}
+impl Direction for DaemonToLink {}
+impl Direction for ClientToDaemon {}
+impl Direction for DaemonToClient {}
+
+const _: () = assert!(
+	!Control::SCHEMA.shares_variant_ids_with(&LinkToDaemon::SCHEMA)
+		&& !Control::SCHEMA.shares_variant_ids_with(&DaemonToLink::SCHEMA)
+		&& !Control::SCHEMA.shares_variant_ids_with(&ClientToDaemon::SCHEMA)
+		&& !Control::SCHEMA.shares_variant_ids_with(&DaemonToClient::SCHEMA),
+	"a direction uses the id of a control packet"
+);
+
+/// Packets the channel itself is built on, sent in every direction.
+#[derive(Debug, Clone, PartialEq, Eq, LinkMessage)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum Control {
+	/// Sent by both sides immediately after the channel is negotiated,
+	/// before anything else. The ID and layout of this packet must never
+	/// change, so that mismatched peers can always tell each other apart.
+	#[proto(id = 15)]
+	Hello {
+		/// The sender's [`PROTOCOL_VERSION`].
+		version: u16,
+		/// The features the sender supports.
+		capabilities: Capabilities,
+	},
+
+	/// The request with the given sequence ID was carried out.
+	#[proto(id = 16)]
+	Ack { seq: u16 },
+
+	/// The request with the given sequence ID was not carried out.
+	#[proto(id = 17)]
+	Nack { seq: u16, reason: NackReason },
 
-/// Packets sent between the client and daemon.
-#[derive(Debug, Clone, LinkMessage)]
+	/// Asks the peer to reply with a [`Control::Pong`] carrying the same
+	/// nonce. Only sent to peers with [`Capabilities::HEARTBEAT`].
+	#[proto(id = 18)]
+	Ping { nonce: u32 },
+
+	/// The reply to a [`Control::Ping`].
+	#[proto(id = 19)]
+	Pong { nonce: u32 },
+
+	/// Offers a fresh ephemeral key for switching both directions over to
+	/// new keys, or answers the peer's offer with one. Each direction's
+	/// new key is derived from its current one and the exchange between
+	/// both sides' ephemeral keys. Only sent to peers with
+	/// [`Capabilities::REKEY`].
+	#[proto(id = 23)]
+	Rekey {
+		#[cfg_attr(feature = "serde", serde(with = "hex_key"))]
+		public_key: [u8; 32],
+	},
+
+	/// Every record after this one is sealed with the sender's new key
+	/// (see [`Control::Rekey`]). Handled by the channel itself.
+	#[proto(id = 25)]
+	Rekeyed,
+}
+
+impl Control {
+	/// Whether a packet with the given id is a control packet.
+	pub fn is_control_id(id: u8) -> bool {
+		Self::SCHEMA.has_variant_id(id)
+	}
+}
+
+/// Packets sent by the link to the daemon.
+#[derive(Debug, Clone, PartialEq, Eq, LinkMessage)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
-pub enum Packet {
+pub enum LinkToDaemon {
 	/// The link is online and ready to receive work. Must be sent at least
 	/// once per connection.
 	#[proto(id = 1)]
 	LinkOnline {
 		/// The link's 256 bit UID (should be a Sha256
 		/// of the PAC/etc. UID chip readout).
+		#[cfg_attr(feature = "serde", serde(with = "hex_key"))]
 		uid: [u8; 32],
 		/// The link's firmware version
 		version: String<16>,
 	},
 
+	/// Output from the system under test's serial line.
+	#[proto(id = 13)]
+	Serial(Vec<u8, 256>),
+
+	/// Sent instead of [`LinkToDaemon::LinkOnline`] when the link is
+	/// reconnecting and wants to pick up the session it was in. Only sent
+	/// to daemons with [`Capabilities::SESSION_RESUME`].
+	#[proto(id = 20)]
+	LinkResume {
+		#[cfg_attr(feature = "serde", serde(with = "hex_key"))]
+		uid: [u8; 32],
+		version: String<16>,
+		/// The token from the last [`DaemonToLink::Session`] the link received.
+		#[cfg_attr(feature = "serde", serde(with = "hex_key"))]
+		token: [u8; 32],
+	},
+}
+
+/// Packets sent by the daemon to the link.
+#[derive(Debug, Clone, PartialEq, Eq, LinkMessage)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum DaemonToLink {
 	/// Resets the link, which is the equivalent of hitting the reset button.
 	#[proto(id = 2)]
 	ResetLink,
@@ -84,29 +245,262 @@ pub enum Packet {
 	#[proto(id = 12)]
 	BootfileSize { uefi: u64, bios: u64 },
 
-	/// A serial line transmission (either to or from the system)
+	/// Input for the system under test's serial line.
 	#[proto(id = 13)]
 	Serial(Vec<u8, 256>),
 
 	/// (DEBUG) An HID key for USB HID testing
 	#[proto(id = 14)]
 	DebugUsbKey(u8),
+
+	/// Sent in reply to [`LinkToDaemon::LinkOnline`] or
+	/// [`LinkToDaemon::LinkResume`] to links with
+	/// [`Capabilities::SESSION_RESUME`].
+	#[proto(id = 21)]
+	Session {
+		/// The token to present when resuming this session.
+		#[cfg_attr(feature = "serde", serde(with = "hex_key"))]
+		token: [u8; 32],
+		/// Whether the link's previous session was resumed. If `false`,
+		/// a new session was started and any state from the previous one
+		/// is gone.
+		resumed: bool,
+	},
+
+	/// Sets what the link does with the system under test while it
+	/// can't reach the daemon.
+	#[proto(id = 22)]
+	SetFailSafePolicy(FailSafePolicy),
 }
 
-#[derive(Debug, Clone, LinkMessage)]
+/// Packets sent by a test runner (e.g. the GitHub Actions runner) to the
+/// daemon, most of which the daemon passes on to the link.
+#[derive(Debug, Clone, PartialEq, Eq, LinkMessage)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
-pub enum Scene {
+pub enum ClientToDaemon {
+	/// See [`DaemonToLink::StartTestSession`].
+	#[proto(id = 6)]
+	StartTestSession {
+		total_tests: u32,
+		author: String<255>,
+		title: String<255>,
+		ref_id: String<255>,
+	},
+
+	/// See [`DaemonToLink::StartTest`].
+	#[proto(id = 7)]
+	StartTest { name: String<255> },
+
+	/// See [`DaemonToLink::PressPower`].
+	#[proto(id = 9)]
+	PressPower,
+
+	/// See [`DaemonToLink::PressReset`].
+	#[proto(id = 10)]
+	PressReset,
+
+	/// See [`DaemonToLink::BootfileSize`].
+	#[proto(id = 12)]
+	BootfileSize { uefi: u64, bios: u64 },
+
+	/// Input for the system under test's serial line.
+	#[proto(id = 13)]
+	Serial(Vec<u8, 256>),
+}
+
+/// Packets sent by the daemon to a test runner.
+#[derive(Debug, Clone, PartialEq, Eq, LinkMessage)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum DaemonToClient {
+	/// Output from the system under test's serial line.
+	#[proto(id = 13)]
+	Serial(Vec<u8, 256>),
+
+	/// The daemon gave up on the session (e.g. the system under test
+	/// hung, or the job ran out of time) and is tearing it down.
+	#[proto(id = 24)]
+	SessionFailed { reason: String<255> },
+}
+
+/// Why a request was [`Control::Nack`]ed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, LinkEnum)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+#[repr(u8)]
+pub enum NackReason {
+	/// The receiver doesn't know how to handle the packet, or a value in it.
+	Unsupported = 1,
+	/// The packet was understood, but can't be acted upon right now.
+	InvalidState = 2,
+	/// The receiver tried to carry out the request, but failed.
+	Failed = 3,
+}
+
+impl core::fmt::Display for NackReason {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match self {
+			NackReason::Unsupported => f.write_str("unsupported"),
+			NackReason::InvalidState => f.write_str("invalid state"),
+			NackReason::Failed => f.write_str("failed"),
+		}
+	}
+}
+
+/// What a link does with the system under test while the daemon
+/// is unreachable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, LinkMessage)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum FailSafePolicy {
+	/// Keeps the system running for the given number of minutes in
+	/// the hopes the daemon comes back, then powers it off.
 	#[proto(id = 1)]
-	Logo,
+	KeepRunning { minutes: u16 },
+	/// Powers the system off immediately.
 	#[proto(id = 2)]
-	Test,
-	#[proto(id = 3)]
-	Log,
+	PowerOff,
+}
+
+impl Default for FailSafePolicy {
+	fn default() -> Self {
+		Self::KeepRunning { minutes: 5 }
+	}
+}
+
+/// A set of optional features a peer supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, LinkMessage)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+	/// Serial line passthrough to the system under test.
+	pub const SERIAL: Self = Self(1 << 0);
+	/// Power and reset control of the system under test.
+	pub const POWER_CONTROL: Self = Self(1 << 1);
+	/// Serving boot files to the system under test over PXE.
+	pub const PXE: Self = Self(1 << 2);
+	/// Acting as a USB HID device to the system under test.
+	pub const USB_HID: Self = Self(1 << 3);
+	/// Capturing the system under test's network traffic.
+	pub const PACKET_CAPTURE: Self = Self(1 << 4);
+	/// Showing scenes and logs on the link's monitor.
+	pub const MONITOR: Self = Self(1 << 5);
+	/// Answering [`Control::Ping`]s.
+	pub const HEARTBEAT: Self = Self(1 << 6);
+	/// Resuming sessions after a reconnect (see [`LinkToDaemon::LinkResume`]).
+	pub const SESSION_RESUME: Self = Self(1 << 7);
+	/// Switching keys mid-session (see [`Control::Rekey`]).
+	pub const REKEY: Self = Self(1 << 8);
+
+	const NAMES: [(Self, &'static str); 9] = [
+		(Self::SERIAL, "serial"),
+		(Self::POWER_CONTROL, "power-control"),
+		(Self::PXE, "pxe"),
+		(Self::USB_HID, "usb-hid"),
+		(Self::PACKET_CAPTURE, "packet-capture"),
+		(Self::MONITOR, "monitor"),
+		(Self::HEARTBEAT, "heartbeat"),
+		(Self::SESSION_RESUME, "session-resume"),
+		(Self::REKEY, "rekey"),
+	];
+
+	/// No capabilities at all.
+	#[inline]
+	pub const fn empty() -> Self {
+		Self(0)
+	}
+
+	/// Whether or not every capability in `other` is also in `self`.
+	#[inline]
+	pub const fn contains(self, other: Self) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// The capabilities that are in either `self` or `other`.
+	#[inline]
+	pub const fn union(self, other: Self) -> Self {
+		Self(self.0 | other.0)
+	}
+
+	/// The capabilities that are in both `self` and `other`.
+	#[inline]
+	pub const fn intersection(self, other: Self) -> Self {
+		Self(self.0 & other.0)
+	}
+
+	/// The capabilities that are in `self` but not in `other`.
+	#[inline]
+	pub const fn difference(self, other: Self) -> Self {
+		Self(self.0 & !other.0)
+	}
+
+	#[inline]
+	pub const fn is_empty(self) -> bool {
+		self.0 == 0
+	}
+}
+
+impl core::ops::BitOr for Capabilities {
+	type Output = Self;
+
+	#[inline]
+	fn bitor(self, rhs: Self) -> Self {
+		self.union(rhs)
+	}
 }
 
-#[derive(Debug, Clone, LinkMessage)]
+impl core::fmt::Display for Capabilities {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		if self.is_empty() {
+			return f.write_str("(none)");
+		}
+
+		let mut first = true;
+		let mut remaining = *self;
+		for (cap, name) in Self::NAMES {
+			if self.contains(cap) {
+				if !first {
+					f.write_str(", ")?;
+				}
+				f.write_str(name)?;
+				first = false;
+				remaining = remaining.difference(cap);
+			}
+		}
+
+		if !remaining.is_empty() {
+			if !first {
+				f.write_str(", ")?;
+			}
+			write!(f, "unknown({:#x})", remaining.0)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, LinkEnum)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+#[repr(u8)]
+pub enum Scene {
+	Logo = 1,
+	Test = 2,
+	Log = 3,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, LinkMessage)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum LogEntry {
 	#[proto(id = 1)]
@@ -117,14 +511,13 @@ pub enum LogEntry {
 	Error(String<255>),
 }
 
-#[derive(Debug, Clone, LinkMessage)]
+#[derive(Debug, Clone, PartialEq, Eq, LinkEnum)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
+#[repr(u8)]
 pub enum PowerState {
-	#[proto(id = 1)]
-	Off,
-	#[proto(id = 2)]
-	Standby,
-	#[proto(id = 3)]
-	On,
+	Off = 1,
+	Standby = 2,
+	On = 3,
 }
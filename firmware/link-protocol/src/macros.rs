@@ -9,6 +9,17 @@ macro_rules! error {
 
 pub(crate) use error;
 
+macro_rules! warning {
+	($($e:expr),*) => {
+		#[cfg(feature = "log")]
+		::log::warn!($($e),*);
+		#[cfg(feature = "defmt")]
+		::defmt::warn!($($e),*);
+	}
+}
+
+pub(crate) use warning;
+
 macro_rules! debug {
 	($($e:expr),*) => {
 		#[cfg(feature = "log")]
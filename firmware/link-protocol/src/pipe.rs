@@ -0,0 +1,218 @@
+//! An in-memory duplex pipe, for exercising channels without a socket
+//! or a board.
+//!
+//! Each direction is a [`PipeWriter`]/[`PipeReader`] pair. Writers can be
+//! given [`Faults`] to inject into everything they write, which is how
+//! delays, short reads, corruption and disconnects are simulated.
+
+use crate::{Error, Read, Write};
+use core::{future::poll_fn, task::Poll, time::Duration};
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Mutex},
+	task::Waker,
+};
+
+/// Faults a [`PipeWriter`] injects into what it writes.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+	/// Waits this long before each write.
+	pub delay: Option<Duration>,
+	/// Splits writes into chunks of at most this many bytes, yielding
+	/// in between, so that the reader only ever sees part of them at once.
+	pub max_chunk: Option<usize>,
+	/// Flips the lowest bit of the byte at this offset into the stream.
+	pub corrupt_at: Option<usize>,
+	/// Disconnects the pipe after this many bytes have been written.
+	pub disconnect_after: Option<usize>,
+}
+
+/// The pipe was disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl core::fmt::Display for Disconnected {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		f.write_str("the pipe was disconnected")
+	}
+}
+
+impl std::error::Error for Disconnected {}
+
+#[derive(Default)]
+struct Buffer {
+	data: VecDeque<u8>,
+	closed: bool,
+	reader: Option<Waker>,
+}
+
+impl Buffer {
+	fn close(&mut self) {
+		self.closed = true;
+		if let Some(waker) = self.reader.take() {
+			waker.wake();
+		}
+	}
+}
+
+/// The writing end of a pipe.
+pub struct PipeWriter {
+	buffer: Arc<Mutex<Buffer>>,
+	faults: Faults,
+	written: usize,
+}
+
+/// The reading end of a pipe.
+pub struct PipeReader {
+	buffer: Arc<Mutex<Buffer>>,
+}
+
+/// One side of a [`duplex`] pipe.
+pub struct PipeEnd {
+	pub writer: PipeWriter,
+	pub reader: PipeReader,
+}
+
+/// Creates a one-way pipe.
+pub fn pipe() -> (PipeWriter, PipeReader) {
+	let buffer = Arc::new(Mutex::new(Buffer::default()));
+	(
+		PipeWriter {
+			buffer: buffer.clone(),
+			faults: Faults::default(),
+			written: 0,
+		},
+		PipeReader { buffer },
+	)
+}
+
+/// Creates a pair of connected pipe ends; whatever one end writes,
+/// the other reads.
+pub fn duplex() -> (PipeEnd, PipeEnd) {
+	let (a_writer, b_reader) = pipe();
+	let (b_writer, a_reader) = pipe();
+	(
+		PipeEnd {
+			writer: a_writer,
+			reader: a_reader,
+		},
+		PipeEnd {
+			writer: b_writer,
+			reader: b_reader,
+		},
+	)
+}
+
+impl PipeWriter {
+	/// Injects the given faults into everything written from here on.
+	pub fn with_faults(mut self, faults: Faults) -> Self {
+		self.faults = faults;
+		self
+	}
+
+	/// Disconnects the pipe. The reader still gets everything written
+	/// before this, after which it fails with [`Error::Eof`].
+	pub fn disconnect(&self) {
+		self.buffer.lock().unwrap().close();
+	}
+
+	fn push(&mut self, buf: &[u8]) -> Result<(), Error<Disconnected>> {
+		let mut buffer = self.buffer.lock().unwrap();
+		if buffer.closed {
+			return Err(Error::Io(Disconnected));
+		}
+
+		for &byte in buf {
+			if self.faults.disconnect_after == Some(self.written) {
+				buffer.close();
+				return Err(Error::Io(Disconnected));
+			}
+
+			let byte = match self.faults.corrupt_at {
+				Some(offset) if offset == self.written => byte ^ 1,
+				_ => byte,
+			};
+			buffer.data.push_back(byte);
+			self.written += 1;
+		}
+
+		if let Some(waker) = buffer.reader.take() {
+			waker.wake();
+		}
+
+		Ok(())
+	}
+}
+
+impl Drop for PipeWriter {
+	fn drop(&mut self) {
+		self.disconnect();
+	}
+}
+
+impl Drop for PipeReader {
+	fn drop(&mut self) {
+		self.buffer.lock().unwrap().close();
+	}
+}
+
+impl Write for PipeWriter {
+	type Error = Disconnected;
+
+	async fn write(&mut self, buf: &[u8]) -> Result<(), Error<Self::Error>> {
+		if let Some(delay) = self.faults.delay {
+			async_std::task::sleep(delay).await;
+		}
+
+		match self.faults.max_chunk {
+			Some(max_chunk) => {
+				for chunk in buf.chunks(max_chunk.max(1)) {
+					self.push(chunk)?;
+					async_std::task::yield_now().await;
+				}
+				Ok(())
+			}
+			None => self.push(buf),
+		}
+	}
+
+	async fn flush(&mut self) -> Result<(), Self::Error> {
+		if self.buffer.lock().unwrap().closed {
+			Err(Disconnected)
+		} else {
+			Ok(())
+		}
+	}
+}
+
+impl Read for PipeReader {
+	type Error = Disconnected;
+
+	async fn read(&mut self, buf: &mut [u8]) -> Result<(), Error<Self::Error>> {
+		let mut filled = 0;
+		while filled < buf.len() {
+			filled += poll_fn(|cx| {
+				let mut buffer = self.buffer.lock().unwrap();
+				if buffer.data.is_empty() {
+					if buffer.closed {
+						return Poll::Ready(Err(Error::Eof));
+					}
+					buffer.reader = Some(cx.waker().clone());
+					return Poll::Pending;
+				}
+
+				let len = (buf.len() - filled).min(buffer.data.len());
+				for (dst, src) in buf[filled..filled + len]
+					.iter_mut()
+					.zip(buffer.data.drain(..len))
+				{
+					*dst = src;
+				}
+				Poll::Ready(Ok(len))
+			})
+			.await?;
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,141 @@
+//! Resuming sessions across reconnects (see [`Capabilities::SESSION_RESUME`]),
+//! from the link's side.
+//!
+//! This only keeps the books; the caller owns the connection. Announce the
+//! link with the packet [`Resume::online`] returns, then hand the
+//! [`DaemonToLink::Session`] the daemon replies with to [`Resume::session`],
+//! or call [`Resume::no_session`] for daemons without
+//! [`Capabilities::SESSION_RESUME`], which don't send one. Both say what's
+//! become of the system under test.
+//!
+//! [`Capabilities::SESSION_RESUME`]: crate::Capabilities::SESSION_RESUME
+//! [`DaemonToLink::Session`]: crate::DaemonToLink::Session
+
+use crate::LinkToDaemon;
+use heapless::String;
+
+/// What a (re)connection to the daemon means for the system under test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Reconnected {
+	/// The daemon picked up the previous session, which carries on.
+	Resumed,
+	/// The daemon started a new session. If `power_off`, the system is
+	/// still in use by an earlier session, and has to be powered off.
+	NewSession { power_off: bool },
+}
+
+#[derive(Default)]
+pub struct Resume {
+	/// The token of the session the daemon last gave us.
+	token: Option<[u8; 32]>,
+	/// Whether there ever was a session, in which case the system is in use.
+	had_session: bool,
+}
+
+impl Resume {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The packet announcing the link to the daemon, which resumes the
+	/// last session if the daemon supports it.
+	pub fn online(
+		&self,
+		uid: [u8; 32],
+		version: String<16>,
+		supports_resume: bool,
+	) -> LinkToDaemon {
+		match self.token.filter(|_| supports_resume) {
+			Some(token) => LinkToDaemon::LinkResume {
+				uid,
+				version,
+				token,
+			},
+			None => LinkToDaemon::LinkOnline { uid, version },
+		}
+	}
+
+	/// Records the session the daemon replied with.
+	pub fn session(&mut self, token: [u8; 32], resumed: bool) -> Reconnected {
+		self.token = Some(token);
+		self.connected(resumed)
+	}
+
+	/// Records that the daemon doesn't support sessions, and so started
+	/// a new one.
+	pub fn no_session(&mut self) -> Reconnected {
+		self.token = None;
+		self.connected(false)
+	}
+
+	fn connected(&mut self, resumed: bool) -> Reconnected {
+		let had_session = core::mem::replace(&mut self.had_session, true);
+		if resumed {
+			Reconnected::Resumed
+		} else {
+			Reconnected::NewSession {
+				power_off: had_session,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const UID: [u8; 32] = [7; 32];
+
+	fn online(resume: &Resume, supports_resume: bool) -> LinkToDaemon {
+		resume.online(UID, "1.0.0".try_into().unwrap(), supports_resume)
+	}
+
+	fn token(online: LinkToDaemon) -> Option<[u8; 32]> {
+		match online {
+			LinkToDaemon::LinkOnline { .. } => None,
+			LinkToDaemon::LinkResume { token, .. } => Some(token),
+			other => panic!("expected the link to come online, got {other:?}"),
+		}
+	}
+
+	#[test]
+	fn presents_the_last_session_token() {
+		let mut resume = Resume::new();
+		assert_eq!(token(online(&resume, true)), None);
+
+		assert_eq!(
+			resume.session([1; 32], false),
+			Reconnected::NewSession { power_off: false }
+		);
+		assert_eq!(token(online(&resume, true)), Some([1; 32]));
+
+		assert_eq!(resume.session([1; 32], true), Reconnected::Resumed);
+		assert_eq!(token(online(&resume, true)), Some([1; 32]));
+	}
+
+	#[test]
+	fn powers_off_when_a_new_session_replaces_the_last_one() {
+		let mut resume = Resume::new();
+		resume.session([1; 32], false);
+
+		assert_eq!(
+			resume.session([2; 32], false),
+			Reconnected::NewSession { power_off: true }
+		);
+		assert_eq!(token(online(&resume, true)), Some([2; 32]));
+	}
+
+	#[test]
+	fn forgets_the_token_for_daemons_without_sessions() {
+		let mut resume = Resume::new();
+		resume.session([1; 32], false);
+		assert_eq!(token(online(&resume, false)), None);
+
+		assert_eq!(
+			resume.no_session(),
+			Reconnected::NewSession { power_off: true }
+		);
+		assert_eq!(token(online(&resume, true)), None);
+	}
+}
@@ -0,0 +1,169 @@
+//! Session transcripts: every packet exchanged during a session, one
+//! JSON object per line, so that the session can be replayed later.
+//!
+//! The first line is a [`Header`] describing the session; every line
+//! after it is an [`Entry`]. Entries carry the packet as it was encoded
+//! on the wire (before encryption), which is what gets replayed, along
+//! with the decoded packet for people to read. Whatever else the session
+//! reacted to (see [`Event`]) is recorded the same way.
+
+use crate::{blocking, Capabilities, Control, DaemonToLink, Error, NackReason};
+use core::convert::Infallible;
+use link_protocol_binser::LinkMessage;
+use std::io::{self, BufRead, Write};
+
+/// Which way a packet travelled, and so which enum its bytes decode as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Route {
+	LinkToDaemon,
+	DaemonToLink,
+	ClientToDaemon,
+	DaemonToClient,
+	/// Not a packet, but an [`Event`].
+	Session,
+}
+
+/// Something that happened to a session other than a packet arriving,
+/// which the session has to react to all the same.
+#[derive(Debug, Clone, PartialEq, Eq, LinkMessage, serde::Serialize, serde::Deserialize)]
+pub enum Event {
+	/// The link didn't carry out `packet`: it rejected it for `reason`,
+	/// or without one, didn't reply in time.
+	#[proto(id = 1)]
+	LinkFailed {
+		packet: DaemonToLink,
+		reason: Option<NackReason>,
+	},
+
+	/// The runner disconnected.
+	#[proto(id = 2)]
+	ClientEnded,
+}
+
+/// Describes the session a transcript was recorded from.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Header {
+	/// The link's UID, as upper-case hex.
+	pub link_id: String,
+	/// The link's firmware version.
+	pub version: String,
+	/// The capabilities negotiated with the link.
+	pub capabilities: Capabilities,
+	/// When the session started, in milliseconds since the Unix epoch.
+	pub started: u64,
+}
+
+/// A single packet in a transcript.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+	/// When the packet was sent or received, in milliseconds since
+	/// the session started.
+	pub millis: u64,
+	pub route: Route,
+	/// The encoded packet.
+	#[serde(with = "hex::serde")]
+	pub bytes: Vec<u8>,
+}
+
+impl Entry {
+	/// Whether this is a [`Control`] packet, rather than one of the
+	/// route's own packets.
+	pub fn is_control(&self) -> bool {
+		self.route != Route::Session
+			&& self
+				.bytes
+				.first()
+				.is_some_and(|&id| Control::is_control_id(id))
+	}
+
+	/// Decodes the packet; `P` is either [`Control`] or the packet
+	/// type of the entry's route ([`Event`] for [`Route::Session`]).
+	pub fn decode<P: crate::Deserialize>(&self) -> Result<P, Error<Infallible>> {
+		blocking::decode_from_slice(&self.bytes).map(|(packet, _)| packet)
+	}
+}
+
+/// How entries are written out; `packet` is skipped when reading.
+#[derive(serde::Serialize)]
+struct Line<'a, P> {
+	#[serde(flatten)]
+	entry: &'a Entry,
+	packet: &'a P,
+}
+
+/// Writes a transcript, flushing after every entry so that nothing is
+/// lost if the session ends abruptly.
+pub struct Writer<W: Write> {
+	out: W,
+}
+
+impl<W: Write> Writer<W> {
+	/// Starts a transcript by writing its header.
+	pub fn new(mut out: W, header: &Header) -> io::Result<Self> {
+		serde_json::to_writer(&mut out, header)?;
+		writeln!(out)?;
+		out.flush()?;
+		Ok(Self { out })
+	}
+
+	/// Appends a packet to the transcript.
+	pub fn record<P>(&mut self, millis: u64, route: Route, packet: &P) -> io::Result<()>
+	where
+		P: crate::Serialize + serde::Serialize,
+	{
+		let entry = Entry {
+			millis,
+			route,
+			bytes: blocking::encode_to_vec(packet).expect("protocol packets always encode"),
+		};
+
+		serde_json::to_writer(
+			&mut self.out,
+			&Line {
+				entry: &entry,
+				packet,
+			},
+		)?;
+		writeln!(self.out)?;
+		self.out.flush()
+	}
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "thiserror", derive(::thiserror::Error))]
+pub enum ReadError {
+	#[cfg_attr(feature = "thiserror", error("failed to read transcript: {0}"))]
+	Io(io::Error),
+	#[cfg_attr(feature = "thiserror", error("transcript has no header"))]
+	MissingHeader,
+	#[cfg_attr(
+		feature = "thiserror",
+		error("malformed transcript entry on line {line}: {error}")
+	)]
+	Malformed {
+		line: usize,
+		error: serde_json::Error,
+	},
+}
+
+/// Reads a transcript's header, and returns it along with its entries.
+pub fn read<R: BufRead>(
+	input: R,
+) -> Result<(Header, impl Iterator<Item = Result<Entry, ReadError>>), ReadError> {
+	let mut lines = input
+		.lines()
+		.enumerate()
+		.filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()));
+
+	let header = parse_line(lines.next().ok_or(ReadError::MissingHeader)?)?;
+	Ok((header, lines.map(parse_line)))
+}
+
+fn parse_line<T: serde::de::DeserializeOwned>(
+	(index, line): (usize, io::Result<String>),
+) -> Result<T, ReadError> {
+	serde_json::from_str(&line.map_err(ReadError::Io)?).map_err(|error| ReadError::Malformed {
+		line: index + 1,
+		error,
+	})
+}
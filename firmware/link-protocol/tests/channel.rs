@@ -0,0 +1,707 @@
+use core::time::Duration;
+use link_protocol::{
+	channel::{
+		exchange_hello, negotiate, Identity, Incoming, NegotiationError, PacketReceiver,
+		PacketSender, RekeyPolicy, ReplyError, Side,
+	},
+	pipe::{duplex, Disconnected, Faults, PipeEnd, PipeReader, PipeWriter},
+	Capabilities, ClientToDaemon, Control, DaemonToClient, DaemonToLink, Direction, Error,
+	FailSafePolicy, LinkToDaemon, LogEntry, NackReason, PowerState, Read, Scene, Write,
+};
+use link_protocol_binser::{LinkEnum, LinkMessage};
+use rand_core::OsRng;
+
+type Channel<S, Rx> = (PacketSender<PipeWriter, S>, PacketReceiver<PipeReader, Rx>);
+type NegotiationResult<S, Rx> =
+	Result<Channel<S, Rx>, NegotiationError<Error<Disconnected>, Error<Disconnected>>>;
+
+fn client_identity() -> Identity {
+	Identity::from_secret([1; 32])
+}
+
+fn server_identity() -> Identity {
+	Identity::from_secret([2; 32])
+}
+
+/// Connects a client sending `C` packets to a server sending `S` packets.
+async fn connect<C: Direction, S: Direction>(
+	client: PipeEnd,
+	server: PipeEnd,
+) -> (NegotiationResult<C, S>, NegotiationResult<S, C>) {
+	connect_as(client, server).await
+}
+
+/// Like [`connect`], but the server receives the client's packets as
+/// `Rx`, e.g. as an older version of them.
+async fn connect_as<C: Direction, S: Direction, Rx: Direction>(
+	client: PipeEnd,
+	server: PipeEnd,
+) -> (NegotiationResult<C, S>, NegotiationResult<S, Rx>) {
+	let (client_identity, server_identity) = (client_identity(), server_identity());
+	let trusted_server = server_identity.public_key();
+	let trusted_clients = [client_identity.public_key()];
+	let (mut client_rng, mut server_rng) = (OsRng, OsRng);
+
+	futures::join!(
+		negotiate(
+			client.writer,
+			client.reader,
+			&mut client_rng,
+			Side::Client,
+			&client_identity,
+			&trusted_server,
+		),
+		negotiate(
+			server.writer,
+			server.reader,
+			&mut server_rng,
+			Side::Server,
+			&server_identity,
+			&trusted_clients[..],
+		),
+	)
+}
+
+/// Connects a link (as the client) to a daemon (as the server).
+async fn connect_link(
+	client: PipeEnd,
+	server: PipeEnd,
+) -> (
+	NegotiationResult<LinkToDaemon, DaemonToLink>,
+	NegotiationResult<DaemonToLink, LinkToDaemon>,
+) {
+	connect(client, server).await
+}
+
+/// What a newer daemon might send: a packet the link has never heard of,
+/// and a power state it doesn't know, next to packets it does know.
+#[derive(Debug, Clone, PartialEq, Eq, LinkMessage)]
+enum NewerDaemonToLink {
+	#[proto(id = 8)]
+	SetPowerState(NewerPowerState),
+	#[proto(id = 99)]
+	Hibernate { minutes: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, LinkEnum)]
+#[repr(u8)]
+enum NewerPowerState {
+	On = 3,
+	Hibernating = 9,
+}
+
+impl Direction for NewerDaemonToLink {}
+
+/// Connects a newer daemon (as the client) to a link (as the server).
+async fn connect_newer_daemon(
+	client: PipeEnd,
+	server: PipeEnd,
+) -> (
+	NegotiationResult<NewerDaemonToLink, LinkToDaemon>,
+	NegotiationResult<LinkToDaemon, DaemonToLink>,
+) {
+	connect_as(client, server).await
+}
+
+/// One of every packet a link sends.
+fn every_link_packet() -> Vec<LinkToDaemon> {
+	vec![
+		LinkToDaemon::LinkOnline {
+			uid: [7; 32],
+			version: "1.2.3".try_into().unwrap(),
+		},
+		LinkToDaemon::Serial((0..=255).collect()),
+		LinkToDaemon::LinkResume {
+			uid: [1; 32],
+			version: "1.0.0".try_into().unwrap(),
+			token: [2; 32],
+		},
+	]
+}
+
+/// One of every packet a daemon sends to a link.
+fn every_daemon_packet() -> Vec<DaemonToLink> {
+	vec![
+		DaemonToLink::ResetLink,
+		DaemonToLink::SetScene(Scene::Logo),
+		DaemonToLink::SetScene(Scene::Test),
+		DaemonToLink::SetScene(Scene::Log),
+		DaemonToLink::Log(LogEntry::Info("info".try_into().unwrap())),
+		DaemonToLink::Log(LogEntry::Warn("warn".try_into().unwrap())),
+		DaemonToLink::Log(LogEntry::Error("error".try_into().unwrap())),
+		DaemonToLink::SetMonitorStandby(true),
+		DaemonToLink::StartTestSession {
+			total_tests: 42,
+			author: "a".repeat(255).as_str().try_into().unwrap(),
+			title: "t".repeat(255).as_str().try_into().unwrap(),
+			ref_id: "r".repeat(255).as_str().try_into().unwrap(),
+		},
+		DaemonToLink::StartTest {
+			name: "test".try_into().unwrap(),
+		},
+		DaemonToLink::SetPowerState(PowerState::Off),
+		DaemonToLink::SetPowerState(PowerState::Standby),
+		DaemonToLink::SetPowerState(PowerState::On),
+		DaemonToLink::PressPower,
+		DaemonToLink::PressReset,
+		DaemonToLink::BootfileSize {
+			uefi: u64::MAX,
+			bios: 1,
+		},
+		DaemonToLink::Serial((0..=255).collect()),
+		DaemonToLink::DebugUsbKey(4),
+		DaemonToLink::Session {
+			token: [3; 32],
+			resumed: true,
+		},
+		DaemonToLink::SetFailSafePolicy(FailSafePolicy::KeepRunning { minutes: 10 }),
+		DaemonToLink::SetFailSafePolicy(FailSafePolicy::PowerOff),
+	]
+}
+
+/// One of every control packet the channel hands to the caller. Replies
+/// and [`Control::Rekeyed`] are consumed by the channel itself, and are
+/// tested separately.
+fn every_control_packet() -> Vec<Control> {
+	vec![
+		Control::Hello {
+			version: 1,
+			capabilities: Capabilities::SERIAL | Capabilities::PXE,
+		},
+		Control::Ping { nonce: 1 },
+		Control::Pong { nonce: u32::MAX },
+		Control::Rekey {
+			public_key: [9; 32],
+		},
+	]
+}
+
+/// Sends every packet from `sender` to `receiver`, followed by every
+/// control packet.
+async fn assert_roundtrips<S: Direction + Clone + PartialEq + core::fmt::Debug>(
+	packets: Vec<S>,
+	sender: &PacketSender<PipeWriter, S>,
+	receiver: &mut PacketReceiver<PipeReader, S>,
+) {
+	for packet in packets {
+		sender.send(packet.clone()).await.unwrap();
+		assert_eq!(receiver.receive().await.unwrap(), Incoming::Packet(packet));
+	}
+
+	for packet in every_control_packet() {
+		sender.send_control(packet.clone()).await.unwrap();
+		assert_eq!(receiver.receive().await.unwrap(), Incoming::Control(packet));
+	}
+}
+
+fn serial(data: &[u8]) -> LinkToDaemon {
+	LinkToDaemon::Serial(data.iter().copied().collect())
+}
+
+fn daemon_serial(data: &[u8]) -> DaemonToLink {
+	DaemonToLink::Serial(data.iter().copied().collect())
+}
+
+/// Receives the peer's [`Control::Rekey`], which must be next.
+async fn receive_rekey<P: Direction + core::fmt::Debug>(
+	receiver: &mut PacketReceiver<PipeReader, P>,
+) -> [u8; 32] {
+	match receiver.receive().await {
+		Ok(Incoming::Control(Control::Rekey { public_key })) => public_key,
+		other => panic!("expected a rekey, got {other:?}"),
+	}
+}
+
+#[async_std::test]
+async fn negotiates_for_both_sides() {
+	let (client, server) = duplex();
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, mut client_receiver) = client.unwrap();
+	let (server_sender, mut server_receiver) = server.unwrap();
+
+	let (client_caps, server_caps) = futures::join!(
+		exchange_hello(
+			&client_sender,
+			&mut client_receiver,
+			Capabilities::SERIAL | Capabilities::MONITOR,
+			Capabilities::empty(),
+		),
+		exchange_hello(
+			&server_sender,
+			&mut server_receiver,
+			Capabilities::SERIAL | Capabilities::PXE,
+			Capabilities::SERIAL,
+		),
+	);
+
+	assert_eq!(client_caps.unwrap(), Capabilities::SERIAL);
+	assert_eq!(server_caps.unwrap(), Capabilities::SERIAL);
+}
+
+#[async_std::test]
+async fn rejects_untrusted_identities() {
+	let (client, server) = duplex();
+	let client_identity = client_identity();
+	let server_identity = server_identity();
+	let trusted_server = server_identity.public_key();
+	let trusted_clients = [[0u8; 32]];
+	let (mut client_rng, mut server_rng) = (OsRng, OsRng);
+	let (client, server): (
+		NegotiationResult<LinkToDaemon, DaemonToLink>,
+		NegotiationResult<DaemonToLink, LinkToDaemon>,
+	) = futures::join!(
+		negotiate(
+			client.writer,
+			client.reader,
+			&mut client_rng,
+			Side::Client,
+			&client_identity,
+			&trusted_server,
+		),
+		negotiate(
+			server.writer,
+			server.reader,
+			&mut server_rng,
+			Side::Server,
+			&server_identity,
+			&trusted_clients[..],
+		),
+	);
+
+	assert!(matches!(
+		client,
+		Err(NegotiationError::Rejected(Side::Server))
+	));
+	assert!(matches!(
+		server,
+		Err(NegotiationError::Rejected(Side::Server))
+	));
+}
+
+#[async_std::test]
+async fn rejects_zero_identities() {
+	let (client, mut server) = duplex();
+	let client_identity = client_identity();
+	// What a pinned key that was never configured would look like.
+	let trusted_server = [0u8; 32];
+	let mut client_rng = OsRng;
+
+	// The server can't derive the channel key without the secret for
+	// its identity, unless its identity is all zeros; then every shared
+	// secret involving it is all zeros too.
+	let impostor = async {
+		let mut client_keys = [0u8; 64];
+		server.reader.read(&mut client_keys).await.unwrap();
+		server
+			.writer
+			.write(&Identity::from_secret([3; 32]).public_key())
+			.await
+			.unwrap();
+		server.writer.write(&[0; 32]).await.unwrap();
+		server.writer.flush().await.unwrap();
+
+		let mut verdict = [0xFF];
+		server.reader.read(&mut verdict).await.unwrap();
+		verdict[0]
+	};
+
+	let (client, verdict): (NegotiationResult<LinkToDaemon, DaemonToLink>, u8) = futures::join!(
+		negotiate(
+			client.writer,
+			client.reader,
+			&mut client_rng,
+			Side::Client,
+			&client_identity,
+			&trusted_server,
+		),
+		impostor,
+	);
+
+	assert!(matches!(
+		client,
+		Err(NegotiationError::Rejected(Side::Client))
+	));
+	assert_eq!(verdict, 0, "the client should have rejected the impostor");
+}
+
+#[async_std::test]
+async fn every_packet_roundtrips_both_ways() {
+	let (client, server) = duplex();
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, mut client_receiver) = client.unwrap();
+	let (server_sender, mut server_receiver) = server.unwrap();
+
+	assert_roundtrips(every_link_packet(), &client_sender, &mut server_receiver).await;
+	assert_roundtrips(every_daemon_packet(), &server_sender, &mut client_receiver).await;
+}
+
+#[async_std::test]
+async fn runner_packets_roundtrip_both_ways() {
+	let (client, server) = duplex();
+	let (client, server) = connect::<ClientToDaemon, DaemonToClient>(client, server).await;
+	let (client_sender, mut client_receiver) = client.unwrap();
+	let (server_sender, mut server_receiver) = server.unwrap();
+
+	let runner_packets = vec![
+		ClientToDaemon::StartTestSession {
+			total_tests: 1,
+			author: "author".try_into().unwrap(),
+			title: "title".try_into().unwrap(),
+			ref_id: "ref".try_into().unwrap(),
+		},
+		ClientToDaemon::StartTest {
+			name: "test".try_into().unwrap(),
+		},
+		ClientToDaemon::PressPower,
+		ClientToDaemon::PressReset,
+		ClientToDaemon::BootfileSize { uefi: 1, bios: 2 },
+		ClientToDaemon::Serial((0..=255).collect()),
+	];
+
+	assert_roundtrips(runner_packets, &client_sender, &mut server_receiver).await;
+	assert_roundtrips(
+		vec![
+			DaemonToClient::Serial(b"output".iter().copied().collect()),
+			DaemonToClient::SessionFailed {
+				reason: "the SUT stopped responding".try_into().unwrap(),
+			},
+		],
+		&server_sender,
+		&mut client_receiver,
+	)
+	.await;
+}
+
+#[async_std::test]
+async fn survives_delays_and_short_reads() {
+	let (mut client, server) = duplex();
+	client.writer = client.writer.with_faults(Faults {
+		delay: Some(Duration::from_millis(1)),
+		max_chunk: Some(3),
+		..Faults::default()
+	});
+
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, _) = client.unwrap();
+	let (_, mut server_receiver) = server.unwrap();
+
+	assert_roundtrips(every_link_packet(), &client_sender, &mut server_receiver).await;
+}
+
+#[async_std::test]
+async fn routes_replies_to_requests() {
+	let (client, server) = duplex();
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, mut client_receiver) = client.unwrap();
+	let (server_sender, mut server_receiver) = server.unwrap();
+
+	let acked = server_sender
+		.send_request(DaemonToLink::PressPower)
+		.await
+		.unwrap();
+	let nacked = server_sender
+		.send_request(DaemonToLink::PressReset)
+		.await
+		.unwrap();
+
+	for reply in [
+		|seq| Control::Ack { seq },
+		|seq| Control::Nack {
+			seq,
+			reason: NackReason::InvalidState,
+		},
+	] {
+		let (seq, _) = client_receiver.receive_request().await.unwrap();
+		client_sender
+			.send_control(reply(seq.unwrap()))
+			.await
+			.unwrap();
+	}
+	client_sender.send(serial(b"done")).await.unwrap();
+
+	// Replies are routed while waiting for the next packet.
+	assert!(matches!(
+		server_receiver.receive().await,
+		Ok(Incoming::Packet(LinkToDaemon::Serial(data))) if data == b"done"
+	));
+	assert!(acked.wait(Duration::from_secs(1)).await.is_ok());
+	assert!(matches!(
+		nacked.wait(Duration::from_secs(1)).await,
+		Err(ReplyError::Rejected(NackReason::InvalidState))
+	));
+}
+
+#[async_std::test]
+async fn skips_unknown_packets() {
+	let (client, server) = duplex();
+	let (daemon, link) = connect_newer_daemon(client, server).await;
+	let (daemon_sender, _) = daemon.unwrap();
+	let (_, mut link_receiver) = link.unwrap();
+
+	// An unknown message code, then an unknown enumeration value.
+	daemon_sender
+		.send(NewerDaemonToLink::Hibernate { minutes: 5 })
+		.await
+		.unwrap();
+	daemon_sender
+		.send(NewerDaemonToLink::SetPowerState(
+			NewerPowerState::Hibernating,
+		))
+		.await
+		.unwrap();
+	daemon_sender
+		.send(NewerDaemonToLink::SetPowerState(NewerPowerState::On))
+		.await
+		.unwrap();
+
+	assert_eq!(
+		link_receiver.receive().await.unwrap(),
+		Incoming::Packet(DaemonToLink::SetPowerState(PowerState::On))
+	);
+}
+
+#[async_std::test]
+async fn rejects_unknown_requests() {
+	let (client, server) = duplex();
+	let (daemon, link) = connect_newer_daemon(client, server).await;
+	let (daemon_sender, mut daemon_receiver) = daemon.unwrap();
+	let (link_sender, mut link_receiver) = link.unwrap();
+
+	let pending = daemon_sender
+		.send_request(NewerDaemonToLink::Hibernate { minutes: 5 })
+		.await
+		.unwrap();
+
+	let (seq, packet) = link_receiver.receive_request().await.unwrap();
+	let seq = seq.unwrap();
+	assert_eq!(packet, Incoming::Unsupported { seq });
+	link_sender
+		.send_control(Control::Nack {
+			seq,
+			reason: NackReason::Unsupported,
+		})
+		.await
+		.unwrap();
+	link_sender.send(serial(b"done")).await.unwrap();
+
+	// Receiving the packet after it routes the reply.
+	assert_eq!(
+		daemon_receiver.receive().await.unwrap(),
+		Incoming::Packet(serial(b"done"))
+	);
+	assert!(matches!(
+		pending.wait(Duration::from_secs(1)).await,
+		Err(ReplyError::Rejected(NackReason::Unsupported))
+	));
+}
+
+#[async_std::test]
+async fn rekeys_both_directions_without_losing_packets() {
+	let (client, server) = duplex();
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, mut client_receiver) = client.unwrap();
+	let (server_sender, mut server_receiver) = server.unwrap();
+
+	client_sender.send(serial(b"before")).await.unwrap();
+	client_sender.rekey(&mut OsRng).await.unwrap();
+	// Still sealed with the old key, as the server hasn't answered yet.
+	client_sender.send(serial(b"during")).await.unwrap();
+	server_sender.send(daemon_serial(b"before")).await.unwrap();
+
+	assert_eq!(
+		server_receiver.receive().await.unwrap(),
+		Incoming::Packet(serial(b"before"))
+	);
+	let offer = receive_rekey(&mut server_receiver).await;
+	server_sender
+		.answer_rekey(&mut server_receiver, &offer)
+		.await
+		.unwrap();
+	server_sender.send(daemon_serial(b"after")).await.unwrap();
+	assert_eq!(
+		server_receiver.receive().await.unwrap(),
+		Incoming::Packet(serial(b"during"))
+	);
+
+	assert_eq!(
+		client_receiver.receive().await.unwrap(),
+		Incoming::Packet(daemon_serial(b"before"))
+	);
+	let answer = receive_rekey(&mut client_receiver).await;
+	client_sender
+		.answer_rekey(&mut client_receiver, &answer)
+		.await
+		.unwrap();
+	client_sender.send(serial(b"after")).await.unwrap();
+
+	assert_eq!(
+		client_receiver.receive().await.unwrap(),
+		Incoming::Packet(daemon_serial(b"after"))
+	);
+	assert_eq!(
+		server_receiver.receive().await.unwrap(),
+		Incoming::Packet(serial(b"after"))
+	);
+}
+
+#[async_std::test]
+async fn rekeys_when_both_sides_start_at_once() {
+	let (client, server) = duplex();
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, mut client_receiver) = client.unwrap();
+	let (server_sender, mut server_receiver) = server.unwrap();
+
+	client_sender.rekey(&mut OsRng).await.unwrap();
+	server_sender.rekey(&mut OsRng).await.unwrap();
+
+	// Each side takes the other's offer as the answer to its own.
+	let server_offer = receive_rekey(&mut client_receiver).await;
+	client_sender
+		.answer_rekey(&mut client_receiver, &server_offer)
+		.await
+		.unwrap();
+	let client_offer = receive_rekey(&mut server_receiver).await;
+	server_sender
+		.answer_rekey(&mut server_receiver, &client_offer)
+		.await
+		.unwrap();
+
+	client_sender.send(serial(b"after")).await.unwrap();
+	server_sender.send(daemon_serial(b"after")).await.unwrap();
+	assert_eq!(
+		server_receiver.receive().await.unwrap(),
+		Incoming::Packet(serial(b"after"))
+	);
+	assert_eq!(
+		client_receiver.receive().await.unwrap(),
+		Incoming::Packet(daemon_serial(b"after"))
+	);
+}
+
+#[async_std::test]
+async fn rekeys_once_over_the_byte_budget() {
+	let (client, server) = duplex();
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, mut client_receiver) = client.unwrap();
+	let (server_sender, mut server_receiver) = server.unwrap();
+
+	let policy = RekeyPolicy {
+		max_bytes: 1024,
+		max_millis: u64::MAX,
+	};
+	assert!(
+		!client_sender
+			.rekey_if_due(&mut OsRng, &policy, 0)
+			.await
+			.unwrap()
+	);
+
+	// No further ticks; sending is what runs over the budget.
+	let packet = serial(&[0; 200]);
+	for _ in 0..8 {
+		client_sender.send(packet.clone()).await.unwrap();
+	}
+
+	let mut received = 0;
+	let offer = loop {
+		match server_receiver.receive().await.unwrap() {
+			Incoming::Packet(_) => received += 1,
+			Incoming::Control(Control::Rekey { public_key }) => break public_key,
+			other => panic!("expected a packet or a rekey, got {other:?}"),
+		}
+	};
+	assert!(received < 8);
+
+	server_sender
+		.answer_rekey(&mut server_receiver, &offer)
+		.await
+		.unwrap();
+	let answer = receive_rekey(&mut client_receiver).await;
+	client_sender
+		.answer_rekey(&mut client_receiver, &answer)
+		.await
+		.unwrap();
+
+	for _ in received..8 {
+		assert_eq!(
+			server_receiver.receive().await.unwrap(),
+			Incoming::Packet(packet.clone())
+		);
+	}
+}
+
+#[async_std::test]
+async fn rejects_low_order_rekey_keys() {
+	let (client, server) = duplex();
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, _) = client.unwrap();
+	let (server_sender, mut server_receiver) = server.unwrap();
+
+	client_sender
+		.send_control(Control::Rekey {
+			public_key: [0; 32],
+		})
+		.await
+		.unwrap();
+	let offer = receive_rekey(&mut server_receiver).await;
+	assert!(matches!(
+		server_sender
+			.answer_rekey(&mut server_receiver, &offer)
+			.await,
+		Err(Error::Unauthenticated)
+	));
+}
+
+#[async_std::test]
+async fn rejects_switching_keys_without_a_rekey() {
+	let (client, server) = duplex();
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, _) = client.unwrap();
+	let (_, mut server_receiver) = server.unwrap();
+
+	client_sender.send_control(Control::Rekeyed).await.unwrap();
+	assert!(matches!(
+		server_receiver.receive().await,
+		Err(Error::Unauthenticated)
+	));
+}
+
+#[async_std::test]
+async fn rejects_corrupted_records() {
+	let (mut client, server) = duplex();
+	// Well past the handshake, inside the first record's ciphertext.
+	client.writer = client.writer.with_faults(Faults {
+		corrupt_at: Some(100),
+		..Faults::default()
+	});
+
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, _) = client.unwrap();
+	let (_, mut server_receiver) = server.unwrap();
+
+	client_sender.send(serial(b"data")).await.unwrap();
+	assert!(matches!(
+		server_receiver.receive().await,
+		Err(Error::Unauthenticated)
+	));
+}
+
+#[async_std::test]
+async fn reports_disconnects() {
+	let (mut client, server) = duplex();
+	// Cuts the first record off halfway through.
+	client.writer = client.writer.with_faults(Faults {
+		disconnect_after: Some(100),
+		..Faults::default()
+	});
+
+	let (client, server) = connect_link(client, server).await;
+	let (client_sender, _) = client.unwrap();
+	let (_, mut server_receiver) = server.unwrap();
+
+	assert!(matches!(
+		client_sender.send(serial(b"data")).await,
+		Err(Error::Io(Disconnected))
+	));
+	assert!(matches!(server_receiver.receive().await, Err(Error::Eof)));
+}
@@ -0,0 +1,46 @@
+use link_protocol::{json, Control, DaemonToLink, LinkToDaemon, LogEntry, PowerState};
+
+#[test]
+fn renders_keys_as_hex() {
+	let mut uid = [0; 32];
+	uid[0] = 0xab;
+	uid[31] = 0x01;
+
+	assert_eq!(
+		json::to_line(&LinkToDaemon::LinkOnline {
+			uid,
+			version: "1.2.3".try_into().unwrap(),
+		}),
+		format!(
+			r#"{{"LinkOnline":{{"uid":"ab{}01","version":"1.2.3"}}}}"#,
+			"00".repeat(30)
+		)
+	);
+}
+
+#[test]
+fn renders_heapless_strings_and_vectors() {
+	assert_eq!(
+		json::to_line(&LinkToDaemon::Serial(b"hi\n".iter().copied().collect())),
+		r#"{"Serial":[104,105,10]}"#
+	);
+	assert_eq!(
+		json::to_line(&DaemonToLink::Log(LogEntry::Warn(
+			"disk \"full\"".try_into().unwrap()
+		))),
+		r#"{"Log":{"Warn":"disk \"full\""}}"#
+	);
+}
+
+#[test]
+fn renders_plain_variants() {
+	assert_eq!(json::to_line(&DaemonToLink::PressReset), r#""PressReset""#);
+	assert_eq!(
+		json::to_line(&DaemonToLink::SetPowerState(PowerState::On)),
+		r#"{"SetPowerState":"On"}"#
+	);
+	assert_eq!(
+		json::to_line(&Control::Ping { nonce: 7 }),
+		r#"{"Ping":{"nonce":7}}"#
+	);
+}
@@ -0,0 +1,209 @@
+use core::fmt::Debug;
+use link_protocol::{
+	arbitrary::{Arbitrary, Unstructured},
+	blocking::{decode_from_slice, encode_to_vec},
+	schema::SchemaBody,
+	Capabilities, ClientToDaemon, Control, DaemonToClient, DaemonToLink, Deserialize, Error,
+	FailSafePolicy, FieldError, LinkToDaemon, LogEntry, MaxEncodedLen, NackReason, PowerState,
+	Scene, Schema, Serialize,
+};
+use std::collections::BTreeSet;
+
+/// How many random inputs each property is checked against.
+const ITERATIONS: usize = 2000;
+
+/// Generated strings need a few bytes of input per character, so this
+/// is enough to fill even the largest ones.
+const MAX_INPUT_LEN: usize = 4096;
+
+/// A fixed-seed xorshift generator, so that failures are reproducible.
+struct Bytes(u64);
+
+impl Bytes {
+	fn new() -> Self {
+		Self(0x6f72_6f2d_6c69_6e6b)
+	}
+
+	fn next(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	fn take(&mut self) -> Vec<u8> {
+		let len = self.next() as usize % MAX_INPUT_LEN;
+		(0..len).map(|_| self.next() as u8).collect()
+	}
+}
+
+trait Message:
+	for<'a> Arbitrary<'a> + Serialize + Deserialize + MaxEncodedLen + Schema + PartialEq + Debug
+{
+}
+
+impl<T> Message for T where
+	T: for<'a> Arbitrary<'a> + Serialize + Deserialize + MaxEncodedLen + Schema + PartialEq + Debug
+{
+}
+
+/// Random values of `T`; every variant of an enum comes up many times
+/// over in this many iterations.
+fn generate<T: Message>() -> impl Iterator<Item = T> {
+	let mut bytes = Bytes::new();
+	(0..ITERATIONS).filter_map(move |_| T::arbitrary(&mut Unstructured::new(&bytes.take())).ok())
+}
+
+fn has_optional_fields<T: Schema>() -> bool {
+	match T::SCHEMA.body {
+		SchemaBody::Struct(fields) => fields.iter().any(|field| field.optional),
+		SchemaBody::Enum(variants) => variants
+			.iter()
+			.flat_map(|variant| variant.fields)
+			.any(|field| field.optional),
+	}
+}
+
+/// Asserts that `deserialize(serialize(value)) == value` for random
+/// values of `T`, and that every variant of `T` was among them.
+fn assert_roundtrips<T: Message>() {
+	let mut seen = BTreeSet::new();
+
+	for value in generate::<T>() {
+		let encoded = encode_to_vec(&value).unwrap();
+		assert!(encoded.len() <= T::MAX_ENCODED_LEN, "{value:?}");
+
+		let (decoded, len) = decode_from_slice::<T>(&encoded).unwrap();
+		assert_eq!(decoded, value);
+		assert_eq!(len, encoded.len(), "{value:?}");
+
+		seen.insert(encoded[0]);
+	}
+
+	if let SchemaBody::Enum(variants) = T::SCHEMA.body {
+		for variant in variants {
+			assert!(
+				seen.contains(&variant.id),
+				"{}::{} was never generated",
+				T::SCHEMA.name,
+				variant.name
+			);
+		}
+	}
+}
+
+/// Asserts that every strict prefix of an encoded value fails with
+/// an EOF, rather than decoding as something else (or panicking). Only
+/// values with optional fields may decode from a prefix.
+fn assert_truncations_fail<T: Message>() {
+	for value in generate::<T>().take(ITERATIONS / 10) {
+		let encoded = encode_to_vec(&value).unwrap();
+
+		for len in 0..encoded.len() {
+			match decode_from_slice::<T>(&encoded[..len]) {
+				Ok(_) => assert!(
+					has_optional_fields::<T>(),
+					"{value:?} decoded from {len} bytes"
+				),
+				Err(err) => assert_eq!(err.without_context(), Error::Eof, "{value:?}"),
+			}
+		}
+	}
+}
+
+/// Feeds random bytes to `T`'s decoder. Whatever decodes must survive
+/// another round trip unchanged.
+fn assert_garbage_is_handled<T: Message>() {
+	let mut bytes = Bytes::new();
+
+	for _ in 0..ITERATIONS {
+		let input = bytes.take();
+		if let Ok((value, _)) = decode_from_slice::<T>(&input) {
+			let encoded = encode_to_vec(&value).unwrap();
+			assert_eq!(decode_from_slice::<T>(&encoded).unwrap().0, value);
+		}
+	}
+}
+
+macro_rules! properties {
+	($($ty:ty),* $(,)?) => {
+		$(
+			assert_roundtrips::<$ty>();
+			assert_truncations_fail::<$ty>();
+			assert_garbage_is_handled::<$ty>();
+		)*
+	};
+}
+
+#[test]
+fn every_packet_roundtrips() {
+	properties!(
+		Control,
+		LinkToDaemon,
+		DaemonToLink,
+		ClientToDaemon,
+		DaemonToClient,
+	);
+}
+
+#[test]
+fn every_field_type_roundtrips() {
+	properties!(
+		NackReason,
+		FailSafePolicy,
+		Capabilities,
+		Scene,
+		LogEntry,
+		PowerState,
+	);
+}
+
+#[test]
+fn rejects_oversized_vectors() {
+	// A 257 byte serial chunk, for a vector that holds 256.
+	let err = decode_from_slice::<DaemonToLink>(&[13, 0x01, 0x01]).unwrap_err();
+	let Error::InField { path, error } = err else {
+		panic!("unexpected error: {err:?}");
+	};
+	assert_eq!(path.to_string(), "DaemonToLink::Serial.0");
+	assert_eq!(error, FieldError::ArrayTooLong { len: 257, max: 256 });
+}
+
+#[test]
+fn rejects_oversized_strings() {
+	let mut input = vec![1];
+	input.extend_from_slice(&[0; 32]);
+	input.push(17);
+	input.extend_from_slice(&[b'1'; 17]);
+
+	let err = decode_from_slice::<LinkToDaemon>(&input).unwrap_err();
+	let Error::InField { path, error } = err else {
+		panic!("unexpected error: {err:?}");
+	};
+	assert_eq!(path.to_string(), "LinkToDaemon::LinkOnline.version");
+	assert_eq!(error, FieldError::StringTooLong { len: 17, max: 16 });
+}
+
+#[test]
+fn rejects_vectors_shorter_than_their_length() {
+	let err = decode_from_slice::<DaemonToLink>(&[13, 0, 10, 1, 2, 3]).unwrap_err();
+	assert_eq!(err.without_context(), Error::Eof);
+}
+
+#[test]
+fn drops_partially_decoded_vectors() {
+	type Strings = heapless::Vec<heapless::String<8>, 4>;
+
+	// Fails on the third string, after two have been decoded.
+	let input = [3, 2, b'h', b'i', 2, b'o', b'k', 1, 0xff];
+	assert_eq!(
+		decode_from_slice::<Strings>(&input).unwrap_err(),
+		Error::MalformedString
+	);
+
+	// Likewise, but running out of input instead.
+	assert_eq!(
+		decode_from_slice::<Strings>(&input[..6]).unwrap_err(),
+		Error::Eof
+	);
+}
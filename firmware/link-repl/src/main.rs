@@ -1,28 +1,41 @@
 #![feature(never_type, async_closure)]
 
-use async_std::{io, net::TcpListener, prelude::*, sync::Mutex, task};
+use async_std::{
+	io,
+	net::TcpListener,
+	prelude::*,
+	sync::{Mutex, MutexGuard},
+	task,
+};
 use envconfig::Envconfig;
 
 use link_protocol::{
-	channel::{PacketSender, RWError},
-	Error as ProtoError, LogEntry, PowerState,
+	channel::{HelloError, Identity, Incoming, NegotiationError, PacketReceiver, PacketSender},
+	Capabilities, Error as ProtoError, LogEntry, PowerState,
 };
 use mini_async_repl::{
 	command::{Command, CommandArgInfo, CommandArgType, ExecuteCommand},
 	CommandStatus, Repl,
 };
 
-use std::{str::FromStr, sync::Arc};
+use std::{str::FromStr, sync::Arc, time::Duration};
 
 use async_std::{
 	io::{BufReader, BufWriter},
 	net::TcpStream,
 };
 use futures::{prelude::*, select};
-use link_protocol::{channel, Packet, Scene};
+use link_protocol::{
+	channel, json,
+	keys::{Key, KeyList},
+	transcript::{self, Route},
+	Control, DaemonToLink, LinkToDaemon, NackReason, Scene,
+};
 use log::{error, info, warn};
 use rand::rngs::OsRng;
 
+type LinkSender = PacketSender<BufWriter<TcpStream>, DaemonToLink>;
+
 #[derive(Envconfig, Clone)]
 pub(crate) struct Config {
 	#[envconfig(from = "LINK_SERVER_PORT", default = "1337")]
@@ -33,6 +46,14 @@ pub(crate) struct Config {
 	pub log_level: String,
 	#[envconfig(from = "VERBOSE", default = "0")]
 	pub verbose: u8,
+	/// The identity secret (hex) to present to links; its public key
+	/// must be pinned in the link firmware.
+	#[envconfig(from = "LINK_IDENTITY_SECRET")]
+	pub identity_secret: Key,
+	/// Comma separated list of link identity public keys (hex) that are
+	/// allowed to connect.
+	#[envconfig(from = "LINK_TRUSTED_KEYS", default = "")]
+	pub trusted_link_keys: KeyList,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -42,8 +63,10 @@ pub(crate) enum Error {
 	AsyncIo(#[from] io::Error),
 	#[error("i/o error during protocol transcoding")]
 	Proto(#[from] ProtoError<io::Error>),
-	#[error("i/o error during link connection negotiation")]
-	RWError(#[from] RWError<ProtoError<io::Error>, ProtoError<io::Error>>),
+	#[error("link connection negotiation failed: {0}")]
+	Negotiation(#[from] NegotiationError<ProtoError<io::Error>, ProtoError<io::Error>>),
+	#[error("link is incompatible with this repl: {0}")]
+	Hello(#[from] HelloError<ProtoError<io::Error>, ProtoError<io::Error>>),
 	#[error("failed to receive channel message")]
 	ChannelRecv,
 	#[error("failed to send channel message")]
@@ -87,6 +110,14 @@ async fn main() -> Result<(), Error> {
 
 	info!("starting oro-repl version {}", env!("CARGO_PKG_VERSION"));
 
+	let identity = Identity::from_secret(config.identity_secret.0);
+	info!(
+		"repl identity public key: {}",
+		hex::encode(identity.public_key())
+	);
+
+	let trusted_link_keys = config.trusted_link_keys.0;
+
 	let listener =
 		TcpListener::bind((config.link_server_bind.as_str(), config.link_server_port)).await?;
 	let mut incoming = listener.incoming();
@@ -99,27 +130,89 @@ async fn main() -> Result<(), Error> {
 	while let Some(stream) = futures::StreamExt::next(&mut incoming).await {
 		let stream = stream?;
 
-		let (outgoing, mut incoming) = {
+		let (outgoing, mut incoming): (LinkSender, PacketReceiver<_, LinkToDaemon>) = {
 			// create buffered readers/writers for stream
 			let sock_reader = BufReader::new(stream.clone());
 			let sock_writer = BufWriter::new(stream);
-			channel::negotiate(sock_writer, sock_reader, &mut OsRng, channel::Side::Server).await?
+			channel::negotiate(
+				sock_writer,
+				sock_reader,
+				&mut OsRng,
+				channel::Side::Server,
+				&identity,
+				&trusted_link_keys[..],
+			)
+			.await?
 		};
 
 		info!("established link protocol channel");
 
-		let mut repl = make_repl(outgoing);
+		// The REPL can poke at anything, but doesn't need anything in particular.
+		let capabilities = channel::exchange_hello(
+			&outgoing,
+			&mut incoming,
+			Capabilities::SERIAL
+				| Capabilities::POWER_CONTROL
+				| Capabilities::PXE
+				| Capabilities::USB_HID
+				| Capabilities::PACKET_CAPTURE
+				| Capabilities::MONITOR
+				| Capabilities::HEARTBEAT
+				| Capabilities::REKEY,
+			Capabilities::empty(),
+		)
+		.await?;
+		info!("negotiated link capabilities: {capabilities}");
 
-		let link_logger_task = task::spawn(async move {
-			let packet = match incoming.receive().await {
-				Ok(packet) => packet,
-				Err(err) => {
-					error!("failed to receive packet: {:?}", err);
-					return;
-				}
-			};
+		let outgoing = Arc::new(Mutex::new(outgoing));
+		let mut repl = make_repl(outgoing.clone());
 
-			info!("received packet: {:?}", packet);
+		// Keeps receiving so that replies to commands are picked up
+		// and the link's pings are answered.
+		let link_logger_task = task::spawn(async move {
+			loop {
+				let packet = match incoming.receive_request().await.map(|(_, packet)| packet) {
+					Ok(Incoming::Packet(packet)) => packet,
+					Ok(Incoming::Control(Control::Ping { nonce })) => {
+						let pong = Control::Pong { nonce };
+						if let Err(err) = outgoing.lock().await.send_control(pong).await {
+							error!("failed to answer ping: {:?}", err);
+							return;
+						}
+						continue;
+					}
+					Ok(Incoming::Control(Control::Rekey { public_key })) => {
+						let outgoing = outgoing.lock().await;
+						if let Err(err) = outgoing.answer_rekey(&mut incoming, &public_key).await {
+							error!("failed to answer rekey: {:?}", err);
+							return;
+						}
+						continue;
+					}
+					Ok(Incoming::Control(packet)) => {
+						info!("received control packet: {}", json::to_line(&packet));
+						continue;
+					}
+					Ok(Incoming::Unsupported { seq }) => {
+						warn!("link sent a request the repl doesn't understand");
+						let nack = Control::Nack {
+							seq,
+							reason: NackReason::Unsupported,
+						};
+						if let Err(err) = outgoing.lock().await.send_control(nack).await {
+							error!("failed to reject request: {:?}", err);
+							return;
+						}
+						continue;
+					}
+					Err(err) => {
+						error!("failed to receive packet: {:?}", err);
+						return;
+					}
+				};
+
+				info!("received packet: {}", json::to_line(&packet));
+			}
 		});
 
 		select! {
@@ -136,9 +229,27 @@ async fn main() -> Result<(), Error> {
 	Ok(())
 }
 
-fn make_repl(outgoing: PacketSender<BufWriter<TcpStream>>) -> Repl {
-	let outgoing = Arc::new(Mutex::new(outgoing));
+/// How long the link has to acknowledge a command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sends a command to the link and reports whether or not it was carried out.
+/// The sender is released while waiting so that pings can still be answered.
+async fn request(
+	sender: MutexGuard<'_, LinkSender>,
+	packet: DaemonToLink,
+) -> mini_async_repl::anyhow::Result<CommandStatus> {
+	let pending = sender.send_request(packet).await?;
+	drop(sender);
+
+	match pending.wait(REPLY_TIMEOUT).await {
+		Ok(()) => info!("link acknowledged the command"),
+		Err(err) => error!("link did not carry out the command: {err}"),
+	}
+
+	Ok(CommandStatus::Done)
+}
 
+fn make_repl(outgoing: Arc<Mutex<LinkSender>>) -> Repl {
 	Repl::builder()
 		.description("Oro Link session REPL")
 		.prompt("oro> ")
@@ -264,7 +375,18 @@ fn make_repl(outgoing: PacketSender<BufWriter<TcpStream>>) -> Repl {
 					CommandArgType::String,
 					"name",
 				)],
-				Box::new(TestCommand(outgoing)),
+				Box::new(TestCommand(outgoing.clone())),
+			),
+		)
+		.add(
+			"replay",
+			Command::new(
+				"replays what a daemon sent to a link, from a session transcript",
+				vec![CommandArgInfo::new_with_name(
+					CommandArgType::String,
+					"transcript",
+				)],
+				Box::new(ReplayCommand(outgoing)),
 			),
 		)
 		.build()
@@ -291,7 +413,7 @@ impl ExecuteCommand for SelfTest {
 	}
 }
 
-struct SceneCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct SceneCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for SceneCommand {
 	fn execute(
@@ -305,7 +427,7 @@ impl ExecuteCommand for SceneCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
+			let sender = self.0.lock().await;
 
 			let scene = match args[0].as_str() {
 				"test" => Scene::Test,
@@ -317,7 +439,7 @@ impl ExecuteCommand for SceneCommand {
 				}
 			};
 
-			let packet = Packet::SetScene(scene);
+			let packet = DaemonToLink::SetScene(scene);
 
 			sender.send(packet).await?;
 
@@ -326,7 +448,7 @@ impl ExecuteCommand for SceneCommand {
 	}
 }
 
-struct MonitorCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct MonitorCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for MonitorCommand {
 	fn execute(
@@ -340,7 +462,7 @@ impl ExecuteCommand for MonitorCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
+			let sender = self.0.lock().await;
 
 			let standby = match args[0].as_str() {
 				"on" => false,
@@ -351,7 +473,7 @@ impl ExecuteCommand for MonitorCommand {
 				}
 			};
 
-			let packet = Packet::SetMonitorStandby(standby);
+			let packet = DaemonToLink::SetMonitorStandby(standby);
 
 			sender.send(packet).await?;
 
@@ -360,7 +482,7 @@ impl ExecuteCommand for MonitorCommand {
 	}
 }
 
-struct PowerCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct PowerCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for PowerCommand {
 	fn execute(
@@ -374,7 +496,7 @@ impl ExecuteCommand for PowerCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
+			let sender = self.0.lock().await;
 
 			let state = match args[0].as_str() {
 				"on" => PowerState::On,
@@ -386,16 +508,12 @@ impl ExecuteCommand for PowerCommand {
 				}
 			};
 
-			let packet = Packet::SetPowerState(state);
-
-			sender.send(packet).await?;
-
-			Ok(CommandStatus::Done)
+			request(sender, DaemonToLink::SetPowerState(state)).await
 		})
 	}
 }
 
-struct PowerButtonCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct PowerButtonCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for PowerButtonCommand {
 	fn execute(
@@ -409,18 +527,14 @@ impl ExecuteCommand for PowerButtonCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
-
-			let packet = Packet::PressPower;
-
-			sender.send(packet).await?;
+			let sender = self.0.lock().await;
 
-			Ok(CommandStatus::Done)
+			request(sender, DaemonToLink::PressPower).await
 		})
 	}
 }
 
-struct ResetButtonCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct ResetButtonCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for ResetButtonCommand {
 	fn execute(
@@ -434,18 +548,14 @@ impl ExecuteCommand for ResetButtonCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
-
-			let packet = Packet::PressReset;
-
-			sender.send(packet).await?;
+			let sender = self.0.lock().await;
 
-			Ok(CommandStatus::Done)
+			request(sender, DaemonToLink::PressReset).await
 		})
 	}
 }
 
-struct InfoLogCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct InfoLogCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for InfoLogCommand {
 	fn execute(
@@ -459,8 +569,8 @@ impl ExecuteCommand for InfoLogCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
-			let packet = Packet::Log(LogEntry::Info(heapless::String::<255>::from_iter(
+			let sender = self.0.lock().await;
+			let packet = DaemonToLink::Log(LogEntry::Info(heapless::String::<255>::from_iter(
 				args.join(" ").chars(),
 			)));
 			sender.send(packet).await?;
@@ -469,7 +579,7 @@ impl ExecuteCommand for InfoLogCommand {
 	}
 }
 
-struct WarnLogCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct WarnLogCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for WarnLogCommand {
 	fn execute(
@@ -483,8 +593,8 @@ impl ExecuteCommand for WarnLogCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
-			let packet = Packet::Log(LogEntry::Warn(heapless::String::<255>::from_iter(
+			let sender = self.0.lock().await;
+			let packet = DaemonToLink::Log(LogEntry::Warn(heapless::String::<255>::from_iter(
 				args.join(" ").chars(),
 			)));
 			sender.send(packet).await?;
@@ -493,7 +603,7 @@ impl ExecuteCommand for WarnLogCommand {
 	}
 }
 
-struct ErrorLogCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct ErrorLogCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for ErrorLogCommand {
 	fn execute(
@@ -507,8 +617,8 @@ impl ExecuteCommand for ErrorLogCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
-			let packet = Packet::Log(LogEntry::Error(heapless::String::<255>::from_iter(
+			let sender = self.0.lock().await;
+			let packet = DaemonToLink::Log(LogEntry::Error(heapless::String::<255>::from_iter(
 				args.join(" ").chars(),
 			)));
 			sender.send(packet).await?;
@@ -517,7 +627,7 @@ impl ExecuteCommand for ErrorLogCommand {
 	}
 }
 
-struct KeyPressDebugCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct KeyPressDebugCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for KeyPressDebugCommand {
 	fn execute(
@@ -531,8 +641,8 @@ impl ExecuteCommand for KeyPressDebugCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
-			let packet = Packet::DebugUsbKey(
+			let sender = self.0.lock().await;
+			let packet = DaemonToLink::DebugUsbKey(
 				args[0]
 					.parse()
 					.map_err(|_| mini_async_repl::anyhow::anyhow!("invalid keycode"))?,
@@ -543,7 +653,7 @@ impl ExecuteCommand for KeyPressDebugCommand {
 	}
 }
 
-struct SuiteCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct SuiteCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for SuiteCommand {
 	fn execute(
@@ -557,8 +667,8 @@ impl ExecuteCommand for SuiteCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
-			let packet = Packet::StartTestSession {
+			let sender = self.0.lock().await;
+			let packet = DaemonToLink::StartTestSession {
 				total_tests: args[0]
 					.parse()
 					.map_err(|_| mini_async_repl::anyhow::anyhow!("invalid total_tests"))?,
@@ -581,7 +691,7 @@ impl ExecuteCommand for SuiteCommand {
 	}
 }
 
-struct TestCommand(Arc<Mutex<PacketSender<BufWriter<TcpStream>>>>);
+struct TestCommand(Arc<Mutex<LinkSender>>);
 
 impl ExecuteCommand for TestCommand {
 	fn execute(
@@ -595,8 +705,8 @@ impl ExecuteCommand for TestCommand {
 		>,
 	> {
 		Box::pin(async move {
-			let mut sender = self.0.lock().await;
-			let packet = Packet::StartTest {
+			let sender = self.0.lock().await;
+			let packet = DaemonToLink::StartTest {
 				name: args[0]
 					.as_str()
 					.try_into()
@@ -607,3 +717,55 @@ impl ExecuteCommand for TestCommand {
 		})
 	}
 }
+
+struct ReplayCommand(Arc<Mutex<LinkSender>>);
+
+impl ExecuteCommand for ReplayCommand {
+	fn execute(
+		&mut self,
+		args: Vec<String>,
+		_args_info: Vec<mini_async_repl::command::CommandArgInfo>,
+	) -> std::pin::Pin<
+		Box<
+			dyn Future<Output = mini_async_repl::anyhow::Result<mini_async_repl::CommandStatus>>
+				+ '_,
+		>,
+	> {
+		Box::pin(async move {
+			let file = std::fs::File::open(&args[0])?;
+			let (header, entries) = transcript::read(std::io::BufReader::new(file))?;
+			info!(
+				"replaying session of link {} (firmware {})",
+				header.link_id, header.version
+			);
+
+			let mut last_millis = 0;
+			for entry in entries {
+				let entry = entry?;
+
+				// Heartbeats belong to the channel, and session tokens
+				// only mean something to the daemon that handed them out.
+				if entry.route != Route::DaemonToLink || entry.is_control() {
+					continue;
+				}
+				let packet: DaemonToLink = entry.decode()?;
+				if matches!(packet, DaemonToLink::Session { .. }) {
+					continue;
+				}
+
+				// Keep the original pacing between packets.
+				task::sleep(Duration::from_millis(
+					entry.millis.saturating_sub(last_millis),
+				))
+				.await;
+				last_millis = entry.millis;
+
+				info!("replaying packet: {}", json::to_line(&packet));
+				self.0.lock().await.send(packet).await?;
+			}
+
+			info!("replay finished");
+			Ok(CommandStatus::Done)
+		})
+	}
+}
@@ -0,0 +1,577 @@
+//! Compares two protocol schemas for changes that would break peers
+//! built against the older one.
+
+use crate::schema::{Body, Field, Message, Protocol, Variant};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+	/// Peers on either side of the change can still talk to each other.
+	Compatible,
+	/// Peers on either side of the change will misread each other.
+	Breaking,
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+	pub severity: Severity,
+	/// The message (and variant) the change is in.
+	pub path: String,
+	pub description: String,
+}
+
+impl fmt::Display for Change {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let severity = match self.severity {
+			Severity::Compatible => "compatible",
+			Severity::Breaking => "breaking",
+		};
+		write!(f, "{severity}: {}: {}", self.path, self.description)
+	}
+}
+
+#[derive(Default)]
+struct Changes(Vec<Change>);
+
+impl Changes {
+	fn push(&mut self, severity: Severity, path: &str, description: String) {
+		self.0.push(Change {
+			severity,
+			path: path.into(),
+			description,
+		});
+	}
+}
+
+/// Lists every difference between `old` and `new`.
+pub fn check(old: &Protocol, new: &Protocol) -> Vec<Change> {
+	let mut changes = Changes::default();
+
+	for old_message in &old.messages {
+		match new.messages.iter().find(|m| m.name == old_message.name) {
+			Some(new_message) => check_message(&mut changes, old_message, new_message),
+			None => changes.push(
+				Severity::Breaking,
+				&old_message.name,
+				"message type was removed".into(),
+			),
+		}
+	}
+
+	for new_message in &new.messages {
+		if !old.messages.iter().any(|m| m.name == new_message.name) {
+			changes.push(
+				Severity::Compatible,
+				&new_message.name,
+				"message type was added".into(),
+			);
+		}
+	}
+
+	check_since(&mut changes, new);
+
+	if !changes.0.is_empty() && old.version == new.version {
+		changes.push(
+			Severity::Breaking,
+			"PROTOCOL_VERSION",
+			format!(
+				"the schema changed but the version is still {}",
+				new.version
+			),
+		);
+	}
+
+	changes.0
+}
+
+/// Fields can't have been added in a version that doesn't exist yet.
+fn check_since(changes: &mut Changes, protocol: &Protocol) {
+	for message in &protocol.messages {
+		let fields: Vec<(String, &Field)> = match &message.body {
+			Body::Struct { fields } => fields
+				.iter()
+				.map(|field| (message.name.clone(), field))
+				.collect(),
+			Body::Enum { variants, .. } => variants
+				.iter()
+				.flat_map(|variant| {
+					let path = format!("{}::{}", message.name, variant.name);
+					variant
+						.fields
+						.iter()
+						.map(move |field| (path.clone(), field))
+				})
+				.collect(),
+		};
+
+		for (path, field) in fields {
+			if let Some(since) = field.since.filter(|since| *since > protocol.version) {
+				changes.push(
+					Severity::Breaking,
+					&path,
+					format!(
+						"field `{}` is marked as added in version {since}, but the version is {}",
+						field.name, protocol.version
+					),
+				);
+			}
+		}
+	}
+}
+
+fn check_message(changes: &mut Changes, old: &Message, new: &Message) {
+	match (&old.body, &new.body) {
+		(Body::Struct { fields: old_fields }, Body::Struct { fields: new_fields }) => {
+			check_fields(changes, &old.name, old_fields, new_fields);
+		}
+		(
+			Body::Enum {
+				variants: old_variants,
+				retired: old_retired,
+			},
+			Body::Enum {
+				variants: new_variants,
+				retired: new_retired,
+			},
+		) => check_variants(
+			changes,
+			&old.name,
+			(old_variants, old_retired),
+			(new_variants, new_retired),
+		),
+		_ => changes.push(
+			Severity::Breaking,
+			&old.name,
+			"changed between a struct and an enum".into(),
+		),
+	}
+}
+
+/// Compares two enums, given as their variants and retired ids.
+fn check_variants(
+	changes: &mut Changes,
+	message: &str,
+	(old, old_retired): (&[Variant], &[u8]),
+	(new, new_retired): (&[Variant], &[u8]),
+) {
+	for old_variant in old {
+		let path = format!("{message}::{}", old_variant.name);
+		let new_by_id = new.iter().find(|v| v.id == old_variant.id);
+		let new_by_name = new.iter().find(|v| v.name == old_variant.name);
+
+		match (new_by_id, new_by_name) {
+			(Some(new_variant), _) if new_variant.name == old_variant.name => {
+				check_fields(changes, &path, &old_variant.fields, &new_variant.fields);
+
+				if new_variant.deprecated && !old_variant.deprecated {
+					changes.push(Severity::Compatible, &path, "variant was deprecated".into());
+				}
+			}
+			// Renaming a variant in place doesn't change what's on the wire.
+			(Some(new_variant), None) if new_variant.fields == old_variant.fields => {
+				changes.push(
+					Severity::Compatible,
+					&path,
+					format!("variant was renamed to `{}`", new_variant.name),
+				);
+			}
+			(Some(new_variant), _) => changes.push(
+				Severity::Breaking,
+				&path,
+				format!("id {} was reused by `{}`", old_variant.id, new_variant.name),
+			),
+			(None, Some(new_variant)) => changes.push(
+				Severity::Breaking,
+				&path,
+				format!("id changed from {} to {}", old_variant.id, new_variant.id),
+			),
+			// Older peers may still send it, but newer ones will know to
+			// reject it rather than misread it as another variant.
+			(None, None) if new_retired.contains(&old_variant.id) => changes.push(
+				Severity::Compatible,
+				&path,
+				format!("variant (id {}) was retired", old_variant.id),
+			),
+			(None, None) => changes.push(
+				Severity::Breaking,
+				&path,
+				format!(
+					"variant (id {}) was removed without retiring its id",
+					old_variant.id
+				),
+			),
+		}
+	}
+
+	for new_variant in new {
+		let is_new = !old
+			.iter()
+			.any(|v| v.id == new_variant.id || v.name == new_variant.name);
+
+		if !is_new {
+			continue;
+		}
+
+		let path = format!("{message}::{}", new_variant.name);
+		if old_retired.contains(&new_variant.id) {
+			changes.push(
+				Severity::Breaking,
+				&path,
+				format!("variant reuses id {}, which was retired", new_variant.id),
+			);
+		} else {
+			changes.push(
+				Severity::Compatible,
+				&path,
+				format!("variant (id {}) was added", new_variant.id),
+			);
+		}
+	}
+
+	// Leaving a retired id off the list would let it be reused later.
+	for id in old_retired {
+		if !new_retired.contains(id) && !new.iter().any(|v| v.id == *id) {
+			changes.push(
+				Severity::Breaking,
+				message,
+				format!("id {id} is no longer listed as retired"),
+			);
+		}
+	}
+}
+
+/// Fields have no framing of their own, so they're compared by position,
+/// by how they're laid out on the wire rather than by their Rust types.
+fn check_fields(changes: &mut Changes, path: &str, old: &[Field], new: &[Field]) {
+	for (i, (old_field, new_field)) in old.iter().zip(new).enumerate() {
+		if old_field.encoding != new_field.encoding {
+			changes.push(
+				Severity::Breaking,
+				path,
+				format!(
+					"field `{}` changed from `{}` to `{}`",
+					old_field.name, old_field.encoding, new_field.encoding
+				),
+			);
+		} else if old_field.name != new_field.name {
+			// Fields that swap places but encode alike are read as each
+			// other, so a name that moved isn't a rename.
+			match new.iter().position(|field| field.name == old_field.name) {
+				Some(j) => changes.push(
+					Severity::Breaking,
+					path,
+					format!("field `{}` moved from position {i} to {j}", old_field.name),
+				),
+				None => changes.push(
+					Severity::Compatible,
+					path,
+					format!(
+						"field `{}` was renamed to `{}`",
+						old_field.name, new_field.name
+					),
+				),
+			}
+		}
+	}
+
+	for old_field in old.iter().skip(new.len()) {
+		changes.push(
+			Severity::Breaking,
+			path,
+			format!("field `{}` was removed", old_field.name),
+		);
+	}
+
+	// Older peers leave out trailing fields they don't know about, so
+	// those only need to be able to default.
+	for new_field in new.iter().skip(old.len()) {
+		if new_field.optional {
+			changes.push(
+				Severity::Compatible,
+				path,
+				format!("optional field `{}` was added", new_field.name),
+			);
+		} else {
+			changes.push(
+				Severity::Breaking,
+				path,
+				format!("field `{}` was added without a default", new_field.name),
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::schema::Encoding;
+
+	const U16: Encoding = Encoding::Int {
+		bytes: 2,
+		signed: false,
+	};
+	const U32: Encoding = Encoding::Int {
+		bytes: 4,
+		signed: false,
+	};
+
+	fn field(name: &str, encoding: Encoding) -> Field {
+		Field {
+			name: name.into(),
+			ty: encoding.to_string(),
+			encoding,
+			optional: false,
+			since: None,
+		}
+	}
+
+	fn optional(name: &str, encoding: Encoding) -> Field {
+		Field {
+			optional: true,
+			since: Some(2),
+			..field(name, encoding)
+		}
+	}
+
+	fn bytes(max: usize) -> Encoding {
+		Encoding::List {
+			prefix: if max <= 255 { 1 } else { 2 },
+			max,
+			item: Box::new(Encoding::Int {
+				bytes: 1,
+				signed: false,
+			}),
+		}
+	}
+
+	fn variant(id: u8, name: &str, fields: Vec<Field>) -> Variant {
+		Variant {
+			id,
+			name: name.into(),
+			fields,
+			deprecated: false,
+		}
+	}
+
+	/// A protocol with a single message, `Packet`.
+	fn protocol(version: u16, variants: Vec<Variant>, retired: Vec<u8>) -> Protocol {
+		Protocol {
+			version,
+			min_version: 1,
+			messages: vec![Message {
+				name: "Packet".into(),
+				body: Body::Enum { variants, retired },
+			}],
+		}
+	}
+
+	/// What a case changes `Packet`'s variants and retired ids to, and the
+	/// most severe change that should be found.
+	type Case = (&'static str, Vec<Variant>, Vec<u8>, Option<Severity>);
+
+	/// The most severe change, if there are any.
+	fn worst(old: &Protocol, new: &Protocol) -> Option<Severity> {
+		let changes = check(old, new);
+		if changes.iter().any(|c| c.severity == Severity::Breaking) {
+			Some(Severity::Breaking)
+		} else {
+			changes.first().map(|_| Severity::Compatible)
+		}
+	}
+
+	#[test]
+	fn classifies_changes() {
+		use Severity::*;
+
+		let hello = || variant(1, "Hello", vec![field("a", U16), field("b", U32)]);
+		let bye = || variant(2, "Bye", vec![]);
+		let old = || vec![hello(), bye()];
+
+		#[rustfmt::skip]
+		let cases: Vec<Case> = vec![
+			("nothing changed", old(), vec![], None),
+			(
+				"optional field added at the end",
+				vec![
+					variant(1, "Hello", vec![field("a", U16), field("b", U32), optional("c", U16)]),
+					bye(),
+				],
+				vec![],
+				Some(Compatible),
+			),
+			(
+				"field added at the end without a default",
+				vec![
+					variant(1, "Hello", vec![field("a", U16), field("b", U32), field("c", U16)]),
+					bye(),
+				],
+				vec![],
+				Some(Breaking),
+			),
+			(
+				"field reordered",
+				vec![variant(1, "Hello", vec![field("b", U32), field("a", U16)]), bye()],
+				vec![],
+				Some(Breaking),
+			),
+			(
+				"field renamed in place",
+				vec![variant(1, "Hello", vec![field("a", U16), field("c", U32)]), bye()],
+				vec![],
+				Some(Compatible),
+			),
+			(
+				"field removed",
+				vec![variant(1, "Hello", vec![field("a", U16)]), bye()],
+				vec![],
+				Some(Breaking),
+			),
+			(
+				"width changed",
+				vec![variant(1, "Hello", vec![field("a", U32), field("b", U32)]), bye()],
+				vec![],
+				Some(Breaking),
+			),
+			(
+				"length prefix widened",
+				vec![variant(1, "Hello", vec![field("a", U16), field("b", bytes(256))]), bye()],
+				vec![],
+				Some(Breaking),
+			),
+			(
+				"type changed but not its encoding",
+				vec![
+					variant(
+						1,
+						"Hello",
+						vec![field("a", U16), Field { ty: "Nonce".into(), ..field("b", U32) }],
+					),
+					bye(),
+				],
+				vec![],
+				None,
+			),
+			(
+				"variant renamed",
+				vec![hello(), variant(2, "Goodbye", vec![])],
+				vec![],
+				Some(Compatible),
+			),
+			(
+				"variant added",
+				vec![hello(), bye(), variant(3, "Again", vec![])],
+				vec![],
+				Some(Compatible),
+			),
+			(
+				"id reused",
+				vec![hello(), variant(2, "Again", vec![field("a", U16)])],
+				vec![],
+				Some(Breaking),
+			),
+			("variant removed", vec![hello()], vec![], Some(Breaking)),
+			("variant retired", vec![hello()], vec![2], Some(Compatible)),
+			("id changed", vec![hello(), variant(3, "Bye", vec![])], vec![], Some(Breaking)),
+		];
+
+		for (name, variants, retired, expected) in cases {
+			let old = protocol(1, old(), vec![]);
+			let new = protocol(2, variants, retired);
+			assert_eq!(
+				worst(&old, &new),
+				expected,
+				"{name}: {:#?}",
+				check(&old, &new)
+			);
+		}
+	}
+
+	#[test]
+	fn fields_that_swap_places_arent_renamed() {
+		let old = protocol(
+			1,
+			vec![variant(1, "Hello", vec![field("a", U16), field("b", U16)])],
+			vec![],
+		);
+		let new = protocol(
+			2,
+			vec![variant(1, "Hello", vec![field("b", U16), field("a", U16)])],
+			vec![],
+		);
+
+		let changes = check(&old, &new);
+		assert_eq!(changes.len(), 2, "{changes:#?}");
+		assert!(changes.iter().all(|c| c.severity == Severity::Breaking));
+	}
+
+	#[test]
+	fn retired_ids_stay_retired() {
+		use Severity::*;
+
+		let hello = || variant(1, "Hello", vec![]);
+		let old = protocol(1, vec![hello()], vec![2]);
+
+		let cases = [
+			("still retired", protocol(2, vec![hello()], vec![2]), None),
+			(
+				"reused",
+				protocol(2, vec![hello(), variant(2, "Bye", vec![])], vec![]),
+				Some(Breaking),
+			),
+			(
+				"no longer listed",
+				protocol(2, vec![hello()], vec![]),
+				Some(Breaking),
+			),
+		];
+
+		for (name, new, expected) in cases {
+			assert_eq!(
+				worst(&old, &new),
+				expected,
+				"{name}: {:#?}",
+				check(&old, &new)
+			);
+		}
+	}
+
+	#[test]
+	fn changes_need_a_new_version() {
+		let old = protocol(1, vec![variant(1, "Hello", vec![])], vec![]);
+
+		let new = protocol(1, vec![variant(1, "Hello", vec![])], vec![]);
+		assert_eq!(worst(&old, &new), None);
+
+		let new = protocol(1, vec![variant(1, "Hi", vec![])], vec![]);
+		let changes = check(&old, &new);
+		assert!(
+			changes
+				.iter()
+				.any(|c| c.severity == Severity::Breaking && c.path == "PROTOCOL_VERSION")
+		);
+	}
+
+	#[test]
+	fn fields_cant_be_from_future_versions() {
+		let new = protocol(
+			2,
+			vec![variant(
+				1,
+				"Hello",
+				vec![Field {
+					since: Some(3),
+					..optional("a", U16)
+				}],
+			)],
+			vec![],
+		);
+
+		let changes = check(&new, &new);
+		assert!(
+			changes
+				.iter()
+				.any(|c| c.severity == Severity::Breaking && c.description.contains("version 3")),
+			"{changes:#?}"
+		);
+	}
+}
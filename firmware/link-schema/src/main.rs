@@ -0,0 +1,83 @@
+//! Dumps the wire schema of the protocol as JSON, and compares two dumps
+//! for breaking changes.
+//!
+//! Dump the schema from each build (e.g. the firmware's and the daemon's
+//! commits) and `check` them against each other before they meet on a rig:
+//!
+//! ```sh
+//! cargo run -p link-schema -- dump > firmware.json
+//! cargo run -p link-schema -- check firmware.json
+//! ```
+mod compat;
+mod schema;
+
+use clap::Parser;
+use compat::Severity;
+use schema::Protocol;
+use std::{fs, io, path::PathBuf, process::ExitCode};
+
+#[derive(clap::Parser, Debug)]
+enum Command {
+	/// Prints the schema of this build as JSON.
+	Dump,
+	/// Lists the changes between two schema dumps, exiting with
+	/// a failure if any of them would break older peers.
+	Check {
+		/// The older schema dump.
+		old: PathBuf,
+		/// The newer schema dump. Defaults to the schema of this build.
+		new: Option<PathBuf>,
+	},
+}
+
+#[derive(thiserror::Error, Debug)]
+enum Error {
+	#[error("failed to read {0}: {1}")]
+	Read(PathBuf, #[source] io::Error),
+	#[error("failed to parse {0}: {1}")]
+	Parse(PathBuf, #[source] serde_json::Error),
+	#[error("failed to write the schema: {0}")]
+	Write(#[from] serde_json::Error),
+}
+
+fn load(path: PathBuf) -> Result<Protocol, Error> {
+	let json = fs::read_to_string(&path).map_err(|err| Error::Read(path.clone(), err))?;
+	serde_json::from_str(&json).map_err(|err| Error::Parse(path, err))
+}
+
+fn run(command: Command) -> Result<bool, Error> {
+	match command {
+		Command::Dump => {
+			serde_json::to_writer_pretty(io::stdout().lock(), &Protocol::current())?;
+			println!();
+			Ok(true)
+		}
+		Command::Check { old, new } => {
+			let old = load(old)?;
+			let new = match new {
+				Some(path) => load(path)?,
+				None => Protocol::current(),
+			};
+
+			let changes = compat::check(&old, &new);
+			for change in &changes {
+				println!("{change}");
+			}
+
+			Ok(changes
+				.iter()
+				.all(|change| change.severity == Severity::Compatible))
+		}
+	}
+}
+
+fn main() -> ExitCode {
+	match run(Command::parse()) {
+		Ok(true) => ExitCode::SUCCESS,
+		Ok(false) => ExitCode::FAILURE,
+		Err(err) => {
+			eprintln!("error: {err}");
+			ExitCode::FAILURE
+		}
+	}
+}
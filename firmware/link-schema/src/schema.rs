@@ -0,0 +1,201 @@
+//! The JSON form of the protocol schema.
+
+use link_protocol::schema::{self, FieldSchema, MessageSchema, SchemaBody, VariantSchema};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A dump of every message type in a build of the protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Protocol {
+	pub version: u16,
+	pub min_version: u16,
+	pub messages: Vec<Message>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+	pub name: String,
+	#[serde(flatten)]
+	pub body: Body,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Body {
+	Struct {
+		fields: Vec<Field>,
+	},
+	Enum {
+		variants: Vec<Variant>,
+		/// The ids of removed variants, which must never be reused.
+		#[serde(default, skip_serializing_if = "Vec::is_empty")]
+		retired: Vec<u8>,
+	},
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variant {
+	pub id: u8,
+	pub name: String,
+	pub fields: Vec<Field>,
+	#[serde(default, skip_serializing_if = "is_false")]
+	pub deprecated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Field {
+	pub name: String,
+	/// The field's type as written in the source, for people to read;
+	/// compatibility is judged by its encoding.
+	#[serde(rename = "type")]
+	pub ty: String,
+	pub encoding: Encoding,
+	#[serde(default, skip_serializing_if = "is_false")]
+	pub optional: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub since: Option<u16>,
+}
+
+/// See [`schema::Encoding`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Encoding {
+	Int {
+		bytes: u8,
+		signed: bool,
+	},
+	Float {
+		bytes: u8,
+	},
+	Bool,
+	Bytes {
+		len: usize,
+	},
+	Option {
+		value: Box<Encoding>,
+	},
+	Tuple {
+		items: Vec<Encoding>,
+	},
+	List {
+		prefix: u8,
+		max: usize,
+		item: Box<Encoding>,
+	},
+	Text {
+		prefix: u8,
+		max: usize,
+	},
+	Message {
+		name: String,
+	},
+}
+
+fn is_false(value: &bool) -> bool {
+	!value
+}
+
+impl Protocol {
+	/// The schema this tool was built against.
+	pub fn current() -> Self {
+		Self {
+			version: link_protocol::PROTOCOL_VERSION,
+			min_version: link_protocol::MIN_PROTOCOL_VERSION,
+			messages: link_protocol::SCHEMAS.iter().map(Message::from).collect(),
+		}
+	}
+}
+
+impl From<&MessageSchema> for Message {
+	fn from(schema: &MessageSchema) -> Self {
+		Self {
+			name: schema.name.into(),
+			body: match schema.body {
+				SchemaBody::Struct(fields) => Body::Struct {
+					fields: fields.iter().map(Field::from).collect(),
+				},
+				SchemaBody::Enum(variants) => Body::Enum {
+					variants: variants.iter().map(Variant::from).collect(),
+					retired: schema.retired.to_vec(),
+				},
+			},
+		}
+	}
+}
+
+impl From<&VariantSchema> for Variant {
+	fn from(schema: &VariantSchema) -> Self {
+		Self {
+			id: schema.id,
+			name: schema.name.into(),
+			fields: schema.fields.iter().map(Field::from).collect(),
+			deprecated: schema.deprecated,
+		}
+	}
+}
+
+impl From<&FieldSchema> for Field {
+	fn from(schema: &FieldSchema) -> Self {
+		Self {
+			name: schema.name.into(),
+			ty: schema.ty.into(),
+			encoding: Encoding::from(&schema.encoding),
+			optional: schema.optional,
+			since: schema.since,
+		}
+	}
+}
+
+impl From<&schema::Encoding> for Encoding {
+	fn from(encoding: &schema::Encoding) -> Self {
+		match *encoding {
+			schema::Encoding::Int { bytes, signed } => Self::Int { bytes, signed },
+			schema::Encoding::Float { bytes } => Self::Float { bytes },
+			schema::Encoding::Bool => Self::Bool,
+			schema::Encoding::Bytes(len) => Self::Bytes { len },
+			schema::Encoding::Option(value) => Self::Option {
+				value: Box::new(value.into()),
+			},
+			schema::Encoding::Tuple(items) => Self::Tuple {
+				items: items.iter().map(Self::from).collect(),
+			},
+			schema::Encoding::List { prefix, max, item } => Self::List {
+				prefix,
+				max,
+				item: Box::new(item.into()),
+			},
+			schema::Encoding::Text { prefix, max } => Self::Text { prefix, max },
+			schema::Encoding::Message(name) => Self::Message { name: name.into() },
+		}
+	}
+}
+
+/// Describes an encoding in Rust-like terms, e.g. `list<=256 of u8 (2-byte length)`.
+impl fmt::Display for Encoding {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Int { bytes, signed } => {
+				write!(f, "{}{}", if *signed { "i" } else { "u" }, bytes * 8)
+			}
+			Self::Float { bytes } => write!(f, "f{}", bytes * 8),
+			Self::Bool => f.write_str("bool"),
+			Self::Bytes { len } => write!(f, "[u8; {len}]"),
+			Self::Option { value } => write!(f, "Option<{value}>"),
+			Self::Tuple { items } => {
+				f.write_str("(")?;
+				for (i, item) in items.iter().enumerate() {
+					if i > 0 {
+						f.write_str(", ")?;
+					}
+					write!(f, "{item}")?;
+				}
+				f.write_str(")")
+			}
+			Self::List { prefix, max, item } => {
+				write!(f, "list<={max} of {item} ({prefix}-byte length)")
+			}
+			Self::Text { prefix, max } => write!(f, "text<={max} ({prefix}-byte length)"),
+			Self::Message { name } => f.write_str(name),
+		}
+	}
+}